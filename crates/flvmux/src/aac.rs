@@ -0,0 +1,335 @@
+use std::io;
+
+/// Sampling frequencies by sampling_frequency_index, ISO/IEC 14496-3 table 1.18
+pub const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Channels by channel_config, ISO/IEC 14496-3 table 1.19. Config 0 means
+/// the layout is described in the bitstream, and 7 is 7.1.
+pub const CHANNEL_COUNTS: [u8; 8] = [0, 1, 2, 3, 4, 5, 6, 8];
+
+/// MPEG-4 audio object type for AAC-LC
+pub const AAC_LC: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdtsHeader {
+    /// MPEG-4 audio object type, one more than the ADTS profile field
+    pub object_type: u8,
+    pub sample_rate_index: u8,
+    pub channel_config: u8,
+    /// Header length, 7 bytes, or 9 with a CRC
+    pub header_length: usize,
+    /// Frame length, header included
+    pub frame_length: usize,
+}
+
+impl AdtsHeader {
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATES[usize::from(self.sample_rate_index)]
+    }
+
+    pub fn channels(&self) -> u8 {
+        channel_count(self.channel_config)
+    }
+
+    pub fn audio_specific_config(&self) -> [u8; 2] {
+        audio_specific_config(
+            self.object_type,
+            self.sample_rate_index,
+            self.channel_config,
+        )
+    }
+}
+
+/// Builds the two byte AudioSpecificConfig (ISO/IEC 14496-3 section 1.6.2.1) that
+/// is the payload of an FLV AAC sequence header.
+pub fn audio_specific_config(
+    object_type: u8,
+    sample_rate_index: u8,
+    channel_config: u8,
+) -> [u8; 2] {
+    // 5 bits object type, 4 bits frequency index, 4 bits channel config, then
+    // zeros for frameLengthFlag, dependsOnCoreCoder and extensionFlag.
+    [
+        (object_type << 3) | (sample_rate_index >> 1),
+        ((sample_rate_index & 1) << 7) | (channel_config << 3),
+    ]
+}
+
+/// The fields of an AudioSpecificConfig that also go in ADTS headers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AudioConfig {
+    pub object_type: u8,
+    pub sample_rate_index: u8,
+    pub channel_config: u8,
+}
+
+impl AudioConfig {
+    pub fn sample_rate(&self) -> u32 {
+        SAMPLE_RATES[usize::from(self.sample_rate_index)]
+    }
+
+    /// Zero if the channel layout is in the bitstream, or is one we don't know.
+    pub fn channels(&self) -> u8 {
+        channel_count(self.channel_config)
+    }
+
+    /// A (CRC-less) ADTS header for a raw AAC frame of the given length, for
+    /// containers like MPEG-TS that carry AAC as ADTS.
+    pub fn adts_header(&self, data_length: usize) -> io::Result<[u8; 7]> {
+        let frame_length = data_length + 7;
+        if frame_length >= 1 << 13 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "AAC frame is too long for an ADTS header",
+            ));
+        }
+
+        // The profile field is the object type minus one, and only has room
+        // for the first four.
+        if !(1..=4).contains(&self.object_type) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "AAC object type {} can't be carried in ADTS",
+                    self.object_type
+                ),
+            ));
+        }
+
+        Ok([
+            0xff,
+            0xf1, // MPEG-4, layer 0, no CRC
+            ((self.object_type - 1) << 6)
+                | (self.sample_rate_index << 2)
+                | (self.channel_config >> 2),
+            ((self.channel_config & 0x03) << 6) | (frame_length >> 11) as u8,
+            (frame_length >> 3) as u8,
+            ((frame_length as u8 & 0x07) << 5) | 0x1f, // buffer fullness 0x7ff, VBR
+            0xfc,
+        ])
+    }
+}
+
+fn channel_count(channel_config: u8) -> u8 {
+    CHANNEL_COUNTS
+        .get(usize::from(channel_config))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Parses the AudioSpecificConfig that is the payload of an FLV AAC sequence
+/// header (see audio_specific_config).
+pub fn parse_audio_specific_config(data: &[u8]) -> io::Result<AudioConfig> {
+    if data.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "corrupted input, AudioSpecificConfig is truncated",
+        ));
+    }
+
+    let object_type = data[0] >> 3;
+    let sample_rate_index = ((data[0] & 0x07) << 1) | (data[1] >> 7);
+    let channel_config = (data[1] >> 3) & 0x0f;
+    if object_type == 31 || usize::from(sample_rate_index) >= SAMPLE_RATES.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported AudioSpecificConfig, escaped object types and explicit sample rates aren't supported",
+        ));
+    }
+
+    Ok(AudioConfig {
+        object_type,
+        sample_rate_index,
+        channel_config,
+    })
+}
+
+/// Parses the fixed and variable ADTS headers (ISO/IEC 13818-7 section 6.2)
+pub fn parse_adts_header(data: &[u8]) -> io::Result<AdtsHeader> {
+    if data.len() < 7 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "corrupted input, ADTS header is truncated",
+        ));
+    }
+
+    if data[0] != 0xff || data[1] & 0xf6 != 0xf0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, missing ADTS sync word",
+        ));
+    }
+
+    let protection_absent = data[1] & 0x01 == 1;
+    let object_type = (data[2] >> 6) + 1;
+    let sample_rate_index = (data[2] >> 2) & 0x0f;
+    let channel_config = ((data[2] & 0x01) << 2) | (data[3] >> 6);
+    let frame_length = (usize::from(data[3] & 0x03) << 11)
+        | (usize::from(data[4]) << 3)
+        | usize::from(data[5] >> 5);
+    let raw_data_blocks = (data[6] & 0x03) + 1;
+    let header_length = if protection_absent { 7 } else { 9 };
+
+    if usize::from(sample_rate_index) >= SAMPLE_RATES.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, invalid ADTS sampling frequency index",
+        ));
+    }
+
+    if channel_config == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported ADTS stream, channel layouts in the bitstream aren't supported",
+        ));
+    }
+
+    if raw_data_blocks != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported ADTS stream, frames must hold exactly one raw data block",
+        ));
+    }
+
+    if frame_length <= header_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, ADTS frame length is too short",
+        ));
+    }
+
+    Ok(AdtsHeader {
+        object_type,
+        sample_rate_index,
+        channel_config,
+        header_length,
+        frame_length,
+    })
+}
+
+pub struct AdtsFrame<'a> {
+    pub header: AdtsHeader,
+    /// The raw AAC frame, without the ADTS header
+    pub data: &'a [u8],
+}
+
+/// Iterates over the frames of an ADTS stream, like a .aac file.
+pub struct AdtsFrames<'a> {
+    data: &'a [u8],
+}
+
+pub fn adts_frames(data: &[u8]) -> AdtsFrames<'_> {
+    AdtsFrames { data }
+}
+
+impl<'a> Iterator for AdtsFrames<'a> {
+    type Item = io::Result<AdtsFrame<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let header = match parse_adts_header(self.data) {
+            Ok(header) => header,
+            Err(e) => {
+                self.data = &[];
+                return Some(Err(e));
+            }
+        };
+
+        if header.frame_length > self.data.len() {
+            self.data = &[];
+            return Some(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "corrupted input, ADTS frame is truncated",
+            )));
+        }
+
+        let (frame, rest) = self.data.split_at(header.frame_length);
+        self.data = rest;
+
+        Some(Ok(AdtsFrame {
+            header,
+            data: &frame[header.header_length..],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AAC-LC, 44.1kHz, stereo, no CRC
+    fn adts_frame(payload: &[u8]) -> Vec<u8> {
+        let frame_length = payload.len() + 7;
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            (1 << 6) | (4 << 2),
+            (2 << 6) | ((frame_length >> 11) as u8 & 0x03),
+            (frame_length >> 3) as u8,
+            ((frame_length as u8 & 0x07) << 5) | 0x1f,
+            0xfc,
+        ];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_adts_frames() {
+        let mut data = adts_frame(&[1, 2, 3]);
+        data.extend(adts_frame(&[4; 300]));
+
+        let frames = adts_frames(&data)
+            .collect::<io::Result<Vec<AdtsFrame>>>()
+            .unwrap();
+        assert_eq!(2, frames.len());
+        assert_eq!(&[1, 2, 3], frames[0].data);
+        assert_eq!(&[4; 300][..], frames[1].data);
+
+        let header = frames[0].header;
+        assert_eq!(AAC_LC, header.object_type);
+        assert_eq!(44100, header.sample_rate());
+        assert_eq!(2, header.channel_config);
+        assert_eq!(2, header.channels());
+        assert_eq!([0x12, 0x10], header.audio_specific_config());
+    }
+
+    #[test]
+    fn test_bad_adts() {
+        let data = adts_frame(&[1, 2, 3]);
+        assert!(adts_frames(&data[..data.len() - 1])
+            .next()
+            .unwrap()
+            .is_err());
+
+        let mut no_sync = data.clone();
+        no_sync[0] = 0;
+        assert!(adts_frames(&no_sync).next().unwrap().is_err());
+
+        assert!(parse_adts_header(&data[..5]).is_err());
+    }
+
+    #[test]
+    fn test_audio_specific_config() {
+        // AAC-LC, 48kHz, mono
+        assert_eq!([0x11, 0x88], audio_specific_config(AAC_LC, 3, 1));
+
+        let config = parse_audio_specific_config(&[0x12, 0x10]).unwrap();
+        assert_eq!(44100, config.sample_rate());
+        assert_eq!(2, config.channel_config);
+        assert_eq!(
+            adts_frame(&[1, 2, 3])[..7],
+            config.adts_header(3).unwrap()[..]
+        );
+        assert!(parse_audio_specific_config(&[0x12]).is_err());
+
+        // 7.1 is config 7, but eight channels
+        let surround = parse_audio_specific_config(&audio_specific_config(AAC_LC, 3, 7)).unwrap();
+        assert_eq!(7, surround.channel_config);
+        assert_eq!(8, surround.channels());
+    }
+}
@@ -0,0 +1,365 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+// From the AMF0 spec, https://rtmp.veriskope.com/pdf/amf0-file-format-specification.pdf
+const NUMBER_MARKER: u8 = 0x00;
+const BOOLEAN_MARKER: u8 = 0x01;
+const STRING_MARKER: u8 = 0x02;
+const OBJECT_MARKER: u8 = 0x03;
+const NULL_MARKER: u8 = 0x05;
+const UNDEFINED_MARKER: u8 = 0x06;
+const ECMA_ARRAY_MARKER: u8 = 0x08;
+const OBJECT_END_MARKER: u8 = 0x09;
+const STRICT_ARRAY_MARKER: u8 = 0x0A;
+const DATE_MARKER: u8 = 0x0B;
+const LONG_STRING_MARKER: u8 = 0x0C;
+
+// Nested objects deeper than this are assumed to be garbage (or hostile)
+const MAX_NESTING_DEPTH: usize = 64;
+
+/// Object and ECMA array properties are kept in order, since some readers
+/// (and people reading hex dumps) care about the order of onMetaData keys.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Amf0Value {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf0Value)>),
+    Null,
+    Undefined,
+    EcmaArray(Vec<(String, Amf0Value)>),
+    StrictArray(Vec<Amf0Value>),
+    /// millis since the epoch, UTC. The timezone is reserved, and should be zero.
+    Date {
+        millis: f64,
+        timezone: i16,
+    },
+}
+
+impl Amf0Value {
+    /// Looks up a property of an object or ECMA array.
+    pub fn get(&self, name: &str) -> Option<&Amf0Value> {
+        match self {
+            Amf0Value::Object(properties) | Amf0Value::EcmaArray(properties) => properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Amf0Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Amf0Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Amf0Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+pub fn write_value(out: &mut impl Write, value: &Amf0Value) -> io::Result<()> {
+    match value {
+        Amf0Value::Number(n) => {
+            out.write_u8(NUMBER_MARKER)?;
+            out.write_f64::<BigEndian>(*n)?;
+        }
+        Amf0Value::Boolean(b) => {
+            out.write_u8(BOOLEAN_MARKER)?;
+            out.write_u8(u8::from(*b))?;
+        }
+        Amf0Value::String(s) => {
+            if s.len() > usize::from(u16::MAX) {
+                out.write_u8(LONG_STRING_MARKER)?;
+                write_long_utf8(out, s)?;
+            } else {
+                out.write_u8(STRING_MARKER)?;
+                write_utf8(out, s)?;
+            }
+        }
+        Amf0Value::Object(properties) => {
+            out.write_u8(OBJECT_MARKER)?;
+            write_properties(out, properties)?;
+        }
+        Amf0Value::Null => out.write_u8(NULL_MARKER)?,
+        Amf0Value::Undefined => out.write_u8(UNDEFINED_MARKER)?,
+        Amf0Value::EcmaArray(properties) => {
+            out.write_u8(ECMA_ARRAY_MARKER)?;
+            // The count is only a hint to readers, the list is still terminated with an end marker.
+            out.write_u32::<BigEndian>(length_u32(properties.len())?)?;
+            write_properties(out, properties)?;
+        }
+        Amf0Value::StrictArray(values) => {
+            out.write_u8(STRICT_ARRAY_MARKER)?;
+            out.write_u32::<BigEndian>(length_u32(values.len())?)?;
+            for v in values {
+                write_value(out, v)?;
+            }
+        }
+        Amf0Value::Date { millis, timezone } => {
+            out.write_u8(DATE_MARKER)?;
+            out.write_f64::<BigEndian>(*millis)?;
+            out.write_i16::<BigEndian>(*timezone)?;
+        }
+    };
+
+    Ok(())
+}
+
+/// Decodes every value in a SCRIPTDATA tag body.
+pub fn read_values(mut data: &[u8]) -> io::Result<Vec<Amf0Value>> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        values.push(read_value(&mut data)?);
+    }
+
+    Ok(values)
+}
+
+pub fn read_value(inf: &mut impl Read) -> io::Result<Amf0Value> {
+    read_nested_value(inf, 0)
+}
+
+fn read_nested_value(inf: &mut impl Read, depth: usize) -> io::Result<Amf0Value> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, AMF0 values are nested too deeply",
+        ));
+    }
+
+    let value = match inf.read_u8()? {
+        NUMBER_MARKER => Amf0Value::Number(inf.read_f64::<BigEndian>()?),
+        BOOLEAN_MARKER => Amf0Value::Boolean(inf.read_u8()? != 0),
+        STRING_MARKER => {
+            let len = inf.read_u16::<BigEndian>()?;
+            Amf0Value::String(read_utf8(inf, u32::from(len))?)
+        }
+        LONG_STRING_MARKER => {
+            let len = inf.read_u32::<BigEndian>()?;
+            Amf0Value::String(read_utf8(inf, len)?)
+        }
+        OBJECT_MARKER => Amf0Value::Object(read_properties(inf, depth)?),
+        NULL_MARKER => Amf0Value::Null,
+        UNDEFINED_MARKER => Amf0Value::Undefined,
+        ECMA_ARRAY_MARKER => {
+            // The count is only a hint, the property list ends with an end marker.
+            let _count = inf.read_u32::<BigEndian>()?;
+            Amf0Value::EcmaArray(read_properties(inf, depth)?)
+        }
+        STRICT_ARRAY_MARKER => {
+            let count = inf.read_u32::<BigEndian>()?;
+            // Don't trust the count for allocation, it might be garbage.
+            let mut values = Vec::with_capacity(cmp::min(count as usize, 1024));
+            for _ in 0..count {
+                values.push(read_nested_value(inf, depth + 1)?);
+            }
+            Amf0Value::StrictArray(values)
+        }
+        DATE_MARKER => {
+            let millis = inf.read_f64::<BigEndian>()?;
+            let timezone = inf.read_i16::<BigEndian>()?;
+            Amf0Value::Date { millis, timezone }
+        }
+        OBJECT_END_MARKER => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted input, unexpected AMF0 object end marker",
+            ))
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported AMF0 type marker {:#x}", other),
+            ))
+        }
+    };
+
+    Ok(value)
+}
+
+fn read_properties(inf: &mut impl Read, depth: usize) -> io::Result<Vec<(String, Amf0Value)>> {
+    let mut properties = Vec::new();
+    loop {
+        let len = inf.read_u16::<BigEndian>()?;
+        if len == 0 {
+            match inf.read_u8()? {
+                OBJECT_END_MARKER => return Ok(properties),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "corrupted input, AMF0 property with an empty name",
+                    ))
+                }
+            }
+        }
+
+        let name = read_utf8(inf, u32::from(len))?;
+        let value = read_nested_value(inf, depth + 1)?;
+        properties.push((name, value));
+    }
+}
+
+fn read_utf8(inf: &mut impl Read, len: u32) -> io::Result<String> {
+    let mut buf = Vec::new();
+    inf.take(u64::from(len)).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "corrupted input, AMF0 string is truncated",
+        ));
+    }
+
+    String::from_utf8(buf).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, AMF0 string isn't valid utf-8",
+        )
+    })
+}
+
+fn write_properties(out: &mut impl Write, properties: &[(String, Amf0Value)]) -> io::Result<()> {
+    for (name, value) in properties {
+        write_utf8(out, name)?;
+        write_value(out, value)?;
+    }
+
+    // An empty name followed by an object end marker
+    out.write_u16::<BigEndian>(0)?;
+    out.write_u8(OBJECT_END_MARKER)?;
+
+    Ok(())
+}
+
+fn write_utf8(out: &mut impl Write, s: &str) -> io::Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "AMF0 property names must be shorter than 65536 bytes",
+        )
+    })?;
+    out.write_u16::<BigEndian>(len)?;
+    out.write_all(s.as_bytes())
+}
+
+fn write_long_utf8(out: &mut impl Write, s: &str) -> io::Result<()> {
+    out.write_u32::<BigEndian>(length_u32(s.len())?)?;
+    out.write_all(s.as_bytes())
+}
+
+fn length_u32(len: usize) -> io::Result<u32> {
+    u32::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "AMF0 value is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_values() {
+        let mut out = Vec::new();
+        write_value(&mut out, &Amf0Value::String("onMetaData".into())).unwrap();
+        write_value(
+            &mut out,
+            &Amf0Value::EcmaArray(vec![
+                ("width".into(), Amf0Value::Number(1280.0)),
+                ("stereo".into(), Amf0Value::Boolean(true)),
+            ]),
+        )
+        .unwrap();
+
+        let mut expected = vec![0x02, 0x00, 0x0a];
+        expected.extend_from_slice(b"onMetaData");
+        expected.extend_from_slice(&[0x08, 0x00, 0x00, 0x00, 0x02]);
+        expected.extend_from_slice(&[0x00, 0x05]);
+        expected.extend_from_slice(b"width");
+        expected.extend_from_slice(&[0x00, 0x40, 0x94, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        expected.extend_from_slice(&[0x00, 0x06]);
+        expected.extend_from_slice(b"stereo");
+        expected.extend_from_slice(&[0x01, 0x01]);
+        expected.extend_from_slice(&[0x00, 0x00, 0x09]);
+
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let values = vec![
+            Amf0Value::String("onMetaData".into()),
+            Amf0Value::EcmaArray(vec![
+                ("duration".into(), Amf0Value::Number(12.5)),
+                ("stereo".into(), Amf0Value::Boolean(false)),
+                (
+                    "keyframes".into(),
+                    Amf0Value::Object(vec![
+                        (
+                            "times".into(),
+                            Amf0Value::StrictArray(vec![
+                                Amf0Value::Number(0.0),
+                                Amf0Value::Number(2.0),
+                            ]),
+                        ),
+                        ("filepositions".into(), Amf0Value::StrictArray(vec![])),
+                    ]),
+                ),
+                ("nothing".into(), Amf0Value::Null),
+                (
+                    "creationdate".into(),
+                    Amf0Value::Date {
+                        millis: 1.6e12,
+                        timezone: 0,
+                    },
+                ),
+            ]),
+        ];
+
+        let mut out = Vec::new();
+        for v in &values {
+            write_value(&mut out, v).unwrap();
+        }
+
+        assert_eq!(values, read_values(&out).unwrap());
+    }
+
+    #[test]
+    fn test_read_malformed() {
+        // Truncated number
+        assert!(read_values(&[0x00, 0x40, 0x94]).is_err());
+        // Missing object end marker
+        assert!(read_values(&[0x03, 0x00, 0x01, b'a', 0x05]).is_err());
+        // Bare object end marker
+        assert!(read_values(&[0x09]).is_err());
+        // Unsupported reference type
+        assert!(read_values(&[0x07, 0x00, 0x01]).is_err());
+        // Huge strict array count, with no data behind it
+        assert!(read_values(&[0x0a, 0xff, 0xff, 0xff, 0xff]).is_err());
+        // Bad utf-8
+        assert!(read_values(&[0x02, 0x00, 0x01, 0xff]).is_err());
+        // Hostile nesting
+        assert!(read_values(&[0x0a, 0x00, 0x00, 0x00, 0x01].repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_write_long_string() {
+        let mut out = Vec::new();
+        let long = "x".repeat(70000);
+        write_value(&mut out, &Amf0Value::String(long)).unwrap();
+        assert_eq!(&[0x0c, 0x00, 0x01, 0x11, 0x70], &out[..5]);
+        assert_eq!(70005, out.len());
+    }
+}
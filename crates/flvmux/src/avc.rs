@@ -0,0 +1,576 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+// NAL unit types, from table 7-1 of the h264 spec
+pub const NAL_SLICE: u8 = 1;
+pub const NAL_SLICE_IDR: u8 = 5;
+pub const NAL_SEI: u8 = 6;
+pub const NAL_SPS: u8 = 7;
+pub const NAL_PPS: u8 = 8;
+pub const NAL_AUD: u8 = 9;
+
+/// We always write four byte NAL unit lengths in AVCC data.
+pub const NAL_LENGTH_SIZE: u8 = 4;
+
+/// The type of a NAL unit, from the low five bits of the first byte.
+/// The NAL shouldn't have a start code or length prefix.
+pub fn nal_unit_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|header| header & 0x1f)
+}
+
+/// Iterates over the NAL units in Annex-B (start code delimited) data. The
+/// NAL units don't include their start codes.
+pub struct AnnexBNalUnits<'a> {
+    data: &'a [u8],
+}
+
+pub fn annexb_nal_units(data: &[u8]) -> AnnexBNalUnits<'_> {
+    // Anything before the first start code isn't a NAL unit
+    let start = match find_start_code(data) {
+        Some(ix) => ix + 3,
+        None => data.len(),
+    };
+
+    AnnexBNalUnits {
+        data: &data[start..],
+    }
+}
+
+impl<'a> Iterator for AnnexBNalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let (mut nal, rest) = match find_start_code(self.data) {
+            Some(ix) => (&self.data[..ix], &self.data[ix + 3..]),
+            None => (self.data, &self.data[self.data.len()..]),
+        };
+        self.data = rest;
+
+        // Drops trailing_zero_8bits, and the leading zero of four byte start codes.
+        while let Some((0, init)) = nal.split_last() {
+            nal = init;
+        }
+
+        Some(nal)
+    }
+}
+
+fn find_start_code(data: &[u8]) -> Option<usize> {
+    data.windows(3).position(|w| w == [0, 0, 1])
+}
+
+/// Rewrites Annex-B data with four byte NAL unit length prefixes in place
+/// of start codes. This is the layout FLV (and MP4) AVC video data uses.
+pub fn annexb_to_avcc(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    for nal in annexb_nal_units(data) {
+        let len = u32::try_from(nal.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "NAL unit is too long"))?;
+        out.write_u32::<BigEndian>(len)?;
+        out.write_all(nal)?;
+    }
+
+    Ok(out)
+}
+
+/// Splits AVCC (four byte length prefixed) data into its NAL units.
+pub fn avcc_nal_units(mut data: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut nals = Vec::new();
+    while !data.is_empty() {
+        let len = data.read_u32::<BigEndian>()? as usize;
+        if len > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted input, NAL unit length is past the end of the data",
+            ));
+        }
+
+        let (nal, rest) = data.split_at(len);
+        nals.push(nal);
+        data = rest;
+    }
+
+    Ok(nals)
+}
+
+/// The parts of an SPS that go in an AVCDecoderConfigurationRecord, and the
+/// picture size
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpsInfo {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+    pub chroma_format_idc: u32,
+    pub bit_depth_luma_minus8: u32,
+    pub bit_depth_chroma_minus8: u32,
+    /// In pixels, after cropping
+    pub width: u32,
+    pub height: u32,
+}
+
+// Profiles that carry chroma format and bit depth in the SPS, and so
+// need the extra fields at the end of an AVCDecoderConfigurationRecord.
+fn is_high_profile(profile_idc: u8) -> bool {
+    matches!(profile_idc, 100 | 110 | 122 | 144)
+}
+
+// Profiles with chroma format, bit depth and scaling matrices in the SPS.
+fn has_chroma_format(profile_idc: u8) -> bool {
+    matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135 | 144
+    )
+}
+
+/// Parses a sequence parameter set NAL unit, up to the frame cropping
+/// (section 7.3.2.1.1 of the h264 spec)
+pub fn parse_sps(sps: &[u8]) -> io::Result<SpsInfo> {
+    if nal_unit_type(sps) != Some(NAL_SPS) || sps.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, not a sequence parameter set",
+        ));
+    }
+
+    let profile_idc = sps[1];
+    let constraint_flags = sps[2];
+    let level_idc = sps[3];
+
+    let rbsp = unescape_rbsp(&sps[4..]);
+    let mut bits = BitReader::new(&rbsp);
+    let _seq_parameter_set_id = bits.read_ue()?;
+
+    let mut separate_colour_plane = false;
+    let (chroma_format_idc, bit_depth_luma_minus8, bit_depth_chroma_minus8) =
+        if has_chroma_format(profile_idc) {
+            let chroma_format_idc = bits.read_ue()?;
+            if chroma_format_idc == 3 {
+                separate_colour_plane = bits.read_bit()? == 1;
+            }
+            let depths = (chroma_format_idc, bits.read_ue()?, bits.read_ue()?);
+
+            let _qpprime_y_zero_transform_bypass_flag = bits.read_bit()?;
+            if bits.read_bit()? == 1 {
+                let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+                for ix in 0..lists {
+                    if bits.read_bit()? == 1 {
+                        skip_scaling_list(&mut bits, if ix < 6 { 16 } else { 64 })?;
+                    }
+                }
+            }
+            depths
+        } else {
+            // 4:2:0, 8 bit, implied by the profile.
+            (1, 0, 0)
+        };
+
+    let _log2_max_frame_num_minus4 = bits.read_ue()?;
+    match bits.read_ue()? {
+        0 => {
+            let _log2_max_pic_order_cnt_lsb_minus4 = bits.read_ue()?;
+        }
+        1 => {
+            let _delta_pic_order_always_zero_flag = bits.read_bit()?;
+            let _offset_for_non_ref_pic = bits.read_se()?;
+            let _offset_for_top_to_bottom_field = bits.read_se()?;
+            for _ in 0..bits.read_ue()? {
+                let _offset_for_ref_frame = bits.read_se()?;
+            }
+        }
+        _ => {}
+    }
+    let _max_num_ref_frames = bits.read_ue()?;
+    let _gaps_in_frame_num_value_allowed_flag = bits.read_bit()?;
+
+    let width_in_mbs = u64::from(bits.read_ue()?) + 1;
+    let height_in_map_units = u64::from(bits.read_ue()?) + 1;
+    let frame_mbs_only = bits.read_bit()?;
+    if frame_mbs_only == 0 {
+        let _mb_adaptive_frame_field_flag = bits.read_bit()?;
+    }
+    let _direct_8x8_inference_flag = bits.read_bit()?;
+
+    // Fields are half height, so interlaced pictures are twice the map units.
+    let mut width = width_in_mbs * 16;
+    let mut height = (2 - u64::from(frame_mbs_only)) * height_in_map_units * 16;
+    if bits.read_bit()? == 1 {
+        // Cropping is in chroma samples, table 6-1
+        let (crop_x, crop_y) = match (separate_colour_plane, chroma_format_idc) {
+            (true, _) | (_, 0) | (_, 3) => (1, 1),
+            (_, 1) => (2, 2),
+            _ => (2, 1),
+        };
+        let crop_y = crop_y * (2 - u64::from(frame_mbs_only));
+
+        let left = u64::from(bits.read_ue()?);
+        let right = u64::from(bits.read_ue()?);
+        let top = u64::from(bits.read_ue()?);
+        let bottom = u64::from(bits.read_ue()?);
+        width = width.saturating_sub(crop_x * (left + right));
+        height = height.saturating_sub(crop_y * (top + bottom));
+    }
+
+    let too_big = |_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, SPS picture size is out of range",
+        )
+    };
+
+    Ok(SpsInfo {
+        profile_idc,
+        constraint_flags,
+        level_idc,
+        chroma_format_idc,
+        bit_depth_luma_minus8,
+        bit_depth_chroma_minus8,
+        width: u32::try_from(width).map_err(too_big)?,
+        height: u32::try_from(height).map_err(too_big)?,
+    })
+}
+
+// Section 7.3.2.1.1.1, we don't care what's in them.
+fn skip_scaling_list(bits: &mut BitReader, size: usize) -> io::Result<()> {
+    let mut last_scale = 8;
+    let mut next_scale = 8;
+    for _ in 0..size {
+        if next_scale != 0 {
+            let delta_scale = bits.read_se()?;
+            if !(-128..=127).contains(&delta_scale) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupted input, SPS scaling list delta is out of range",
+                ));
+            }
+            next_scale = (last_scale + delta_scale + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Ok(())
+}
+
+/// Builds an AVCDecoderConfigurationRecord (ISO/IEC 14496-15 section 5.2.4.1), the
+/// payload of an FLV AVC sequence header. The NAL units shouldn't have start codes.
+pub fn decoder_configuration_record(sps: &[&[u8]], pps: &[&[u8]]) -> io::Result<Vec<u8>> {
+    let first_sps = match sps.first() {
+        Some(first) => parse_sps(first)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't build a decoder configuration without an SPS",
+            ))
+        }
+    };
+
+    if pps.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "can't build a decoder configuration without a PPS",
+        ));
+    }
+
+    let sps_count = u8::try_from(sps.len())
+        .ok()
+        .filter(|count| *count < 32)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many SPS NAL units"))?;
+    let pps_count = u8::try_from(pps.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many PPS NAL units"))?;
+
+    let mut out = Vec::new();
+    out.write_u8(1)?; // configurationVersion
+    out.write_u8(first_sps.profile_idc)?;
+    out.write_u8(first_sps.constraint_flags)?;
+    out.write_u8(first_sps.level_idc)?;
+    out.write_u8(0xfc | (NAL_LENGTH_SIZE - 1))?; // 6 reserved bits, lengthSizeMinusOne
+    out.write_u8(0xe0 | sps_count)?; // 3 reserved bits, numOfSequenceParameterSets
+    for nal in sps {
+        write_parameter_set(&mut out, nal)?;
+    }
+
+    out.write_u8(pps_count)?;
+    for nal in pps {
+        write_parameter_set(&mut out, nal)?;
+    }
+
+    if is_high_profile(first_sps.profile_idc) {
+        // Each with reserved bits set to one
+        out.write_u8(0xfc | (first_sps.chroma_format_idc & 0x3) as u8)?;
+        out.write_u8(0xf8 | (first_sps.bit_depth_luma_minus8 & 0x7) as u8)?;
+        out.write_u8(0xf8 | (first_sps.bit_depth_chroma_minus8 & 0x7) as u8)?;
+        out.write_u8(0)?; // numOfSequenceParameterSetExt
+    }
+
+    Ok(out)
+}
+
+/// Builds an AVCDecoderConfigurationRecord from the SPS and PPS NAL units
+/// in Annex-B data, like the output of x264_encoder_headers. Other NAL units
+/// (SEI, for example) are ignored.
+pub fn decoder_configuration_record_from_annexb(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sps = Vec::new();
+    let mut pps = Vec::new();
+    for nal in annexb_nal_units(data) {
+        match nal_unit_type(nal) {
+            Some(NAL_SPS) => sps.push(nal),
+            Some(NAL_PPS) => pps.push(nal),
+            _ => {}
+        }
+    }
+
+    decoder_configuration_record(&sps, &pps)
+}
+
+/// The parameter sets in an AVCDecoderConfigurationRecord, which players that
+/// want Annex-B (MPEG-TS, for one) need in-band.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecoderConfiguration {
+    pub profile_idc: u8,
+    pub level_idc: u8,
+    /// Size of the NAL unit lengths in AVCC video data
+    pub nal_length_size: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+/// Parses an AVCDecoderConfigurationRecord, like the payload of an FLV AVC
+/// sequence header.
+pub fn parse_decoder_configuration_record(mut data: &[u8]) -> io::Result<DecoderConfiguration> {
+    let version = data.read_u8()?;
+    if version != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, unknown AVC configurationVersion",
+        ));
+    }
+
+    let profile_idc = data.read_u8()?;
+    let _constraint_flags = data.read_u8()?;
+    let level_idc = data.read_u8()?;
+    let nal_length_size = (data.read_u8()? & 0x03) + 1;
+
+    let sps_count = data.read_u8()? & 0x1f;
+    let sps = read_parameter_sets(&mut data, sps_count)?;
+    let pps_count = data.read_u8()?;
+    let pps = read_parameter_sets(&mut data, pps_count)?;
+
+    // Any high profile extension fields don't matter to us.
+    Ok(DecoderConfiguration {
+        profile_idc,
+        level_idc,
+        nal_length_size,
+        sps,
+        pps,
+    })
+}
+
+fn read_parameter_sets(data: &mut &[u8], count: u8) -> io::Result<Vec<Vec<u8>>> {
+    let mut sets = Vec::new();
+    for _ in 0..count {
+        let len = usize::from(data.read_u16::<BigEndian>()?);
+        if len > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted input, parameter set is past the end of the decoder configuration",
+            ));
+        }
+
+        let (nal, rest) = data.split_at(len);
+        sets.push(nal.to_vec());
+        *data = rest;
+    }
+
+    Ok(sets)
+}
+
+fn write_parameter_set(out: &mut impl Write, nal: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(nal.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "parameter set is too long"))?;
+    out.write_u16::<BigEndian>(len)?;
+    out.write_all(nal)
+}
+
+// Removes emulation prevention bytes (the 3 in 00 00 03)
+fn unescape_rbsp(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &b in data {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+
+    out
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, bit: 0 }
+    }
+
+    fn read_bit(&mut self) -> io::Result<u32> {
+        let byte = self.data.get(self.bit / 8).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "corrupted input, SPS is truncated",
+            )
+        })?;
+        let ret = (byte >> (7 - self.bit % 8)) & 1;
+        self.bit += 1;
+        Ok(u32::from(ret))
+    }
+
+    // Exp-Golomb coded unsigned int, section 9.1 of the h264 spec
+    fn read_ue(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupted input, bad Exp-Golomb code in SPS",
+                ));
+            }
+        }
+
+        let mut suffix = 0u32;
+        for _ in 0..leading_zeros {
+            suffix = (suffix << 1) | self.read_bit()?;
+        }
+
+        Ok((1u32 << leading_zeros) - 1 + suffix)
+    }
+
+    // Exp-Golomb coded signed int, section 9.1.1
+    fn read_se(&mut self) -> io::Result<i32> {
+        let code = i64::from(self.read_ue()?);
+        let value = if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -(code / 2)
+        };
+        Ok(value as i32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From an x264 "high" profile 1280x720 encode
+    const SPS: [u8; 25] = [
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xba, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xef, 0x8f, 0xcb];
+
+    #[test]
+    fn test_annexb_nal_units() {
+        let data = [
+            0, 0, 0, 1, 0x67, 1, 2, 0, 0, 1, 0x68, 3, 0, 0, 0, 0, 1, 0x65, 4, 0, 0, 3, 1,
+        ];
+        let nals: Vec<&[u8]> = annexb_nal_units(&data).collect();
+        assert_eq!(
+            vec![&[0x67, 1, 2][..], &[0x68, 3], &[0x65, 4, 0, 0, 3, 1]],
+            nals
+        );
+    }
+
+    #[test]
+    fn test_annexb_to_avcc() {
+        let data = [0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x65, 4, 5, 6];
+        let avcc = annexb_to_avcc(&data).unwrap();
+        assert_eq!(
+            vec![0, 0, 0, 2, 0x09, 0xf0, 0, 0, 0, 4, 0x65, 4, 5, 6],
+            avcc
+        );
+        assert_eq!(
+            vec![&[0x09, 0xf0][..], &[0x65, 4, 5, 6]],
+            avcc_nal_units(&avcc).unwrap()
+        );
+        assert!(avcc_nal_units(&avcc[..avcc.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_sps() {
+        let info = parse_sps(&SPS).unwrap();
+        assert_eq!(100, info.profile_idc);
+        assert_eq!(0x1f, info.level_idc);
+        assert_eq!(1, info.chroma_format_idc);
+        assert_eq!(0, info.bit_depth_luma_minus8);
+        assert_eq!(0, info.bit_depth_chroma_minus8);
+        assert_eq!(1280, info.width);
+        assert_eq!(720, info.height);
+
+        // 1088 lines of macroblocks, cropped to 1080
+        let sps_1080p = [
+            0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0x84, 0x00, 0x00,
+            0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+        ];
+        let info = parse_sps(&sps_1080p).unwrap();
+        assert_eq!((1920, 1080), (info.width, info.height));
+    }
+
+    #[test]
+    fn test_parse_sps_bad_scaling_list() {
+        // High profile with a scaling list whose first delta_scale is i32::MAX
+        let sps = [
+            0x67, 0x64, 0x00, 0x1f, 0xad, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xfe, 0x80,
+        ];
+        let err = parse_sps(&sps).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_decoder_configuration_record() {
+        let mut annexb = vec![0, 0, 0, 1];
+        annexb.extend_from_slice(&SPS);
+        annexb.extend_from_slice(&[0, 0, 0, 1]);
+        annexb.extend_from_slice(&PPS);
+        annexb.extend_from_slice(&[0, 0, 1, 0x06, 0x05, 0x01, 0x80]); // SEI, ignored
+
+        let record = decoder_configuration_record_from_annexb(&annexb).unwrap();
+
+        let mut expected = vec![1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 25];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[1, 0, 4]);
+        expected.extend_from_slice(&PPS);
+        expected.extend_from_slice(&[0xfd, 0xf8, 0xf8, 0]);
+        assert_eq!(expected, record);
+    }
+
+    #[test]
+    fn test_parse_decoder_configuration_record() {
+        let record = decoder_configuration_record(&[&SPS], &[&PPS]).unwrap();
+        let config = parse_decoder_configuration_record(&record).unwrap();
+        assert_eq!(100, config.profile_idc);
+        assert_eq!(0x1f, config.level_idc);
+        assert_eq!(4, config.nal_length_size);
+        assert_eq!(vec![SPS.to_vec()], config.sps);
+        assert_eq!(vec![PPS.to_vec()], config.pps);
+
+        assert!(parse_decoder_configuration_record(&record[..20]).is_err());
+    }
+
+    #[test]
+    fn test_missing_parameter_sets() {
+        assert!(decoder_configuration_record(&[], &[&PPS]).is_err());
+        assert!(decoder_configuration_record(&[&SPS], &[]).is_err());
+        assert!(decoder_configuration_record(&[&PPS], &[&PPS]).is_err());
+    }
+}
@@ -1,7 +1,22 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::convert::TryFrom;
 use std::io::{self, Read, Write};
 
+pub mod aac;
+pub mod amf0;
+pub mod avc;
+mod metadata;
+mod reader;
+mod writer;
+
+pub use amf0::Amf0Value;
+pub use metadata::{read_metadata, write_metadata, Metadata, AAC_CODEC_ID, AVC_CODEC_ID};
+#[cfg(feature = "tokio")]
+pub use reader::AsyncFlvReader;
+pub use reader::{FlvParser, FlvReader, FlvTag, TagKind};
+#[cfg(feature = "tokio")]
+pub use writer::AsyncFlvWriter;
+pub use writer::{FlvStats, FlvWriter, TimestampPolicy};
+
 // From https://www.adobe.com/content/dam/acom/en/devnet/flv/video_file_format_spec_v10.pdf
 const FLV_HEADER: [u8; 9] = [
     0x46, 0x4c, 0x56, // 'FLV'
@@ -11,6 +26,13 @@ const FLV_HEADER: [u8; 9] = [
     0x09, // size of this header
 ];
 
+// Every tag is preceded by 11 bytes of tag header
+const TAG_HEADER_LENGTH: u32 = 11;
+
+// Tag data sizes are 24 bits
+const MAX_DATA_SIZE: usize = 0xff_ffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AvcPacketType {
     SequenceHeader,
     Nalu {
@@ -20,6 +42,7 @@ pub enum AvcPacketType {
     SequenceEnd,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AacAudioPacketType {
     SequenceHeader,
     Raw,
@@ -31,9 +54,11 @@ pub fn write_flv_header(out: &mut impl Write) -> io::Result<()> {
     Ok(())
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MediaType {
     Audio = 8,
     Video = 9,
+    ScriptData = 18,
 }
 
 pub fn read_audio_header(mut inf: impl Read) -> io::Result<AacAudioPacketType> {
@@ -80,9 +105,12 @@ pub fn read_video_header(mut inf: impl Read) -> io::Result<AvcPacketType> {
         0 => AvcPacketType::SequenceHeader,
         2 => AvcPacketType::SequenceEnd,
         1 => {
+            // Signed, presentation time minus decode time. Writers convert
+            // both times to milliseconds before taking the difference, so
+            // timestamp + offset is the presentation time, rounded.
             let composition_offset_millis = inf.read_i24::<BigEndian>()?;
             AvcPacketType::Nalu {
-                composition_offset_millis, // OH NO! THIS IS WRONG!
+                composition_offset_millis,
                 seekable,
             }
         }
@@ -130,13 +158,95 @@ pub fn write_audio_tag_header(
     write_media_tag_header(out, MediaType::Audio, data_size, decode_timestamp)
 }
 
-/// input timestamps should be in h264 ticks, 1/90,000 of a second.
+// Writes 11 bytes of tag header
+pub fn write_script_tag_header(
+    out: &mut impl Write,
+    data_size: u32,
+    timestamp: i32,
+) -> io::Result<()> {
+    write_media_tag_header(out, MediaType::ScriptData, data_size, timestamp)
+}
+
+/// Writes a SCRIPTDATA tag with the given AMF0 values as its body. Script tags
+/// are conventionally a string name followed by arguments, for
+/// example "onMetaData" and an ECMA array of properties.
+pub fn write_script_tag(
+    mut out: &mut impl Write,
+    timestamp: i32,
+    values: &[Amf0Value],
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    for value in values {
+        amf0::write_value(&mut body, value)?;
+    }
+
+    let data_size = data_size(body.len())?;
+
+    write_script_tag_header(&mut out, data_size, timestamp)?;
+    out.write_all(&body)?;
+
+    // Total tag length is data_size + 11 bytes tag header
+    out.write_u32::<BigEndian>(data_size + 11)?;
+
+    Ok(())
+}
+
+/// Writes an AAC audio tag. FLV AAC audio is always flagged as 44kHz, 16 bit
+/// stereo; the real sample rate and channel layout are in the AudioSpecificConfig.
+pub fn write_audio_tag(
+    mut out: &mut impl Write,
+    timestamp_millis: i32,
+    packet_type: AacAudioPacketType,
+    data: &[u8],
+) -> io::Result<()> {
+    let header = audio_packet_header(packet_type);
+    let data_size = data_size(header.len() + data.len())?;
+
+    // Tag header - 11 bytes
+    write_audio_tag_header(&mut out, data_size, timestamp_millis)?;
+    out.write_all(&header)?;
+    out.write_all(data)?;
+
+    // Total tag length is data_size + 11 bytes tag header
+    out.write_u32::<BigEndian>(data_size + 11)?;
+
+    Ok(())
+}
+
+/// Writes an AVC video tag. The decode timestamp is in milliseconds.
 pub fn write_video_tag(
     mut out: &mut impl Write,
     decode_ts_millis: i32,
     packet_type: AvcPacketType,
     data: &[u8],
 ) -> io::Result<()> {
+    let header = video_packet_header(packet_type);
+    let data_size = data_size(header.len() + data.len())?;
+
+    // Tag header - 11 bytes
+    write_video_tag_header(&mut out, data_size, decode_ts_millis)?;
+    out.write_all(&header)?;
+    out.write_all(data)?;
+
+    // Total tag length is data_size + 11 bytes tag header
+    out.write_u32::<BigEndian>(data_size + 11)?;
+
+    Ok(())
+}
+
+// The AUDIODATA header, then the AACAUDIODATA header
+fn audio_packet_header(packet_type: AacAudioPacketType) -> [u8; 2] {
+    let packet_type_code = match packet_type {
+        AacAudioPacketType::SequenceHeader => 0,
+        AacAudioPacketType::Raw => 1,
+    };
+
+    // (format 10, AAC)(rate 3, 44kHz)(size 1, 16 bit)(type 1, stereo)
+    [0xAF, packet_type_code]
+}
+
+// The VIDEODATA header, then the AVCVIDEOPACKET header
+fn video_packet_header(packet_type: AvcPacketType) -> [u8; 5] {
     let (packet_type_code, composition_offset_millis, seekable) = match packet_type {
         AvcPacketType::SequenceHeader => (0, 0, true),
         AvcPacketType::SequenceEnd => (2, 0, true),
@@ -146,25 +256,24 @@ pub fn write_video_tag(
         } => (1, composition_offset_millis, seekable),
     };
 
-    // Data length is data.len() + 1 byte videodata header + 4 bytes avcvideopacket header
-    let data_size = u32::try_from(data.len()).unwrap() + 1 + 4;
-
-    // Tag header - 11 bytes
-    write_video_tag_header(&mut out, data_size, decode_ts_millis)?;
-
-    // VIDEODATA header - one byte
     let frametype = if seekable { 1u8 << 4 } else { 2u8 << 4 };
     let codec_id = 7u8; // AVC codec
-    out.write_u8(frametype | codec_id)?;
-
-    // AVCVIDEOPACKET header - 4 bytes
-    out.write_u8(packet_type_code)?;
-    out.write_i24::<BigEndian>(composition_offset_millis)?;
-
-    out.write_all(data)?;
-
-    // Total tag length is data_size + 11 bytes tag header
-    out.write_u32::<BigEndian>(data_size + 11)?;
+    let offset = composition_offset_millis.to_be_bytes();
+    [
+        frametype | codec_id,
+        packet_type_code,
+        offset[1],
+        offset[2],
+        offset[3],
+    ]
+}
 
-    Ok(())
+fn data_size(size: usize) -> io::Result<u32> {
+    if size > MAX_DATA_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes is too large for an FLV tag", size),
+        ));
+    }
+    Ok(size as u32)
 }
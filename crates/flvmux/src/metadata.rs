@@ -0,0 +1,139 @@
+use std::io::{self, Write};
+
+use crate::{amf0, write_script_tag, Amf0Value};
+
+/// FLV codec id for AVC video, as used in the VIDEODATA header
+pub const AVC_CODEC_ID: f64 = 7.0;
+
+/// FLV sound format id for AAC audio, as used in the AUDIODATA header
+pub const AAC_CODEC_ID: f64 = 10.0;
+
+/// The well known onMetaData properties. Missing values are left out of the tag.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    /// In seconds. Live streams should leave this out (or set it to zero).
+    pub duration: Option<f64>,
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    /// Frames per second
+    pub framerate: Option<f64>,
+    pub videocodecid: Option<f64>,
+    /// In kilobits per second
+    pub videodatarate: Option<f64>,
+    pub audiocodecid: Option<f64>,
+    /// In kilobits per second
+    pub audiodatarate: Option<f64>,
+    /// In hertz
+    pub audiosamplerate: Option<f64>,
+    /// In bits
+    pub audiosamplesize: Option<f64>,
+    pub stereo: Option<bool>,
+    pub encoder: Option<String>,
+}
+
+impl Metadata {
+    pub fn to_amf0(&self) -> Amf0Value {
+        let numbers = [
+            ("duration", self.duration),
+            ("width", self.width),
+            ("height", self.height),
+            ("framerate", self.framerate),
+            ("videocodecid", self.videocodecid),
+            ("videodatarate", self.videodatarate),
+            ("audiocodecid", self.audiocodecid),
+            ("audiodatarate", self.audiodatarate),
+            ("audiosamplerate", self.audiosamplerate),
+            ("audiosamplesize", self.audiosamplesize),
+        ];
+
+        let mut properties: Vec<(String, Amf0Value)> = numbers
+            .iter()
+            .filter_map(|(name, value)| value.map(|v| (name.to_string(), Amf0Value::Number(v))))
+            .collect();
+
+        if let Some(stereo) = self.stereo {
+            properties.push(("stereo".into(), Amf0Value::Boolean(stereo)));
+        }
+
+        if let Some(encoder) = &self.encoder {
+            properties.push(("encoder".into(), Amf0Value::String(encoder.clone())));
+        }
+
+        Amf0Value::EcmaArray(properties)
+    }
+
+    /// Picks the well known properties out of a decoded onMetaData object (or
+    /// ECMA array). Properties of the wrong type are ignored.
+    pub fn from_amf0(value: &Amf0Value) -> Self {
+        let number = |name| value.get(name).and_then(Amf0Value::as_number);
+        Metadata {
+            duration: number("duration"),
+            width: number("width"),
+            height: number("height"),
+            framerate: number("framerate"),
+            videocodecid: number("videocodecid"),
+            videodatarate: number("videodatarate"),
+            audiocodecid: number("audiocodecid"),
+            audiodatarate: number("audiodatarate"),
+            audiosamplerate: number("audiosamplerate"),
+            audiosamplesize: number("audiosamplesize"),
+            stereo: value.get("stereo").and_then(Amf0Value::as_bool),
+            encoder: value
+                .get("encoder")
+                .and_then(Amf0Value::as_str)
+                .map(String::from),
+        }
+    }
+}
+
+/// Decodes a SCRIPTDATA tag body, returning the metadata if the tag is an onMetaData tag.
+pub fn read_metadata(data: &[u8]) -> io::Result<Option<Metadata>> {
+    let values = amf0::read_values(data)?;
+    match values.as_slice() {
+        [Amf0Value::String(name), properties, ..] if name == "onMetaData" => {
+            Ok(Some(Metadata::from_amf0(properties)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Writes an onMetaData script tag at timestamp zero. This should come
+/// directly after the FLV header, before any audio or video tags.
+pub fn write_metadata(out: &mut impl Write, metadata: &Metadata) -> io::Result<()> {
+    write_script_tag(
+        out,
+        0,
+        &[Amf0Value::String("onMetaData".into()), metadata.to_amf0()],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FlvReader, TagKind};
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let metadata = Metadata {
+            width: Some(1280.0),
+            height: Some(720.0),
+            framerate: Some(30.0),
+            videocodecid: Some(AVC_CODEC_ID),
+            stereo: Some(true),
+            encoder: Some("test".into()),
+            ..Metadata::default()
+        };
+
+        let mut out = Vec::new();
+        crate::write_flv_header(&mut out).unwrap();
+        write_metadata(&mut out, &metadata).unwrap();
+
+        let tag = FlvReader::new(&out[..])
+            .unwrap()
+            .read_tag()
+            .unwrap()
+            .unwrap();
+        assert_eq!(TagKind::ScriptData, tag.kind);
+        assert_eq!(Some(metadata), read_metadata(&tag.payload).unwrap());
+    }
+}
@@ -0,0 +1,578 @@
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use std::io::{self, Read};
+
+use crate::{
+    audio_packet_header, read_audio_header, read_video_header, video_packet_header,
+    AacAudioPacketType, AvcPacketType, MediaType, TAG_HEADER_LENGTH,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagKind {
+    Audio(AacAudioPacketType),
+    Video(AvcPacketType),
+    ScriptData,
+}
+
+#[derive(Clone, Debug)]
+pub struct FlvTag {
+    /// Offset of the start of the tag header from the beginning of the input.
+    pub offset: u64,
+    /// Size of the tag body, not including the 11 byte tag header.
+    pub data_size: u32,
+    pub timestamp: i32,
+    pub kind: TagKind,
+    /// The tag body following the AUDIODATA / VIDEODATA packet headers. For
+    /// script data tags, this is the whole (AMF encoded) tag body.
+    pub payload: Vec<u8>,
+}
+
+impl FlvTag {
+    /// Size of the whole tag, header included. This is what the trailing
+    /// previous tag size check should contain.
+    pub fn tag_size(&self) -> u32 {
+        self.data_size + TAG_HEADER_LENGTH
+    }
+
+    pub fn media_type(&self) -> MediaType {
+        match self.kind {
+            TagKind::Audio(_) => MediaType::Audio,
+            TagKind::Video(_) => MediaType::Video,
+            TagKind::ScriptData => MediaType::ScriptData,
+        }
+    }
+
+    /// The tag body as it was in the FLV, with the packet headers put back
+    /// in front of the payload.
+    pub fn body(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.payload.len() + 5);
+        match self.kind {
+            TagKind::Audio(packet_type) => {
+                body.extend_from_slice(&audio_packet_header(packet_type))
+            }
+            TagKind::Video(packet_type) => {
+                body.extend_from_slice(&video_packet_header(packet_type))
+            }
+            TagKind::ScriptData => {}
+        }
+        body.extend_from_slice(&self.payload);
+        body
+    }
+}
+
+/// Reads FLV tags, one at a time, from any source. Checks the FLV file header,
+/// and the size check that follows every tag.
+pub struct FlvReader<R: Read> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Read> FlvReader<R> {
+    /// Reads and validates the FLV header (and the first, always zero, previous tag size)
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let header_size = read_header(&mut inner)?;
+        Ok(FlvReader {
+            inner,
+            offset: u64::from(header_size) + 4,
+        })
+    }
+
+    /// Returns None on a clean end of input, that is, an EOF directly after a
+    /// previous tag size check.
+    pub fn read_tag(&mut self) -> io::Result<Option<FlvTag>> {
+        let tag = read_tag(&mut self.inner, self.offset)?;
+        if let Some(tag) = &tag {
+            self.offset += u64::from(tag.tag_size()) + 4;
+        }
+
+        Ok(tag)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Iterator for FlvReader<R> {
+    type Item = io::Result<FlvTag>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_tag().transpose()
+    }
+}
+
+/// Splits FLV that arrives in arbitrary pieces (like the buffers handed to a
+/// Write) into tags, with the same checks as FlvReader.
+#[derive(Default)]
+pub struct FlvParser {
+    buffer: Vec<u8>,
+    state: ParserState,
+}
+
+#[derive(Default)]
+enum ParserState {
+    // Waiting for the first nine bytes of the header
+    #[default]
+    Header,
+    // Discarding the rest of a longer header as it arrives, then checking
+    // the first previous tag size
+    SkipHeader {
+        header_size: u32,
+        remaining: u64,
+    },
+    Tags {
+        offset: u64,
+    },
+}
+
+impl FlvParser {
+    pub fn new() -> Self {
+        FlvParser::default()
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete tag pushed so far, or None if we need more input.
+    pub fn next_tag(&mut self) -> io::Result<Option<FlvTag>> {
+        loop {
+            match self.state {
+                ParserState::Header => {
+                    if self.buffer.len() < 9 {
+                        return Ok(None);
+                    }
+
+                    let header_size = read_header_start(&self.buffer[..9])?;
+                    self.buffer.drain(..9);
+                    self.state = ParserState::SkipHeader {
+                        header_size,
+                        remaining: u64::from(header_size - 9),
+                    };
+                }
+                ParserState::SkipHeader {
+                    header_size,
+                    remaining,
+                } => {
+                    let skip = remaining.min(self.buffer.len() as u64);
+                    self.buffer.drain(..skip as usize);
+                    let remaining = remaining - skip;
+                    self.state = ParserState::SkipHeader {
+                        header_size,
+                        remaining,
+                    };
+                    if remaining > 0 || self.buffer.len() < 4 {
+                        return Ok(None);
+                    }
+
+                    check_first_previous_size(BigEndian::read_u32(&self.buffer[..4]))?;
+                    self.buffer.drain(..4);
+                    self.state = ParserState::Tags {
+                        offset: u64::from(header_size) + 4,
+                    };
+                }
+                ParserState::Tags { offset } => return self.next_tag_at(offset),
+            }
+        }
+    }
+
+    fn next_tag_at(&mut self, offset: u64) -> io::Result<Option<FlvTag>> {
+        if self.buffer.len() < TAG_HEADER_LENGTH as usize {
+            return Ok(None);
+        }
+
+        let data_size = BigEndian::read_u24(&self.buffer[1..4]);
+        let size = (data_size + TAG_HEADER_LENGTH) as usize + 4;
+        if self.buffer.len() < size {
+            return Ok(None);
+        }
+
+        let tag = read_tag(&self.buffer[..size], offset)?;
+        self.buffer.drain(..size);
+        self.state = ParserState::Tags {
+            offset: offset + size as u64,
+        };
+        Ok(tag)
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use self::async_reader::AsyncFlvReader;
+
+#[cfg(feature = "tokio")]
+mod async_reader {
+    use byteorder::{BigEndian, ByteOrder};
+    use std::io;
+
+    use tokio::io::{AsyncRead, AsyncReadExt};
+
+    use super::{check_first_previous_size, read_header_start, read_tag, FlvTag};
+    use crate::TAG_HEADER_LENGTH;
+
+    /// FlvReader for an AsyncRead, with the same checks.
+    pub struct AsyncFlvReader<R: AsyncRead + Unpin> {
+        inner: R,
+        offset: u64,
+        // One tag at a time, handed to the blocking parser
+        buffer: Vec<u8>,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncFlvReader<R> {
+        /// Reads and validates the FLV header (and the first, always zero, previous tag size)
+        pub async fn new(mut inner: R) -> io::Result<Self> {
+            let mut buffer = vec![0u8; 9];
+            inner.read_exact(&mut buffer).await?;
+            let header_size = read_header_start(&buffer[..])?;
+
+            // Skipping any longer header as it streams past, rather than
+            // buffering however much the header says it has
+            let extra = u64::from(header_size - 9);
+            tokio::io::copy(&mut (&mut inner).take(extra), &mut tokio::io::sink()).await?;
+            check_first_previous_size(inner.read_u32().await?)?;
+
+            Ok(AsyncFlvReader {
+                inner,
+                offset: u64::from(header_size) + 4,
+                buffer,
+            })
+        }
+
+        /// Returns None on a clean end of input, that is, an EOF directly
+        /// after a previous tag size check.
+        pub async fn read_tag(&mut self) -> io::Result<Option<FlvTag>> {
+            let header_length = TAG_HEADER_LENGTH as usize;
+            self.buffer.resize(header_length, 0);
+            if self.inner.read(&mut self.buffer[..1]).await? == 0 {
+                return Ok(None);
+            }
+            self.inner.read_exact(&mut self.buffer[1..]).await?;
+
+            // The body, then the previous tag size
+            let data_size = BigEndian::read_u24(&self.buffer[1..4]) as usize;
+            self.buffer.resize(header_length + data_size + 4, 0);
+            self.inner
+                .read_exact(&mut self.buffer[header_length..])
+                .await?;
+
+            let tag = read_tag(&self.buffer[..], self.offset)?;
+            if let Some(tag) = &tag {
+                self.offset += u64::from(tag.tag_size()) + 4;
+            }
+
+            Ok(tag)
+        }
+
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+    }
+}
+
+// Returns the header size, not counting the first previous tag size
+fn read_header(mut inner: impl Read) -> io::Result<u32> {
+    let header_size = read_header_start(&mut inner)?;
+
+    // Later versions of the format may have a longer header, which we skip.
+    io::copy(
+        &mut (&mut inner).take(u64::from(header_size - 9)),
+        &mut io::sink(),
+    )?;
+
+    check_first_previous_size(inner.read_u32::<BigEndian>()?)?;
+    Ok(header_size)
+}
+
+// The first nine bytes of the header, which all versions have. Returns the
+// header size, at least nine.
+fn read_header_start(mut inner: impl Read) -> io::Result<u32> {
+    let mut signature = [0u8; 3];
+    inner.read_exact(&mut signature)?;
+    if &signature != b"FLV" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, missing FLV signature",
+        ));
+    }
+
+    let version = inner.read_u8()?;
+    if version != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported FLV version {}", version),
+        ));
+    }
+
+    let _flags = inner.read_u8()?;
+    let header_size = inner.read_u32::<BigEndian>()?;
+    if header_size < 9 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, FLV header is too short",
+        ));
+    }
+
+    Ok(header_size)
+}
+
+fn check_first_previous_size(size: u32) -> io::Result<()> {
+    if size != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, first previous tag size must be zero",
+        ));
+    }
+
+    Ok(())
+}
+
+fn read_tag(mut inner: impl Read, offset: u64) -> io::Result<Option<FlvTag>> {
+    let tagtype = match read_first_byte(&mut inner)? {
+        None => return Ok(None),
+        Some(b) => b,
+    };
+
+    if tagtype & 0x20 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported input, encrypted FLV tags aren't supported",
+        ));
+    }
+
+    let data_size = inner.read_u24::<BigEndian>()?;
+    let low_timestamp = inner.read_u24::<BigEndian>()?;
+    let high_timestamp = inner.read_u8()?;
+    let stream_id = inner.read_u24::<BigEndian>()?;
+    if stream_id != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, stream id != 0",
+        ));
+    }
+
+    let timestamp = ((u32::from(high_timestamp) << 24) | low_timestamp) as i32;
+
+    let mut body = vec![0u8; data_size as usize];
+    inner.read_exact(&mut body)?;
+
+    let (kind, header_length) = match tagtype & 0x1f {
+        8 => (TagKind::Audio(read_audio_header(&body[..])?), 2),
+        9 => (TagKind::Video(read_video_header(&body[..])?), 5),
+        18 => (TagKind::ScriptData, 0),
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted input, invalid FLV tag type {}", other),
+            ))
+        }
+    };
+
+    // The packet headers above may not have needed all of header_length
+    if body.len() < header_length {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, tag is too short for its packet header",
+        ));
+    }
+
+    let check_previous_size = inner.read_u32::<BigEndian>()?;
+    if check_previous_size != data_size + TAG_HEADER_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted input, expected size check didn't match",
+        ));
+    }
+
+    body.drain(..header_length);
+
+    Ok(Some(FlvTag {
+        offset,
+        data_size,
+        timestamp,
+        kind,
+        payload: body,
+    }))
+}
+
+// Like read_u8, but tells us about a clean EOF rather than returning an error.
+fn read_first_byte(mut inf: impl Read) -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    loop {
+        match inf.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        write_audio_tag_header, write_flv_header, write_video_tag, write_video_tag_header,
+    };
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+
+    fn sample_flv() -> Vec<u8> {
+        let mut out = Vec::new();
+        write_flv_header(&mut out).unwrap();
+        write_video_tag(&mut out, 0, AvcPacketType::SequenceHeader, &[1, 2, 3]).unwrap();
+
+        write_audio_tag_header(&mut out, 4, 23).unwrap();
+        out.write_all(&[0xAF, 1, 7, 8]).unwrap();
+        out.write_u32::<BigEndian>(15).unwrap();
+
+        write_video_tag(
+            &mut out,
+            0x01000021, // needs the extended timestamp byte
+            AvcPacketType::Nalu {
+                composition_offset_millis: 66,
+                seekable: false,
+            },
+            &[4, 5],
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_read_tags() {
+        let tags = FlvReader::new(&sample_flv()[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>()
+            .unwrap();
+
+        assert_eq!(3, tags.len());
+
+        assert_eq!(13, tags[0].offset);
+        assert_eq!(TagKind::Video(AvcPacketType::SequenceHeader), tags[0].kind);
+        assert_eq!(vec![1, 2, 3], tags[0].payload);
+
+        assert_eq!(13 + 19 + 4, tags[1].offset);
+        assert_eq!(23, tags[1].timestamp);
+        assert_eq!(TagKind::Audio(AacAudioPacketType::Raw), tags[1].kind);
+        assert_eq!(vec![7, 8], tags[1].payload);
+
+        assert_eq!(0x01000021, tags[2].timestamp);
+        assert_eq!(
+            TagKind::Video(AvcPacketType::Nalu {
+                composition_offset_millis: 66,
+                seekable: false
+            }),
+            tags[2].kind
+        );
+        assert_eq!(vec![4, 5], tags[2].payload);
+
+        assert_eq!(MediaType::Audio, tags[1].media_type());
+        assert_eq!(vec![0xaf, 1, 7, 8], tags[1].body());
+        assert_eq!(vec![0x27, 1, 0, 0, 66, 4, 5], tags[2].body());
+    }
+
+    #[test]
+    fn test_parser() {
+        let flv = sample_flv();
+        let mut parser = FlvParser::new();
+        let mut tags = Vec::new();
+        for chunk in flv.chunks(7) {
+            parser.push(chunk);
+            while let Some(tag) = parser.next_tag().unwrap() {
+                tags.push(tag);
+            }
+        }
+
+        assert_eq!(3, tags.len());
+        assert_eq!(13 + 19 + 4, tags[1].offset);
+        assert_eq!(vec![7, 8], tags[1].payload);
+        assert_eq!(0x01000021, tags[2].timestamp);
+
+        let mut parser = FlvParser::new();
+        parser.push(b"FLX\x01\x05\0\0\0\x09\0\0\0\0");
+        assert!(parser.next_tag().is_err());
+    }
+
+    #[test]
+    fn test_bad_size_check() {
+        let mut flv = sample_flv();
+        let last = flv.len() - 1;
+        flv[last] ^= 0xff;
+
+        let result = FlvReader::new(&flv[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>();
+        assert_eq!(io::ErrorKind::InvalidData, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn test_truncated_tag() {
+        let flv = sample_flv();
+        let mut reader = FlvReader::new(&flv[..flv.len() - 6]).unwrap();
+        assert!(reader.read_tag().unwrap().is_some());
+        assert!(reader.read_tag().unwrap().is_some());
+        assert!(reader.read_tag().is_err());
+    }
+
+    #[test]
+    fn test_truncated_video_tag() {
+        // A sequence header packet, missing its composition time
+        let mut flv = Vec::new();
+        write_flv_header(&mut flv).unwrap();
+        write_video_tag_header(&mut flv, 2, 0).unwrap();
+        flv.write_all(&[0x17, 0]).unwrap();
+        flv.write_u32::<BigEndian>(13).unwrap();
+
+        let mut reader = FlvReader::new(&flv[..]).unwrap();
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            reader.read_tag().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_parser_long_header() {
+        // A header with three extra bytes, which we skip
+        let flv = sample_flv();
+        let mut longer = flv[..9].to_vec();
+        longer[8] = 12;
+        longer.extend_from_slice(&[0xaa; 3]);
+        longer.extend_from_slice(&flv[9..]);
+
+        let mut parser = FlvParser::new();
+        let mut tags = Vec::new();
+        for chunk in longer.chunks(2) {
+            parser.push(chunk);
+            while let Some(tag) = parser.next_tag().unwrap() {
+                tags.push(tag);
+            }
+        }
+        assert_eq!(3, tags.len());
+        assert_eq!(16, tags[0].offset);
+
+        // A header that claims to be 4 GiB is discarded as it arrives
+        let mut flv = sample_flv();
+        flv[5..9].copy_from_slice(&[0xff; 4]);
+        let mut parser = FlvParser::new();
+        for chunk in flv.chunks(7) {
+            parser.push(chunk);
+            assert!(parser.next_tag().unwrap().is_none());
+            assert!(parser.buffer.len() < 9);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_long_header() {
+        // A header that claims to be 4 GiB, on a short file
+        let mut flv = sample_flv();
+        flv[5..9].copy_from_slice(&[0xff; 4]);
+        let err = AsyncFlvReader::new(&flv[..]).await.err().unwrap();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_bad_signature() {
+        let mut flv = sample_flv();
+        flv[0] = b'X';
+        assert!(FlvReader::new(&flv[..]).is_err());
+    }
+}
@@ -0,0 +1,559 @@
+use std::cmp;
+use std::io::{self, Write};
+
+use crate::{
+    amf0, audio_packet_header, data_size, video_packet_header, write_flv_header,
+    write_media_tag_header, AacAudioPacketType, Amf0Value, AvcPacketType, MediaType, Metadata,
+    TAG_HEADER_LENGTH,
+};
+
+/// What FlvWriter does with an audio or video tag that's earlier than the
+/// last one on its track. Players tend to stall or skip on those.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// Refuse the tag with an InvalidInput error, writing nothing.
+    #[default]
+    Reject,
+    /// Write the tag with the track's last timestamp instead.
+    Clamp,
+}
+
+/// What an FlvWriter has written so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlvStats {
+    /// Including the FLV header
+    pub bytes: u64,
+    pub video_tags: u64,
+    pub audio_tags: u64,
+    pub script_tags: u64,
+    /// Seekable video tags, sequence headers aside
+    pub keyframes: u64,
+    /// Tags written with a later timestamp than they came with, under
+    /// TimestampPolicy::Clamp
+    pub timestamps_clamped: u64,
+    /// Earliest and latest audio or video timestamps, in milliseconds
+    pub first_timestamp: Option<i32>,
+    pub last_timestamp: Option<i32>,
+}
+
+impl FlvStats {
+    /// From the earliest audio or video tag to the latest, in milliseconds
+    pub fn duration_millis(&self) -> i64 {
+        match (self.first_timestamp, self.last_timestamp) {
+            (Some(first), Some(last)) => i64::from(last) - i64::from(first),
+            _ => 0,
+        }
+    }
+}
+
+/// Writes an FLV stream: the header, then whole tags, each followed by its
+/// previous tag size. Audio and video timestamps are checked (or clamped,
+/// see TimestampPolicy) so each track's never go backwards.
+pub struct FlvWriter<W: Write> {
+    out: W,
+    state: WriterState,
+}
+
+impl<W: Write> FlvWriter<W> {
+    /// Writes the FLV header.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        write_flv_header(&mut out)?;
+        Ok(FlvWriter {
+            out,
+            state: WriterState::default(),
+        })
+    }
+
+    pub fn timestamp_policy(mut self, policy: TimestampPolicy) -> Self {
+        self.state.policy = policy;
+        self
+    }
+
+    /// Writes an AVC video tag. The decode timestamp is in milliseconds.
+    pub fn write_video(
+        &mut self,
+        decode_ts_millis: i32,
+        packet_type: AvcPacketType,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let header = video_packet_header(packet_type);
+        self.write_tag(MediaType::Video, decode_ts_millis, &header, data)
+    }
+
+    pub fn write_audio(
+        &mut self,
+        timestamp_millis: i32,
+        packet_type: AacAudioPacketType,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let header = audio_packet_header(packet_type);
+        self.write_tag(MediaType::Audio, timestamp_millis, &header, data)
+    }
+
+    /// Writes a SCRIPTDATA tag with the given AMF0 values as its body.
+    pub fn write_script(&mut self, timestamp: i32, values: &[Amf0Value]) -> io::Result<()> {
+        let body = script_body(values)?;
+        self.write_tag(MediaType::ScriptData, timestamp, &[], &body)
+    }
+
+    pub fn write_metadata(&mut self, metadata: &Metadata) -> io::Result<()> {
+        self.write_script(0, &metadata_values(metadata))
+    }
+
+    /// Writes a tag body as it is, AUDIODATA or VIDEODATA header and all,
+    /// like the body of a tag from another FLV stream or an RTMP message.
+    pub fn write_raw_tag(
+        &mut self,
+        media_type: MediaType,
+        timestamp: i32,
+        body: &[u8],
+    ) -> io::Result<()> {
+        self.write_tag(media_type, timestamp, &[], body)
+    }
+
+    pub fn stats(&self) -> FlvStats {
+        self.state.stats
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    /// Writing to the output directly will confuse the next tag's
+    /// previous tag size, but taking what's been written (from a Vec, say)
+    /// is fine.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_tag(
+        &mut self,
+        media_type: MediaType,
+        timestamp: i32,
+        header: &[u8],
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (tag_header, timestamp) = self.state.tag_header(media_type, timestamp, header, data)?;
+
+        self.out.write_all(&tag_header)?;
+        self.out.write_all(header)?;
+        self.out.write_all(data)?;
+        self.out.write_all(&tag_trailer(header, data))?;
+
+        self.state.record(media_type, timestamp, header, data);
+        Ok(())
+    }
+}
+
+/// The timestamp checks and stats shared by FlvWriter and AsyncFlvWriter
+struct WriterState {
+    policy: TimestampPolicy,
+    last_audio: Option<i32>,
+    last_video: Option<i32>,
+    stats: FlvStats,
+}
+
+impl Default for WriterState {
+    fn default() -> Self {
+        WriterState {
+            policy: TimestampPolicy::default(),
+            last_audio: None,
+            last_video: None,
+            stats: FlvStats {
+                bytes: 13, // the FLV header and the first previous tag size
+                ..FlvStats::default()
+            },
+        }
+    }
+}
+
+impl WriterState {
+    // The 11 byte header for a tag, and the timestamp it ended up with
+    fn tag_header(
+        &mut self,
+        media_type: MediaType,
+        timestamp: i32,
+        header: &[u8],
+        data: &[u8],
+    ) -> io::Result<(Vec<u8>, i32)> {
+        let data_size = data_size(header.len() + data.len())?;
+        let timestamp = self.check_timestamp(media_type, timestamp)?;
+
+        let mut tag_header = Vec::with_capacity(TAG_HEADER_LENGTH as usize);
+        write_media_tag_header(&mut tag_header, media_type, data_size, timestamp)?;
+        Ok((tag_header, timestamp))
+    }
+
+    // Call once the tag has been written
+    fn record(&mut self, media_type: MediaType, timestamp: i32, header: &[u8], data: &[u8]) {
+        let stats = &mut self.stats;
+        stats.bytes += (header.len() + data.len()) as u64 + u64::from(TAG_HEADER_LENGTH) + 4;
+        match media_type {
+            MediaType::Audio => {
+                self.last_audio = Some(timestamp);
+                stats.audio_tags += 1;
+            }
+            MediaType::Video => {
+                self.last_video = Some(timestamp);
+                stats.video_tags += 1;
+
+                // (frame type 1, seekable), and a NALU packet
+                let mut body = header.iter().chain(data);
+                if let (Some(frame_type), Some(1)) = (body.next(), body.next()) {
+                    if frame_type >> 4 == 1 {
+                        stats.keyframes += 1;
+                    }
+                }
+            }
+            MediaType::ScriptData => stats.script_tags += 1,
+        }
+
+        if media_type != MediaType::ScriptData {
+            stats.first_timestamp = Some(
+                stats
+                    .first_timestamp
+                    .map_or(timestamp, |first| cmp::min(first, timestamp)),
+            );
+            stats.last_timestamp = Some(
+                stats
+                    .last_timestamp
+                    .map_or(timestamp, |last| cmp::max(last, timestamp)),
+            );
+        }
+    }
+
+    // The timestamp to write, given the policy and the track's last one
+    fn check_timestamp(&mut self, media_type: MediaType, timestamp: i32) -> io::Result<i32> {
+        let (track, last) = match media_type {
+            MediaType::Audio => ("audio", self.last_audio),
+            MediaType::Video => ("video", self.last_video),
+            MediaType::ScriptData => return Ok(timestamp),
+        };
+
+        match last {
+            Some(last) if timestamp < last => match self.policy {
+                TimestampPolicy::Reject => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} timestamps must not go backwards, got {} after {}",
+                        track, timestamp, last
+                    ),
+                )),
+                TimestampPolicy::Clamp => {
+                    self.stats.timestamps_clamped += 1;
+                    Ok(last)
+                }
+            },
+            _ => Ok(timestamp),
+        }
+    }
+}
+
+// The previous tag size that follows every tag. Sizes were checked by
+// tag_header.
+fn tag_trailer(header: &[u8], data: &[u8]) -> [u8; 4] {
+    let size = (header.len() + data.len()) as u32 + TAG_HEADER_LENGTH;
+    size.to_be_bytes()
+}
+
+fn script_body(values: &[Amf0Value]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    for value in values {
+        amf0::write_value(&mut body, value)?;
+    }
+    Ok(body)
+}
+
+fn metadata_values(metadata: &Metadata) -> [Amf0Value; 2] {
+    [Amf0Value::String("onMetaData".into()), metadata.to_amf0()]
+}
+
+#[cfg(feature = "tokio")]
+pub use self::async_writer::AsyncFlvWriter;
+
+#[cfg(feature = "tokio")]
+mod async_writer {
+    use std::io;
+
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+    use super::{metadata_values, script_body, tag_trailer, WriterState};
+    use crate::{
+        audio_packet_header, video_packet_header, AacAudioPacketType, Amf0Value, AvcPacketType,
+        FlvStats, MediaType, Metadata, TimestampPolicy, FLV_HEADER,
+    };
+
+    /// FlvWriter for an AsyncWrite. Tags go straight to the output (there's
+    /// no buffering here, so wrap it in a BufWriter if that matters).
+    pub struct AsyncFlvWriter<W: AsyncWrite + Unpin> {
+        out: W,
+        state: WriterState,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncFlvWriter<W> {
+        /// Writes the FLV header.
+        pub async fn new(mut out: W) -> io::Result<Self> {
+            out.write_all(&FLV_HEADER).await?;
+            out.write_all(&[0; 4]).await?; // previous tag size is zero
+            Ok(AsyncFlvWriter {
+                out,
+                state: WriterState::default(),
+            })
+        }
+
+        pub fn timestamp_policy(mut self, policy: TimestampPolicy) -> Self {
+            self.state.policy = policy;
+            self
+        }
+
+        pub async fn write_video(
+            &mut self,
+            decode_ts_millis: i32,
+            packet_type: AvcPacketType,
+            data: &[u8],
+        ) -> io::Result<()> {
+            let header = video_packet_header(packet_type);
+            self.write_tag(MediaType::Video, decode_ts_millis, &header, data)
+                .await
+        }
+
+        pub async fn write_audio(
+            &mut self,
+            timestamp_millis: i32,
+            packet_type: AacAudioPacketType,
+            data: &[u8],
+        ) -> io::Result<()> {
+            let header = audio_packet_header(packet_type);
+            self.write_tag(MediaType::Audio, timestamp_millis, &header, data)
+                .await
+        }
+
+        pub async fn write_script(
+            &mut self,
+            timestamp: i32,
+            values: &[Amf0Value],
+        ) -> io::Result<()> {
+            let body = script_body(values)?;
+            self.write_tag(MediaType::ScriptData, timestamp, &[], &body)
+                .await
+        }
+
+        pub async fn write_metadata(&mut self, metadata: &Metadata) -> io::Result<()> {
+            self.write_script(0, &metadata_values(metadata)).await
+        }
+
+        /// See FlvWriter::write_raw_tag
+        pub async fn write_raw_tag(
+            &mut self,
+            media_type: MediaType,
+            timestamp: i32,
+            body: &[u8],
+        ) -> io::Result<()> {
+            self.write_tag(media_type, timestamp, &[], body).await
+        }
+
+        pub fn stats(&self) -> FlvStats {
+            self.state.stats
+        }
+
+        pub fn get_ref(&self) -> &W {
+            &self.out
+        }
+
+        pub async fn flush(&mut self) -> io::Result<()> {
+            self.out.flush().await
+        }
+
+        pub async fn shutdown(&mut self) -> io::Result<()> {
+            self.out.shutdown().await
+        }
+
+        pub fn into_inner(self) -> W {
+            self.out
+        }
+
+        async fn write_tag(
+            &mut self,
+            media_type: MediaType,
+            timestamp: i32,
+            header: &[u8],
+            data: &[u8],
+        ) -> io::Result<()> {
+            let (tag_header, timestamp) =
+                self.state.tag_header(media_type, timestamp, header, data)?;
+
+            self.out.write_all(&tag_header).await?;
+            self.out.write_all(header).await?;
+            self.out.write_all(data).await?;
+            self.out.write_all(&tag_trailer(header, data)).await?;
+
+            self.state.record(media_type, timestamp, header, data);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{write_audio_tag, write_flv_header, write_video_tag, FlvReader, FlvTag, TagKind};
+
+    const KEYFRAME: AvcPacketType = AvcPacketType::Nalu {
+        composition_offset_millis: 33,
+        seekable: true,
+    };
+
+    #[test]
+    fn test_flv_writer() {
+        let mut writer = FlvWriter::new(Vec::new()).unwrap();
+        writer
+            .write_video(0, AvcPacketType::SequenceHeader, &[1, 2, 3])
+            .unwrap();
+        writer
+            .write_audio(0, AacAudioPacketType::SequenceHeader, &[0x12, 0x10])
+            .unwrap();
+        writer.write_video(0, KEYFRAME, &[4, 5]).unwrap();
+        writer
+            .write_audio(23, AacAudioPacketType::Raw, &[6])
+            .unwrap();
+        // The same as tags from somewhere else: an inter frame at 33ms
+        writer
+            .write_raw_tag(MediaType::Video, 33, &[0x27, 1, 0, 0, 0, 7])
+            .unwrap();
+
+        let stats = writer.stats();
+        let out = writer.into_inner();
+
+        // Byte for byte what the tag functions write
+        let mut expected = Vec::new();
+        write_flv_header(&mut expected).unwrap();
+        write_video_tag(&mut expected, 0, AvcPacketType::SequenceHeader, &[1, 2, 3]).unwrap();
+        write_audio_tag(
+            &mut expected,
+            0,
+            AacAudioPacketType::SequenceHeader,
+            &[0x12, 0x10],
+        )
+        .unwrap();
+        write_video_tag(&mut expected, 0, KEYFRAME, &[4, 5]).unwrap();
+        write_audio_tag(&mut expected, 23, AacAudioPacketType::Raw, &[6]).unwrap();
+        let inter = AvcPacketType::Nalu {
+            composition_offset_millis: 0,
+            seekable: false,
+        };
+        write_video_tag(&mut expected, 33, inter, &[7]).unwrap();
+        assert_eq!(expected, out);
+
+        assert_eq!(
+            FlvStats {
+                bytes: out.len() as u64,
+                video_tags: 3,
+                audio_tags: 2,
+                script_tags: 0,
+                keyframes: 1,
+                timestamps_clamped: 0,
+                first_timestamp: Some(0),
+                last_timestamp: Some(33),
+            },
+            stats
+        );
+        assert_eq!(33, stats.duration_millis());
+
+        let tags = FlvReader::new(&out[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>()
+            .unwrap();
+        assert_eq!(5, tags.len());
+    }
+
+    #[test]
+    fn test_timestamp_policy() {
+        let mut writer = FlvWriter::new(Vec::new()).unwrap();
+        writer.write_video(100, KEYFRAME, &[1]).unwrap();
+        // Tracks are checked separately
+        writer
+            .write_audio(50, AacAudioPacketType::Raw, &[2])
+            .unwrap();
+        let before = writer.get_ref().len();
+        let err = writer.write_video(99, KEYFRAME, &[3]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(before, writer.get_ref().len());
+
+        let mut writer = FlvWriter::new(Vec::new())
+            .unwrap()
+            .timestamp_policy(TimestampPolicy::Clamp);
+        writer.write_video(100, KEYFRAME, &[1]).unwrap();
+        writer.write_video(99, KEYFRAME, &[3]).unwrap();
+        writer.write_video(101, KEYFRAME, &[4]).unwrap();
+        assert_eq!(1, writer.stats().timestamps_clamped);
+
+        let timestamps: Vec<(TagKind, i32)> = FlvReader::new(&writer.into_inner()[..])
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .map(|tag| (tag.kind, tag.timestamp))
+            .collect();
+        let video = TagKind::Video(KEYFRAME);
+        assert_eq!(vec![(video, 100), (video, 100), (video, 101)], timestamps);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        use crate::AsyncFlvReader;
+
+        let mut writer = FlvWriter::new(Vec::new()).unwrap();
+        let mut async_writer = AsyncFlvWriter::new(Vec::new()).await.unwrap();
+        writer.write_metadata(&Metadata::default()).unwrap();
+        async_writer
+            .write_metadata(&Metadata::default())
+            .await
+            .unwrap();
+        writer.write_video(0, KEYFRAME, &[1, 2]).unwrap();
+        async_writer
+            .write_video(0, KEYFRAME, &[1, 2])
+            .await
+            .unwrap();
+        writer
+            .write_audio(23, AacAudioPacketType::Raw, &[3])
+            .unwrap();
+        async_writer
+            .write_audio(23, AacAudioPacketType::Raw, &[3])
+            .await
+            .unwrap();
+        assert!(async_writer.write_video(-1, KEYFRAME, &[4]).await.is_err());
+
+        assert_eq!(writer.stats(), async_writer.stats());
+        let out = async_writer.into_inner();
+        assert_eq!(writer.into_inner(), out);
+
+        let mut reader = AsyncFlvReader::new(&out[..]).await.unwrap();
+        let mut tags = Vec::new();
+        while let Some(tag) = reader.read_tag().await.unwrap() {
+            tags.push(tag);
+        }
+        let expected = FlvReader::new(&out[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>()
+            .unwrap();
+        assert_eq!(3, tags.len());
+        for (tag, expected) in tags.iter().zip(&expected) {
+            assert_eq!(expected.offset, tag.offset);
+            assert_eq!(expected.kind, tag.kind);
+            assert_eq!(expected.payload, tag.payload);
+        }
+
+        // Truncated in the middle of a tag
+        let mut reader = AsyncFlvReader::new(&out[..out.len() - 2]).await.unwrap();
+        reader.read_tag().await.unwrap();
+        reader.read_tag().await.unwrap();
+        assert!(reader.read_tag().await.is_err());
+    }
+}
@@ -0,0 +1,77 @@
+// Cuts an FLV stream into HLS segments. Run with
+//
+//    ./target/release/my_show | ./target/release/stream_hls [playlist] [segment seconds]
+//
+// The playlist defaults to ./stream/stream.m3u8, with segments alongside it.
+// Tags are written out in real time, by their timestamps, like ffmpeg -re,
+// so a recording plays as a live stream. Input that's already live isn't
+// held back any further.
+
+use std::env;
+use std::io::{self, BufReader};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flvmux::FlvReader;
+use hls::{HlsSegmenter, DEFAULT_TARGET_DURATION};
+
+const DEFAULT_PLAYLIST: &str = "stream/stream.m3u8";
+
+// Keeps tags on the wall clock, like stream::Pacer with real time pacing.
+// Stream time zero is the first call to wait_until.
+#[derive(Default)]
+struct Pacer {
+    start: Option<Instant>,
+}
+
+impl Pacer {
+    // Sleeps until the given stream time (in milliseconds) comes around.
+    // Input that's fallen behind goes out as it comes.
+    fn wait_until(&mut self, millis: i32) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due = start + Duration::from_millis(millis.max(0) as u64);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+}
+
+fn segment(playlist: &str, target_duration: u32) -> io::Result<()> {
+    let mut segmenter = HlsSegmenter::create(playlist)?.target_duration(target_duration);
+    let mut pacer = Pacer::default();
+    let mut first_timestamp = None;
+
+    let stdin = io::stdin();
+    let reader = FlvReader::new(BufReader::new(stdin.lock()))?;
+    for tag in reader {
+        let tag = tag?;
+        // Recordings don't always start at zero
+        let first = *first_timestamp.get_or_insert(tag.timestamp);
+        pacer.wait_until(tag.timestamp.saturating_sub(first));
+        segmenter.write_tag(&tag)?;
+    }
+
+    segmenter.finish()
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let playlist = args.next().unwrap_or_else(|| DEFAULT_PLAYLIST.to_string());
+    let target_duration = match args.next() {
+        Some(seconds) => seconds.parse().unwrap_or_else(|_| {
+            eprintln!(
+                "stream_hls: segment length should be whole seconds, got {:?}",
+                seconds
+            );
+            process::exit(2);
+        }),
+        None => DEFAULT_TARGET_DURATION,
+    };
+
+    if let Err(e) = segment(&playlist, target_duration) {
+        eprintln!("stream_hls: {}", e);
+        process::exit(1);
+    }
+}
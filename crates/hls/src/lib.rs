@@ -0,0 +1,504 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use flvmux::aac::{self, AudioConfig};
+use flvmux::avc::{self, DecoderConfiguration};
+use flvmux::{AacAudioPacketType, AvcPacketType, FlvParser, FlvTag, TagKind};
+
+pub mod ts;
+
+use ts::{Continuity, TsWriter};
+
+/// Segments are cut at the first keyframe after they're this long, like
+/// ffmpeg's -hls_time.
+pub const DEFAULT_TARGET_DURATION: u32 = 6;
+
+/// How many segments the live playlist lists, like ffmpeg's -hls_list_size.
+pub const DEFAULT_PLAYLIST_LENGTH: usize = 5;
+
+// Transport stream timestamps start here rather than at zero, so the clock
+// reference (which runs ahead of decode times) doesn't start out negative.
+const TIMESTAMP_OFFSET: u64 = 2 * ts::PCR_DELAY;
+
+const MILLIS_PER_SECOND: i64 = 1000;
+
+struct Segment {
+    name: String,
+    duration_millis: i64,
+}
+
+// A segment that's dropped out of the playlist, but that players with an
+// older copy of the playlist might still ask for
+struct ExpiredSegment {
+    name: String,
+    expired_millis: i64,
+}
+
+struct OpenSegment {
+    name: String,
+    start_millis: i64,
+    ts: TsWriter<BufWriter<File>>,
+}
+
+/// Cuts FLV tags into MPEG-TS segments, and keeps an HLS playlist of the
+/// most recent ones up to date. Segments start on keyframes, and old ones
+/// are deleted once they've been out of the playlist for as long as the
+/// playlist lasts (RFC 8216 section 6.2.2).
+///
+/// Write FLV to it (from stream::stream_to, say), or hand it tags one at a
+/// time. The playlist is only marked finished by finish(), or when the
+/// segmenter is dropped.
+pub struct HlsSegmenter {
+    dir: PathBuf,
+    playlist: PathBuf,
+    stem: String,
+    target_duration: u32,
+    playlist_length: usize,
+    segments: VecDeque<Segment>,
+    expired: VecDeque<ExpiredSegment>,
+    media_sequence: u64,
+    next_segment: u64,
+    current: Option<OpenSegment>,
+    // Where the last segment's continuity counters left off
+    continuity: Continuity,
+    avc: Option<DecoderConfiguration>,
+    aac: Option<AudioConfig>,
+    last_video_millis: Option<i64>,
+    frame_millis: i64,
+    last_millis: i64,
+    parser: FlvParser,
+    finished: bool,
+}
+
+impl HlsSegmenter {
+    /// Segments go alongside the playlist, named after it: stream.m3u8 is
+    /// made of stream0.ts, stream1.ts and so on. Creates the playlist's
+    /// directory if it needs to.
+    pub fn create(playlist: impl AsRef<Path>) -> io::Result<Self> {
+        let playlist = playlist.as_ref().to_path_buf();
+        let stem = match playlist.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => stem.to_string(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("bad HLS playlist path {:?}", playlist),
+                ))
+            }
+        };
+
+        let dir = match playlist.parent() {
+            Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        fs::create_dir_all(&dir)?;
+
+        Ok(HlsSegmenter {
+            dir,
+            playlist,
+            stem,
+            target_duration: DEFAULT_TARGET_DURATION,
+            playlist_length: DEFAULT_PLAYLIST_LENGTH,
+            segments: VecDeque::new(),
+            expired: VecDeque::new(),
+            media_sequence: 0,
+            next_segment: 0,
+            current: None,
+            continuity: Continuity::default(),
+            avc: None,
+            aac: None,
+            last_video_millis: None,
+            frame_millis: 0,
+            last_millis: 0,
+            parser: FlvParser::new(),
+            finished: false,
+        })
+    }
+
+    /// In seconds
+    pub fn target_duration(mut self, seconds: u32) -> Self {
+        self.target_duration = cmp::max(seconds, 1);
+        self
+    }
+
+    /// The number of segments in the playlist
+    pub fn playlist_length(mut self, segments: usize) -> Self {
+        self.playlist_length = cmp::max(segments, 1);
+        self
+    }
+
+    pub fn write_tag(&mut self, tag: &FlvTag) -> io::Result<()> {
+        match tag.kind {
+            TagKind::ScriptData => Ok(()),
+            TagKind::Video(AvcPacketType::SequenceHeader) => {
+                let config = avc::parse_decoder_configuration_record(&tag.payload)?;
+                if config.nal_length_size != avc::NAL_LENGTH_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unsupported input, AVC NAL unit lengths must be four bytes",
+                    ));
+                }
+                self.avc = Some(config);
+                Ok(())
+            }
+            TagKind::Video(AvcPacketType::SequenceEnd) => Ok(()),
+            TagKind::Video(AvcPacketType::Nalu {
+                composition_offset_millis,
+                seekable,
+            }) => self.write_video(tag, composition_offset_millis, seekable),
+            TagKind::Audio(AacAudioPacketType::SequenceHeader) => {
+                self.aac = Some(aac::parse_audio_specific_config(&tag.payload)?);
+                Ok(())
+            }
+            TagKind::Audio(AacAudioPacketType::Raw) => self.write_audio(tag),
+        }
+    }
+
+    fn write_video(
+        &mut self,
+        tag: &FlvTag,
+        composition_offset: i32,
+        seekable: bool,
+    ) -> io::Result<()> {
+        let access_unit = match &self.avc {
+            Some(config) => annexb_access_unit(config, seekable, &tag.payload)?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupted input, video before the AVC sequence header",
+                ))
+            }
+        };
+
+        let timestamp = i64::from(tag.timestamp);
+        if let Some(last) = self.last_video_millis {
+            self.frame_millis = timestamp - last;
+        }
+        self.last_video_millis = Some(timestamp);
+        self.last_millis = cmp::max(self.last_millis, timestamp);
+
+        if seekable && self.segment_is_due(timestamp) {
+            self.close_segment(timestamp)?;
+            self.open_segment(timestamp)?;
+        }
+
+        // Players can only start a segment at a keyframe, so anything before
+        // the first one is useless.
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let dts = ts_timestamp(timestamp);
+        let pts = ts_timestamp(timestamp + i64::from(composition_offset));
+        current.ts.write_video(pts, dts, seekable, &access_unit)
+    }
+
+    fn write_audio(&mut self, tag: &FlvTag) -> io::Result<()> {
+        let config = match &self.aac {
+            Some(config) => *config,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupted input, audio before the AAC sequence header",
+                ))
+            }
+        };
+
+        // Audio only streams can cut anywhere, but with video, segments
+        // wait for a keyframe.
+        let timestamp = i64::from(tag.timestamp);
+        self.last_millis = cmp::max(self.last_millis, timestamp);
+        if self.avc.is_none() && self.segment_is_due(timestamp) {
+            self.close_segment(timestamp)?;
+            self.open_segment(timestamp)?;
+        }
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let mut frame = config.adts_header(tag.payload.len())?.to_vec();
+        frame.extend_from_slice(&tag.payload);
+        current.ts.write_audio(ts_timestamp(timestamp), &frame)
+    }
+
+    fn segment_is_due(&self, timestamp: i64) -> bool {
+        match &self.current {
+            Some(current) => {
+                timestamp - current.start_millis
+                    >= i64::from(self.target_duration) * MILLIS_PER_SECOND
+            }
+            None => true,
+        }
+    }
+
+    fn open_segment(&mut self, start_millis: i64) -> io::Result<()> {
+        let name = format!("{}{}.ts", self.stem, self.next_segment);
+        self.next_segment += 1;
+
+        let file = File::create(self.dir.join(&name))?;
+        let mut ts = TsWriter::new(BufWriter::new(file), self.avc.is_some(), self.aac.is_some())
+            .with_continuity(self.continuity);
+        ts.write_tables()?;
+        self.current = Some(OpenSegment {
+            name,
+            start_millis,
+            ts,
+        });
+        Ok(())
+    }
+
+    // Finishes the current segment, if any, and adds it to the playlist.
+    fn close_segment(&mut self, end_millis: i64) -> io::Result<()> {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        self.continuity = current.ts.continuity();
+        current.ts.into_inner().into_inner()?.sync_all()?;
+
+        self.segments.push_back(Segment {
+            name: current.name,
+            duration_millis: end_millis - current.start_millis,
+        });
+        while self.segments.len() > self.playlist_length {
+            let old = self.segments.pop_front().unwrap();
+            self.media_sequence += 1;
+            self.expired.push_back(ExpiredSegment {
+                name: old.name,
+                expired_millis: end_millis,
+            });
+        }
+
+        self.write_playlist()?;
+        self.remove_expired(end_millis)
+    }
+
+    // Deletes segments that have been out of the playlist for longer than
+    // anyone could still be playing from a copy that had them.
+    fn remove_expired(&mut self, now_millis: i64) -> io::Result<()> {
+        let keep_millis =
+            i64::from(self.target_duration) * self.playlist_length as i64 * MILLIS_PER_SECOND;
+        while let Some(old) = self.expired.front() {
+            if now_millis - old.expired_millis < keep_millis {
+                break;
+            }
+
+            match fs::remove_file(self.dir.join(&old.name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            self.expired.pop_front();
+        }
+
+        Ok(())
+    }
+
+    // Written to the side and renamed into place, so players never see half
+    // a playlist.
+    fn write_playlist(&self) -> io::Result<()> {
+        let longest = self
+            .segments
+            .iter()
+            .map(|s| s.duration_millis)
+            .max()
+            .unwrap_or(0);
+        let target_duration = cmp::max(
+            i64::from(self.target_duration),
+            (longest + MILLIS_PER_SECOND - 1) / MILLIS_PER_SECOND,
+        );
+
+        let mut playlist = String::new();
+        playlist.push_str("#EXTM3U\n#EXT-X-VERSION:3\n");
+        playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
+        playlist.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", self.media_sequence));
+        for segment in &self.segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}\n",
+                segment.duration_millis as f64 / MILLIS_PER_SECOND as f64,
+                segment.name
+            ));
+        }
+        if self.finished {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        let mut partial = self.playlist.clone().into_os_string();
+        partial.push(".tmp");
+        fs::write(&partial, playlist)?;
+        fs::rename(&partial, &self.playlist)
+    }
+
+    /// Closes the last segment and marks the playlist finished. Called by
+    /// drop, but call it yourself to see any errors.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        // The last frame lasts about as long as the one before it
+        let end_millis = match self.last_video_millis {
+            Some(last) => cmp::max(self.last_millis, last + self.frame_millis),
+            None => self.last_millis,
+        };
+        if self.current.is_some() {
+            self.close_segment(end_millis)
+        } else {
+            self.write_playlist()
+        }
+    }
+}
+
+impl Write for HlsSegmenter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.parser.push(buf);
+        while let Some(tag) = self.parser.next_tag()? {
+            self.write_tag(&tag)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.current {
+            Some(current) => current.ts.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for HlsSegmenter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+fn ts_timestamp(millis: i64) -> u64 {
+    // FLV timestamps aren't negative, so this is at least TIMESTAMP_OFFSET.
+    (cmp::max(millis, 0) as u64) * ts::CLOCK_RATE / 1000 + TIMESTAMP_OFFSET
+}
+
+// Rewrites AVCC video data as an Annex-B access unit, starting with an
+// access unit delimiter. Keyframes carry the parameter sets, since a player
+// might start with any segment.
+fn annexb_access_unit(
+    config: &DecoderConfiguration,
+    keyframe: bool,
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    let nals = avc::avcc_nal_units(data)?;
+    let has = |nal_type| {
+        nals.iter()
+            .any(|nal| avc::nal_unit_type(nal) == Some(nal_type))
+    };
+
+    let mut out = Vec::with_capacity(data.len() + 64);
+    if !has(avc::NAL_AUD) {
+        out.extend_from_slice(&[0, 0, 0, 1, avc::NAL_AUD, 0xf0]);
+    }
+    if keyframe && !has(avc::NAL_SPS) {
+        for nal in config.sps.iter().chain(config.pps.iter()) {
+            out.extend_from_slice(&[0, 0, 0, 1]);
+            out.extend_from_slice(nal);
+        }
+    }
+    for nal in nals {
+        out.extend_from_slice(&[0, 0, 0, 1]);
+        out.extend_from_slice(nal);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // From an x264 "high" profile 1280x720 encode
+    const SPS: [u8; 25] = [
+        0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9, 0x40, 0x50, 0x05, 0xba, 0x10, 0x00, 0x00, 0x03, 0x00,
+        0x10, 0x00, 0x00, 0x03, 0x03, 0xc0, 0xf1, 0x83, 0x19, 0x60,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xef, 0x8f, 0xcb];
+
+    fn video_tag(timestamp: i32, seekable: bool) -> FlvTag {
+        FlvTag {
+            offset: 0,
+            data_size: 0,
+            timestamp,
+            kind: TagKind::Video(AvcPacketType::Nalu {
+                composition_offset_millis: 66,
+                seekable,
+            }),
+            payload: vec![0, 0, 0, 2, 0x65, 0x88],
+        }
+    }
+
+    fn sequence_header() -> FlvTag {
+        FlvTag {
+            offset: 0,
+            data_size: 0,
+            timestamp: 0,
+            kind: TagKind::Video(AvcPacketType::SequenceHeader),
+            payload: avc::decoder_configuration_record(&[&SPS], &[&PPS]).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_access_unit() {
+        let config = avc::parse_decoder_configuration_record(&sequence_header().payload).unwrap();
+        let keyframe = annexb_access_unit(&config, true, &video_tag(0, true).payload).unwrap();
+        let expected = [
+            &[0, 0, 0, 1, 9, 0xf0, 0, 0, 0, 1][..],
+            &SPS,
+            &[0, 0, 0, 1],
+            &PPS,
+            &[0, 0, 0, 1, 0x65, 0x88],
+        ];
+        assert_eq!(expected.concat(), keyframe);
+
+        let frame = annexb_access_unit(&config, false, &video_tag(0, false).payload).unwrap();
+        assert_eq!(vec![0, 0, 0, 1, 9, 0xf0, 0, 0, 0, 1, 0x65, 0x88], frame);
+    }
+
+    #[test]
+    fn test_segments() {
+        let dir = env::temp_dir().join(format!("hls-test-{}", process::id()));
+        let playlist = dir.join("live.m3u8");
+        let mut segmenter = HlsSegmenter::create(&playlist)
+            .unwrap()
+            .target_duration(2)
+            .playlist_length(2);
+
+        segmenter.write_tag(&sequence_header()).unwrap();
+        // Keyframes every 1.5 seconds, for 15 seconds at 10 fps
+        for frame in 0..150 {
+            segmenter
+                .write_tag(&video_tag(frame * 100, frame % 15 == 0))
+                .unwrap();
+        }
+        segmenter.finish().unwrap();
+
+        let contents = fs::read_to_string(&playlist).unwrap();
+        assert_eq!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:3\n#EXT-X-MEDIA-SEQUENCE:3\n\
+             #EXTINF:3.000,\nlive3.ts\n#EXTINF:3.000,\nlive4.ts\n#EXT-X-ENDLIST\n",
+            contents
+        );
+
+        // Segments stay for the length of the playlist (4 seconds) after
+        // they leave it. live0 left at 9 seconds, and live1 at 12.
+        assert!(!dir.join("live0.ts").exists());
+        assert!(dir.join("live1.ts").exists());
+        assert!(dir.join("live2.ts").exists());
+        let segment = fs::read(dir.join("live4.ts")).unwrap();
+        assert_eq!(0, segment.len() % ts::PACKET_SIZE);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use std::cmp;
+use std::io::{self, Write};
+
+// From ISO/IEC 13818-1 (MPEG-2 systems)
+pub const PACKET_SIZE: usize = 188;
+const PAYLOAD_SIZE: usize = PACKET_SIZE - 4;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0;
+const PMT_PID: u16 = 0x1000;
+pub const VIDEO_PID: u16 = 0x100;
+pub const AUDIO_PID: u16 = 0x101;
+
+const STREAM_TYPE_H264: u8 = 0x1b;
+const STREAM_TYPE_AAC: u8 = 0x0f; // ADTS framed
+
+const STREAM_ID_VIDEO: u8 = 0xe0;
+const STREAM_ID_AUDIO: u8 = 0xc0;
+
+/// Timestamps are in 90kHz ticks, and wrap at 33 bits.
+pub const CLOCK_RATE: u64 = 90_000;
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// How far the PCR runs ahead of video decode times, so decoders have time
+/// to buffer. Timestamps passed to TsWriter should be at least this large.
+pub const PCR_DELAY: u64 = CLOCK_RATE * 7 / 10;
+
+/// Writes an MPEG transport stream with one program, holding H.264 video,
+/// AAC audio, or both. The clock reference rides along with the video, or
+/// with the audio if there isn't any.
+pub struct TsWriter<W: Write> {
+    out: W,
+    has_video: bool,
+    has_audio: bool,
+    continuity: Continuity,
+}
+
+/// The continuity counters for each PID. Players check they count up
+/// across segments, too, so a segment's writer should start where the last
+/// one left off.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Continuity {
+    pat: u8,
+    pmt: u8,
+    video: u8,
+    audio: u8,
+}
+
+impl<W: Write> TsWriter<W> {
+    pub fn new(out: W, has_video: bool, has_audio: bool) -> Self {
+        TsWriter {
+            out,
+            has_video,
+            has_audio,
+            continuity: Continuity::default(),
+        }
+    }
+
+    /// Carries on counting from another writer's continuity()
+    pub fn with_continuity(mut self, continuity: Continuity) -> Self {
+        self.continuity = continuity;
+        self
+    }
+
+    pub fn continuity(&self) -> Continuity {
+        self.continuity
+    }
+
+    /// Writes the program association and program map tables. Each segment
+    /// should start with these, so players can join at any segment.
+    pub fn write_tables(&mut self) -> io::Result<()> {
+        let pat = psi_section(0x00, &pat_body());
+        let cc = next_continuity(&mut self.continuity.pat);
+        write_psi_packet(&mut self.out, PAT_PID, cc, &pat)?;
+
+        let pmt = psi_section(0x02, &pmt_body(self.has_video, self.has_audio));
+        let cc = next_continuity(&mut self.continuity.pmt);
+        write_psi_packet(&mut self.out, PMT_PID, cc, &pmt)
+    }
+
+    /// Writes one H.264 access unit, in Annex-B format.
+    pub fn write_video(
+        &mut self,
+        pts: u64,
+        dts: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let header = pes_header(STREAM_ID_VIDEO, pts, Some(dts), None);
+        let pcr = dts.saturating_sub(PCR_DELAY);
+        write_pes(
+            &mut self.out,
+            VIDEO_PID,
+            &mut self.continuity.video,
+            Some(pcr),
+            keyframe,
+            &header,
+            data,
+        )
+    }
+
+    /// Writes ADTS framed AAC audio.
+    pub fn write_audio(&mut self, pts: u64, data: &[u8]) -> io::Result<()> {
+        let header = pes_header(STREAM_ID_AUDIO, pts, None, Some(data.len()));
+        let pcr = if self.has_video {
+            None
+        } else {
+            Some(pts.saturating_sub(PCR_DELAY))
+        };
+        write_pes(
+            &mut self.out,
+            AUDIO_PID,
+            &mut self.continuity.audio,
+            pcr,
+            false,
+            &header,
+            data,
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+fn next_continuity(counter: &mut u8) -> u8 {
+    let ret = *counter;
+    *counter = (*counter + 1) & 0x0f;
+    ret
+}
+
+fn pat_body() -> Vec<u8> {
+    vec![
+        0x00,
+        0x01, // program number 1
+        0xe0 | (PMT_PID >> 8) as u8,
+        PMT_PID as u8,
+    ]
+}
+
+fn pmt_body(has_video: bool, has_audio: bool) -> Vec<u8> {
+    let pcr_pid = if has_video { VIDEO_PID } else { AUDIO_PID };
+    let mut body = vec![
+        0xe0 | (pcr_pid >> 8) as u8, // PCR PID
+        pcr_pid as u8,
+        0xf0, // no program descriptors
+        0x00,
+    ];
+
+    let mut streams = Vec::new();
+    if has_video {
+        streams.push((STREAM_TYPE_H264, VIDEO_PID));
+    }
+    if has_audio {
+        streams.push((STREAM_TYPE_AAC, AUDIO_PID));
+    }
+
+    for (stream_type, pid) in streams {
+        body.extend_from_slice(&[
+            stream_type,
+            0xe0 | (pid >> 8) as u8,
+            pid as u8,
+            0xf0, // no elementary stream descriptors
+            0x00,
+        ]);
+    }
+
+    body
+}
+
+// Wraps a table body in a long form section header (for program 1, or
+// transport stream 1), and CRC.
+fn psi_section(table_id: u8, body: &[u8]) -> Vec<u8> {
+    // Everything after the length field, CRC included
+    let section_length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xb0 | (section_length >> 8) as u8, // section syntax indicator, reserved bits
+        section_length as u8,
+        0x00,
+        0x01, // transport stream id or program number
+        0xc1, // version 0, current
+        0x00, // section number
+        0x00, // last section number
+    ];
+    section.extend_from_slice(body);
+
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+fn write_psi_packet(
+    out: &mut impl Write,
+    pid: u16,
+    continuity: u8,
+    section: &[u8],
+) -> io::Result<()> {
+    let mut packet = [0xffu8; PACKET_SIZE];
+    packet[..4].copy_from_slice(&packet_header(pid, true, false, continuity));
+    packet[4] = 0; // pointer field, the section starts right away
+    packet[5..5 + section.len()].copy_from_slice(section);
+    out.write_all(&packet)
+}
+
+fn packet_header(pid: u16, unit_start: bool, adaptation: bool, continuity: u8) -> [u8; 4] {
+    let adaptation_control = if adaptation { 0x30 } else { 0x10 };
+    [
+        SYNC_BYTE,
+        (if unit_start { 0x40 } else { 0 }) | (pid >> 8) as u8 & 0x1f,
+        pid as u8,
+        adaptation_control | continuity,
+    ]
+}
+
+// PES_packet_length only counts what follows it, and is zero (unbounded)
+// when that doesn't fit.
+fn pes_header(stream_id: u8, pts: u64, dts: Option<u64>, data_length: Option<usize>) -> Vec<u8> {
+    let (flags, header_data_length) = match dts {
+        Some(_) => (0xc0, 10),
+        None => (0x80, 5),
+    };
+    let packet_length = data_length
+        .map(|len| len + 3 + header_data_length)
+        .filter(|len| *len <= 0xffff)
+        .unwrap_or(0);
+
+    let mut header = vec![
+        0x00,
+        0x00,
+        0x01,
+        stream_id,
+        (packet_length >> 8) as u8,
+        packet_length as u8,
+        0x80, // marker bits, not scrambled
+        flags,
+        header_data_length as u8,
+    ];
+
+    match dts {
+        Some(dts) => {
+            header.extend_from_slice(&pes_timestamp(0x3, pts));
+            header.extend_from_slice(&pes_timestamp(0x1, dts));
+        }
+        None => header.extend_from_slice(&pes_timestamp(0x2, pts)),
+    }
+
+    header
+}
+
+// 33 bits, split 3 / 15 / 15 around marker bits
+fn pes_timestamp(prefix: u8, timestamp: u64) -> [u8; 5] {
+    let ts = timestamp & TIMESTAMP_MASK;
+    [
+        (prefix << 4) | ((ts >> 29) as u8 & 0x0e) | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xfe) | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+// Splits a PES packet across transport packets. The first carries the PCR
+// and random access flag, if any, and the last is padded out with stuffing
+// in its adaptation field.
+fn write_pes(
+    out: &mut impl Write,
+    pid: u16,
+    continuity: &mut u8,
+    pcr: Option<u64>,
+    random_access: bool,
+    header: &[u8],
+    data: &[u8],
+) -> io::Result<()> {
+    let mut payload = Vec::with_capacity(header.len() + data.len());
+    payload.extend_from_slice(header);
+    payload.extend_from_slice(data);
+
+    let mut rest = &payload[..];
+    let mut first = true;
+    while first || !rest.is_empty() {
+        // Adaptation field flags and fields, after the length and flags bytes
+        let mut fields = Vec::new();
+        let mut flags = 0u8;
+        if first && random_access {
+            flags |= 0x40;
+        }
+        if let (true, Some(pcr)) = (first, pcr) {
+            flags |= 0x10;
+            fields.extend_from_slice(&pcr_field(pcr));
+        }
+
+        let min_adaptation = if flags != 0 { 2 + fields.len() } else { 0 };
+        let len = cmp::min(rest.len(), PAYLOAD_SIZE - min_adaptation);
+        let adaptation_size = PAYLOAD_SIZE - len;
+
+        let mut packet = Vec::with_capacity(PACKET_SIZE);
+        let cc = next_continuity(continuity);
+        packet.extend_from_slice(&packet_header(pid, first, adaptation_size > 0, cc));
+        if adaptation_size > 0 {
+            packet.push((adaptation_size - 1) as u8);
+            if adaptation_size > 1 {
+                packet.push(flags);
+                packet.extend_from_slice(&fields);
+                packet.resize(4 + adaptation_size, 0xff);
+            }
+        }
+
+        let (chunk, remaining) = rest.split_at(len);
+        packet.extend_from_slice(chunk);
+        out.write_all(&packet)?;
+
+        rest = remaining;
+        first = false;
+    }
+
+    Ok(())
+}
+
+// 33 bit base, 6 reserved bits, and a 9 bit extension we leave at zero
+fn pcr_field(pcr: u64) -> [u8; 6] {
+    let base = pcr & TIMESTAMP_MASK;
+    [
+        (base >> 25) as u8,
+        (base >> 17) as u8,
+        (base >> 9) as u8,
+        (base >> 1) as u8,
+        ((base << 7) as u8 & 0x80) | 0x7e,
+        0x00,
+    ]
+}
+
+// CRC-32/MPEG-2: polynomial 0x04c11db7, not reflected, no final xor
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &b in data {
+        crc ^= u32::from(b) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packets(ts: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(0, ts.len() % PACKET_SIZE);
+        let packets: Vec<&[u8]> = ts.chunks(PACKET_SIZE).collect();
+        assert!(packets.iter().all(|p| p[0] == SYNC_BYTE));
+        packets
+    }
+
+    fn pid(packet: &[u8]) -> u16 {
+        (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2])
+    }
+
+    #[test]
+    fn test_tables() {
+        let mut writer = TsWriter::new(Vec::new(), true, true);
+        writer.write_tables().unwrap();
+        let ts = writer.into_inner();
+        let packets = packets(&ts);
+        assert_eq!(2, packets.len());
+
+        // The same PAT ffmpeg writes
+        assert_eq!(PAT_PID, pid(packets[0]));
+        assert_eq!(
+            [
+                0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0x2a, 0xb1,
+                0x04, 0xb2
+            ],
+            packets[0][5..21]
+        );
+
+        // A CRC over the whole section, its own CRC included, comes out zero
+        assert_eq!(PMT_PID, pid(packets[1]));
+        let section_length = usize::from(packets[1][7]);
+        assert_eq!(0, crc32(&packets[1][5..8 + section_length]));
+        assert_eq!(STREAM_TYPE_AAC, packets[1][8 + 9 + 5]);
+        assert_eq!(VIDEO_PID, pid(&packets[1][12..]));
+    }
+
+    #[test]
+    fn test_audio_only_pcr() {
+        let mut writer = TsWriter::new(Vec::new(), false, true);
+        writer.write_tables().unwrap();
+        writer.write_audio(PCR_DELAY + 90, &[7u8; 20]).unwrap();
+        let ts = writer.into_inner();
+        let packets = packets(&ts);
+
+        // The PMT names the audio as the PCR PID, and lists nothing else
+        assert_eq!(AUDIO_PID, pid(&packets[1][12..]));
+        assert_eq!(5 + 4 + 5 + 4, usize::from(packets[1][7]));
+        assert_eq!(STREAM_TYPE_AAC, packets[1][8 + 9]);
+
+        // Which carries it
+        assert_eq!(AUDIO_PID, pid(packets[2]));
+        assert_eq!(0x10, packets[2][5]);
+        assert_eq!([0, 0, 0, 45, 0x7e, 0], packets[2][6..12]); // 90 ticks
+    }
+
+    #[test]
+    fn test_video_packets() {
+        let mut writer = TsWriter::new(Vec::new(), true, false);
+        let frame = vec![7u8; 400];
+        writer
+            .write_video(PCR_DELAY + 3000, PCR_DELAY, true, &frame)
+            .unwrap();
+        let ts = writer.into_inner();
+        let packets = packets(&ts);
+        assert_eq!(3, packets.len());
+
+        // PES start, with a PCR and the random access flag
+        assert_eq!(0x40, packets[0][1] & 0x40);
+        assert_eq!(0x30, packets[0][3] & 0x30);
+        assert_eq!(0x50, packets[0][5]);
+        assert_eq!([0, 0, 0, 0, 0x7e, 0], packets[0][6..12]);
+        assert_eq!([0, 0, 1, STREAM_ID_VIDEO], packets[0][12..16]);
+
+        // Continuity counts up, and the last packet is padded
+        let counters: Vec<u8> = packets.iter().map(|p| p[3] & 0x0f).collect();
+        assert_eq!(vec![0, 1, 2], counters);
+        assert_eq!(0, packets[1][1] & 0x40);
+        assert_eq!(7, *packets[2].last().unwrap());
+
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&packets[0][12..]);
+        payload.extend_from_slice(&packets[1][4..]);
+        let last = packets[2];
+        payload.extend_from_slice(&last[5 + usize::from(last[4])..]);
+        assert_eq!(19 + 400, payload.len());
+        assert_eq!(frame[..], payload[19..]);
+    }
+
+    #[test]
+    fn test_continuity() {
+        let mut first = TsWriter::new(Vec::new(), true, false);
+        first.write_tables().unwrap();
+        first
+            .write_video(PCR_DELAY, PCR_DELAY, true, &[7u8; 400])
+            .unwrap();
+
+        let mut second = TsWriter::new(Vec::new(), true, false).with_continuity(first.continuity());
+        second.write_tables().unwrap();
+        second
+            .write_video(PCR_DELAY, PCR_DELAY, true, &[7u8; 10])
+            .unwrap();
+        let ts = second.into_inner();
+        let counters: Vec<(u16, u8)> = packets(&ts).iter().map(|p| (pid(p), p[3] & 0x0f)).collect();
+        assert_eq!(vec![(PAT_PID, 1), (PMT_PID, 1), (VIDEO_PID, 3)], counters);
+    }
+
+    #[test]
+    fn test_pes_timestamp() {
+        assert_eq!([0x21, 0, 1, 0, 1], pes_timestamp(0x2, 0));
+        assert_eq!([0x31, 0, 0x05, 0xbf, 0x21], pes_timestamp(0x3, 90000));
+        assert_eq!(
+            [0x2f, 0xff, 0xff, 0xff, 0xff],
+            pes_timestamp(0x2, TIMESTAMP_MASK)
+        );
+    }
+}
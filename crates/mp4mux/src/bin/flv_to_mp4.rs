@@ -0,0 +1,57 @@
+// Remuxes an H.264 and AAC FLV file (like a recorded show) into MP4,
+// without re-encoding. Run with
+//
+//    ./target/release/flv_to_mp4 recording.flv recording.mp4
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::process;
+
+use flvmux::{AacAudioPacketType, AvcPacketType, FlvReader, TagKind};
+use mp4mux::{AudioTrack, Mp4Writer, VideoTrack};
+
+fn remux(input: &str, output: &str) -> io::Result<()> {
+    let reader = FlvReader::new(BufReader::new(File::open(input)?))?;
+    let mut writer = Mp4Writer::new(BufWriter::new(File::create(output)?))?;
+    for tag in reader {
+        let tag = tag?;
+        match tag.kind {
+            TagKind::Video(AvcPacketType::SequenceHeader) => {
+                writer.set_video_track(VideoTrack::from_decoder_configuration(tag.payload)?)?
+            }
+            TagKind::Video(AvcPacketType::Nalu {
+                composition_offset_millis,
+                seekable,
+            }) => writer.write_video(
+                tag.timestamp,
+                composition_offset_millis,
+                seekable,
+                &tag.payload,
+            )?,
+            TagKind::Audio(AacAudioPacketType::SequenceHeader) => {
+                writer.set_audio_track(AudioTrack::new(tag.payload)?)?
+            }
+            TagKind::Audio(AacAudioPacketType::Raw) => {
+                writer.write_audio(tag.timestamp, &tag.payload)?
+            }
+            TagKind::Video(AvcPacketType::SequenceEnd) | TagKind::ScriptData => {}
+        }
+    }
+
+    writer.finish()?;
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("usage: flv_to_mp4 input.flv output.mp4");
+        process::exit(2);
+    }
+
+    if let Err(e) = remux(&args[0], &args[1]) {
+        eprintln!("flv_to_mp4: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,378 @@
+// Box writers shared by fragmented and progressive MP4, from ISO/IEC 14496-12
+// (the ISO base media file format) and 14496-14 (MP4).
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::{
+    AudioTrack, VideoTrack, AUDIO_TRACK_ID, MOVIE_TIMESCALE, VIDEO_TIMESCALE, VIDEO_TRACK_ID,
+};
+
+// Identity transform, in 16.16 and 2.30 fixed point
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+// Packed ISO-639-2/T "und"
+const LANGUAGE_UNDETERMINED: u16 = 0x55c4;
+
+#[derive(Clone, Copy)]
+pub enum Track<'a> {
+    Video(&'a VideoTrack),
+    Audio(&'a AudioTrack),
+}
+
+impl<'a> Track<'a> {
+    pub fn id(&self) -> u32 {
+        match self {
+            Track::Video(_) => VIDEO_TRACK_ID,
+            Track::Audio(_) => AUDIO_TRACK_ID,
+        }
+    }
+
+    pub fn timescale(&self) -> u32 {
+        match self {
+            Track::Video(_) => VIDEO_TIMESCALE,
+            Track::Audio(audio) => audio.config.sample_rate(),
+        }
+    }
+}
+
+/// Writes a box: a 32 bit size, the type, then whatever body writes.
+pub fn write_box(
+    out: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    body: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    let start = out.len();
+    out.write_u32::<BigEndian>(0)?;
+    out.write_all(box_type)?;
+    body(out)?;
+
+    let size = u32::try_from(out.len() - start)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MP4 box is too large"))?;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    Ok(())
+}
+
+/// A box with a version and flags ahead of its body
+pub fn write_full_box(
+    out: &mut Vec<u8>,
+    box_type: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    write_box(out, box_type, |out| {
+        out.write_u8(version)?;
+        out.write_u24::<BigEndian>(flags)?;
+        body(out)
+    })
+}
+
+pub fn write_ftyp(
+    out: &mut Vec<u8>,
+    major_brand: &[u8; 4],
+    compatible: &[&[u8; 4]],
+) -> io::Result<()> {
+    write_box(out, b"ftyp", |out| {
+        out.write_all(major_brand)?;
+        out.write_u32::<BigEndian>(0)?; // minor version
+        for brand in compatible {
+            out.write_all(*brand)?;
+        }
+        Ok(())
+    })
+}
+
+/// Durations are in MOVIE_TIMESCALE units, zero when fragments follow.
+pub fn write_mvhd(out: &mut Vec<u8>, duration: u64) -> io::Result<()> {
+    write_full_box(out, b"mvhd", version_for(duration), 0, |out| {
+        write_times(out, duration, |out| {
+            out.write_u32::<BigEndian>(MOVIE_TIMESCALE)
+        })?;
+        out.write_u32::<BigEndian>(0x0001_0000)?; // rate 1.0
+        out.write_u16::<BigEndian>(0x0100)?; // volume 1.0
+        out.write_all(&[0; 10])?; // reserved
+        write_matrix(out)?;
+        out.write_all(&[0; 24])?; // pre_defined
+        out.write_u32::<BigEndian>(AUDIO_TRACK_ID + 1) // next track id
+    })
+}
+
+pub fn write_tkhd(out: &mut Vec<u8>, track: Track, duration: u64) -> io::Result<()> {
+    let (volume, width, height) = match track {
+        Track::Video(video) => (0, video.width, video.height),
+        Track::Audio(_) => (0x0100, 0, 0),
+    };
+
+    // Enabled, and part of the presentation
+    write_full_box(out, b"tkhd", version_for(duration), 0x3, |out| {
+        write_times(out, duration, |out| {
+            out.write_u32::<BigEndian>(track.id())?;
+            out.write_u32::<BigEndian>(0) // reserved
+        })?;
+        out.write_all(&[0; 8])?; // reserved
+        out.write_u16::<BigEndian>(0)?; // layer
+        out.write_u16::<BigEndian>(0)?; // alternate group
+        out.write_u16::<BigEndian>(volume)?;
+        out.write_u16::<BigEndian>(0)?; // reserved
+        write_matrix(out)?;
+
+        // 16.16 fixed point
+        out.write_u32::<BigEndian>(u32::from(width) << 16)?;
+        out.write_u32::<BigEndian>(u32::from(height) << 16)
+    })
+}
+
+pub fn write_mdhd(out: &mut Vec<u8>, timescale: u32, duration: u64) -> io::Result<()> {
+    write_full_box(out, b"mdhd", version_for(duration), 0, |out| {
+        write_times(out, duration, |out| out.write_u32::<BigEndian>(timescale))?;
+        out.write_u16::<BigEndian>(LANGUAGE_UNDETERMINED)?;
+        out.write_u16::<BigEndian>(0) // pre_defined
+    })
+}
+
+pub fn write_hdlr(out: &mut Vec<u8>, track: Track) -> io::Result<()> {
+    let (handler_type, name): (&[u8; 4], &[u8]) = match track {
+        Track::Video(_) => (b"vide", b"VideoHandler\0"),
+        Track::Audio(_) => (b"soun", b"SoundHandler\0"),
+    };
+
+    write_full_box(out, b"hdlr", 0, 0, |out| {
+        out.write_u32::<BigEndian>(0)?; // pre_defined
+        out.write_all(handler_type)?;
+        out.write_all(&[0; 12])?; // reserved
+        out.write_all(name)
+    })
+}
+
+/// The media header, and a data reference saying the samples are in this file
+pub fn write_media_header(out: &mut Vec<u8>, track: Track) -> io::Result<()> {
+    match track {
+        Track::Video(_) => write_full_box(out, b"vmhd", 0, 1, |out| {
+            out.write_u16::<BigEndian>(0)?; // graphics mode, copy
+            out.write_all(&[0; 6]) // opcolor
+        })?,
+        Track::Audio(_) => write_full_box(out, b"smhd", 0, 0, |out| {
+            out.write_u16::<BigEndian>(0)?; // balance
+            out.write_u16::<BigEndian>(0) // reserved
+        })?,
+    }
+
+    write_box(out, b"dinf", |out| {
+        write_full_box(out, b"dref", 0, 0, |out| {
+            out.write_u32::<BigEndian>(1)?; // entry count
+            write_full_box(out, b"url ", 0, 1, |_| Ok(()))
+        })
+    })
+}
+
+/// The sample description for H.264 video, with its decoder configuration
+pub fn write_avc1_stsd(out: &mut Vec<u8>, video: &VideoTrack) -> io::Result<()> {
+    write_full_box(out, b"stsd", 0, 0, |out| {
+        out.write_u32::<BigEndian>(1)?; // entry count
+        write_box(out, b"avc1", |out| {
+            out.write_all(&[0; 6])?; // reserved
+            out.write_u16::<BigEndian>(1)?; // data reference index
+            out.write_all(&[0; 16])?; // pre_defined and reserved
+            out.write_u16::<BigEndian>(video.width)?;
+            out.write_u16::<BigEndian>(video.height)?;
+            out.write_u32::<BigEndian>(0x0048_0000)?; // 72 dpi
+            out.write_u32::<BigEndian>(0x0048_0000)?;
+            out.write_u32::<BigEndian>(0)?; // reserved
+            out.write_u16::<BigEndian>(1)?; // frame count
+            out.write_all(&[0; 32])?; // compressor name
+            out.write_u16::<BigEndian>(0x0018)?; // depth, color without alpha
+            out.write_i16::<BigEndian>(-1)?; // pre_defined
+            write_box(out, b"avcC", |out| {
+                out.write_all(&video.decoder_configuration)
+            })
+        })
+    })
+}
+
+/// The sample description for AAC audio, with its AudioSpecificConfig
+pub fn write_mp4a_stsd(out: &mut Vec<u8>, audio: &AudioTrack) -> io::Result<()> {
+    // 16.16 fixed point, where it fits. The esds says what it really is.
+    let sample_rate = match audio.config.sample_rate() {
+        rate if rate <= 0xffff => rate << 16,
+        _ => 0,
+    };
+
+    write_full_box(out, b"stsd", 0, 0, |out| {
+        out.write_u32::<BigEndian>(1)?; // entry count
+        write_box(out, b"mp4a", |out| {
+            out.write_all(&[0; 6])?; // reserved
+            out.write_u16::<BigEndian>(1)?; // data reference index
+            out.write_all(&[0; 8])?; // reserved
+            out.write_u16::<BigEndian>(u16::from(audio.config.channels()))?;
+            out.write_u16::<BigEndian>(16)?; // sample size
+            out.write_u32::<BigEndian>(0)?; // pre_defined and reserved
+            out.write_u32::<BigEndian>(sample_rate)?;
+            write_esds(out, &audio.audio_specific_config)
+        })
+    })
+}
+
+// An ES_Descriptor, from ISO/IEC 14496-1 section 7.2.6.5
+fn write_esds(out: &mut Vec<u8>, audio_specific_config: &[u8]) -> io::Result<()> {
+    let mut decoder_specific_info = Vec::new();
+    write_descriptor(&mut decoder_specific_info, 0x05, audio_specific_config)?;
+
+    let mut decoder_config = vec![
+        0x40, // object type, MPEG-4 audio
+        0x15, // stream type 5 (audio) << 2, reserved bit
+        0, 0, 0, // buffer size
+        0, 0, 0, 0, // max bitrate
+        0, 0, 0, 0, // average bitrate
+    ];
+    decoder_config.extend_from_slice(&decoder_specific_info);
+
+    let mut es = vec![
+        0, 0, // ES_ID
+        0, // no dependencies, URL or OCR stream
+    ];
+    write_descriptor(&mut es, 0x04, &decoder_config)?;
+    write_descriptor(&mut es, 0x06, &[0x02])?; // SLConfigDescriptor, predefined for MP4
+
+    write_full_box(out, b"esds", 0, 0, |out| write_descriptor(out, 0x03, &es))
+}
+
+// Descriptor sizes are written 7 bits at a time, high bit set on all but the last.
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, body: &[u8]) -> io::Result<()> {
+    if body.len() >= 1 << 28 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MP4 descriptor is too large",
+        ));
+    }
+
+    out.write_u8(tag)?;
+    for shift in [21, 14, 7] {
+        if body.len() >= 1 << shift {
+            out.write_u8(0x80 | (body.len() >> shift) as u8 & 0x7f)?;
+        }
+    }
+    out.write_u8(body.len() as u8 & 0x7f)?;
+    out.write_all(body)
+}
+
+/// A track's header, handler and sample description, around whatever sample
+/// table boxes the caller writes. Durations are in the movie and media
+/// timescales.
+pub fn write_trak(
+    out: &mut Vec<u8>,
+    track: Track,
+    movie_duration: u64,
+    media_duration: u64,
+    edits: &[Edit],
+    sample_table: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    write_box(out, b"trak", |out| {
+        write_tkhd(out, track, movie_duration)?;
+        if !edits.is_empty() {
+            write_edts(out, edits)?;
+        }
+        write_box(out, b"mdia", |out| {
+            write_mdhd(out, track.timescale(), media_duration)?;
+            write_hdlr(out, track)?;
+            write_box(out, b"minf", |out| {
+                write_media_header(out, track)?;
+                write_box(out, b"stbl", |out| {
+                    match track {
+                        Track::Video(video) => write_avc1_stsd(out, video)?,
+                        Track::Audio(audio) => write_mp4a_stsd(out, audio)?,
+                    }
+                    sample_table(out)
+                })
+            })
+        })
+    })
+}
+
+/// An edit list entry. Durations are in MOVIE_TIMESCALE units, media times
+/// in the track's timescale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edit {
+    /// Nothing plays for this long
+    Empty { duration: u64 },
+    /// Plays the media from media_time on
+    Media { duration: u64, media_time: u64 },
+}
+
+fn write_edts(out: &mut Vec<u8>, edits: &[Edit]) -> io::Result<()> {
+    let longest = edits
+        .iter()
+        .map(|edit| match edit {
+            Edit::Empty { duration } => *duration,
+            Edit::Media {
+                duration,
+                media_time,
+            } => cmp::max(*duration, *media_time),
+        })
+        .max()
+        .unwrap_or(0);
+    let version = version_for(longest);
+
+    write_box(out, b"edts", |out| {
+        write_full_box(out, b"elst", version, 0, |out| {
+            out.write_u32::<BigEndian>(edits.len() as u32)?;
+            for edit in edits {
+                let (duration, media_time) = match edit {
+                    Edit::Empty { duration } => (*duration, -1),
+                    Edit::Media {
+                        duration,
+                        media_time,
+                    } => (*duration, *media_time as i64),
+                };
+                if version == 1 {
+                    out.write_u64::<BigEndian>(duration)?;
+                    out.write_i64::<BigEndian>(media_time)?;
+                } else {
+                    out.write_u32::<BigEndian>(duration as u32)?;
+                    out.write_i32::<BigEndian>(media_time as i32)?;
+                }
+                out.write_i16::<BigEndian>(1)?; // rate
+                out.write_i16::<BigEndian>(0)?;
+            }
+            Ok(())
+        })
+    })
+}
+
+// Headers switch to version 1, with 64 bit times, when durations need it.
+fn version_for(duration: u64) -> u8 {
+    if duration > u64::from(u32::MAX) {
+        1
+    } else {
+        0
+    }
+}
+
+// Creation and modification times (always zero), whatever goes between them
+// and the duration, then the duration. All 64 bit in version 1 boxes.
+fn write_times(
+    out: &mut Vec<u8>,
+    duration: u64,
+    middle: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+) -> io::Result<()> {
+    if version_for(duration) == 1 {
+        out.write_u64::<BigEndian>(0)?;
+        out.write_u64::<BigEndian>(0)?;
+        middle(out)?;
+        out.write_u64::<BigEndian>(duration)
+    } else {
+        out.write_u32::<BigEndian>(0)?;
+        out.write_u32::<BigEndian>(0)?;
+        middle(out)?;
+        out.write_u32::<BigEndian>(duration as u32)
+    }
+}
+
+fn write_matrix(out: &mut impl Write) -> io::Result<()> {
+    for value in &UNITY_MATRIX {
+        out.write_u32::<BigEndian>(*value)?;
+    }
+    Ok(())
+}
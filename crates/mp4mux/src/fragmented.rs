@@ -0,0 +1,481 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::boxes::{self, Track};
+use crate::{
+    millis_to_timescale, sample_size, AudioTrack, VideoTrack, AAC_FRAME_SAMPLES,
+    DEFAULT_FRAME_TICKS, VIDEO_TIMESCALE,
+};
+
+// Sample flags (ISO/IEC 14496-12 section 8.8.3.1): sync samples don't depend
+// on any others, the rest do.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+// tfhd flags: sample data offsets are relative to the moof
+const DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+
+// trun flags
+const DATA_OFFSET_PRESENT: u32 = 0x001;
+const SAMPLE_DURATION_PRESENT: u32 = 0x100;
+const SAMPLE_SIZE_PRESENT: u32 = 0x200;
+const SAMPLE_FLAGS_PRESENT: u32 = 0x400;
+const SAMPLE_COMPOSITION_OFFSET_PRESENT: u32 = 0x800;
+
+/// The CMAF header (ISO/IEC 23000-19 section 7.3.2.1) of a video track: an
+/// ftyp and a moov describing the track, with no samples.
+pub fn video_init_segment(video: &VideoTrack) -> io::Result<Vec<u8>> {
+    init_segment(Track::Video(video))
+}
+
+/// The CMAF header of an AAC track
+pub fn audio_init_segment(audio: &AudioTrack) -> io::Result<Vec<u8>> {
+    init_segment(Track::Audio(audio))
+}
+
+fn init_segment(track: Track) -> io::Result<Vec<u8>> {
+    let default_flags = match track {
+        Track::Video(_) => NON_SYNC_SAMPLE_FLAGS,
+        Track::Audio(_) => SYNC_SAMPLE_FLAGS,
+    };
+
+    let mut out = Vec::new();
+    boxes::write_ftyp(&mut out, b"iso6", &[b"iso6", b"cmfc"])?;
+    boxes::write_box(&mut out, b"moov", |out| {
+        boxes::write_mvhd(out, 0)?;
+        boxes::write_trak(out, track, 0, 0, &[], write_empty_sample_table)?;
+        boxes::write_box(out, b"mvex", |out| {
+            boxes::write_full_box(out, b"trex", 0, 0, |out| {
+                out.write_u32::<BigEndian>(track.id())?;
+                out.write_u32::<BigEndian>(1)?; // sample description index
+                out.write_u32::<BigEndian>(0)?; // duration
+                out.write_u32::<BigEndian>(0)?; // size
+                out.write_u32::<BigEndian>(default_flags)
+            })
+        })
+    })?;
+
+    Ok(out)
+}
+
+// The samples are all in fragments.
+fn write_empty_sample_table(out: &mut Vec<u8>) -> io::Result<()> {
+    for box_type in &[b"stts", b"stsc", b"stco"] {
+        boxes::write_full_box(out, box_type, 0, 0, |out| out.write_u32::<BigEndian>(0))?;
+    }
+    boxes::write_full_box(out, b"stsz", 0, 0, |out| {
+        out.write_u32::<BigEndian>(0)?; // sample size, they vary
+        out.write_u32::<BigEndian>(0) // sample count
+    })
+}
+
+struct VideoSample {
+    decode_time: u64,
+    composition_offset: i32,
+    seekable: bool,
+    data: Vec<u8>,
+}
+
+struct AudioSample {
+    timestamp_millis: i64,
+    data: Vec<u8>,
+}
+
+// One CMAF track in one output: its header, then fragments of a moof (with
+// a single traf) and an mdat.
+struct TrackFragments<W: Write> {
+    out: W,
+    track_id: u32,
+    timescale: u32,
+    sequence_number: u32,
+}
+
+impl<W: Write> TrackFragments<W> {
+    fn new(mut out: W, track: Track) -> io::Result<Self> {
+        out.write_all(&init_segment(track)?)?;
+        Ok(TrackFragments {
+            out,
+            track_id: track.id(),
+            timescale: track.timescale(),
+            sequence_number: 1,
+        })
+    }
+
+    // entries writes the trun's fields for each sample, after the sample
+    // count and data offset.
+    fn write_fragment(
+        &mut self,
+        base_decode_time: u64,
+        trun_version: u8,
+        trun_flags: u32,
+        samples: &[&[u8]],
+        entries: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // Where the trun's data_offset field is in the moof
+        let mut data_offset_field = 0;
+        let mut moof = Vec::new();
+        boxes::write_box(&mut moof, b"moof", |out| {
+            boxes::write_full_box(out, b"mfhd", 0, 0, |out| {
+                out.write_u32::<BigEndian>(self.sequence_number)
+            })?;
+            boxes::write_box(out, b"traf", |out| {
+                boxes::write_full_box(out, b"tfhd", 0, DEFAULT_BASE_IS_MOOF, |out| {
+                    out.write_u32::<BigEndian>(self.track_id)
+                })?;
+                boxes::write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.write_u64::<BigEndian>(base_decode_time)
+                })?;
+                let flags = DATA_OFFSET_PRESENT | trun_flags;
+                boxes::write_full_box(out, b"trun", trun_version, flags, |out| {
+                    out.write_u32::<BigEndian>(samples.len() as u32)?;
+                    data_offset_field = out.len();
+                    out.write_i32::<BigEndian>(0)?;
+                    entries(out)
+                })
+            })
+        })?;
+
+        // Now that we know how big the moof is, the samples start just
+        // after the mdat's header.
+        let mdat_header_size = 8;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "MP4 fragment is too large");
+        let data_offset = i32::try_from(moof.len() + mdat_header_size).map_err(|_| too_large())?;
+        moof[data_offset_field..data_offset_field + 4].copy_from_slice(&data_offset.to_be_bytes());
+        let data_size: usize = samples.iter().map(|sample| sample.len()).sum();
+        let mdat_size = u32::try_from(data_size + mdat_header_size).map_err(|_| too_large())?;
+
+        self.out.write_all(&moof)?;
+        self.out.write_u32::<BigEndian>(mdat_size)?;
+        self.out.write_all(b"mdat")?;
+        for sample in samples {
+            self.out.write_all(sample)?;
+        }
+        self.sequence_number += 1;
+        Ok(())
+    }
+}
+
+/// Writes CMAF (ISO/IEC 23000-19) tracks for LL-HLS and DASH: the video to
+/// one output, and the AAC, if there is any, to another. Each output starts
+/// with the track's CMAF header, then has a fragment for each group of
+/// pictures. Video fragments start with a keyframe, and audio fragments hold
+/// the audio that starts during the video fragment they go with.
+///
+/// Timestamps are FLV style milliseconds, so the encoded video (and AAC)
+/// the stream crate produces, or the tags in an FLV file, can go straight in.
+pub struct FragmentedMp4Writer<W: Write> {
+    video: TrackFragments<W>,
+    audio: Option<TrackFragments<W>>,
+    video_samples: Vec<VideoSample>,
+    audio_samples: Vec<AudioSample>,
+    // In samples, from the first audio frame on
+    audio_decode_time: Option<u64>,
+    // Of the last frame, whether it's been written yet or not
+    last_decode_time: Option<u64>,
+    last_frame_ticks: u32,
+    seen_keyframe: bool,
+}
+
+impl<W: Write> FragmentedMp4Writer<W> {
+    /// Writes the CMAF headers.
+    pub fn new(
+        video_out: W,
+        video: VideoTrack,
+        audio: Option<(W, AudioTrack)>,
+    ) -> io::Result<Self> {
+        let video = TrackFragments::new(video_out, Track::Video(&video))?;
+        let audio = match audio {
+            Some((audio_out, audio)) => Some(TrackFragments::new(audio_out, Track::Audio(&audio))?),
+            None => None,
+        };
+
+        Ok(FragmentedMp4Writer {
+            video,
+            audio,
+            video_samples: Vec::new(),
+            audio_samples: Vec::new(),
+            audio_decode_time: None,
+            last_decode_time: None,
+            last_frame_ticks: DEFAULT_FRAME_TICKS,
+            seen_keyframe: false,
+        })
+    }
+
+    /// Adds a frame of AVCC video. A seekable frame finishes the fragments
+    /// before it. Frames before the first seekable one are dropped, since
+    /// nothing could decode them.
+    pub fn write_video(
+        &mut self,
+        decode_time_millis: i32,
+        composition_offset_millis: i32,
+        seekable: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let decode_time = millis_to_timescale(i64::from(decode_time_millis), VIDEO_TIMESCALE)?;
+        if let Some(last) = self.last_decode_time {
+            if decode_time < last {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "video decode times must not go backwards, got {} after {}",
+                        decode_time_millis,
+                        last * 1000 / u64::from(VIDEO_TIMESCALE)
+                    ),
+                ));
+            }
+        }
+
+        if seekable && !self.video_samples.is_empty() {
+            self.write_fragments(Some(decode_time))?;
+        }
+
+        self.seen_keyframe |= seekable;
+        if !self.seen_keyframe {
+            return Ok(());
+        }
+
+        let composition_offset =
+            i64::from(composition_offset_millis) * i64::from(VIDEO_TIMESCALE) / 1000;
+        self.video_samples.push(VideoSample {
+            decode_time,
+            composition_offset: composition_offset as i32,
+            seekable,
+            data: data.to_vec(),
+        });
+        self.last_decode_time = Some(decode_time);
+        Ok(())
+    }
+
+    /// Adds a raw AAC frame.
+    pub fn write_audio(&mut self, timestamp_millis: i32, data: &[u8]) -> io::Result<()> {
+        if self.audio.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "this fragmented MP4 has no audio track",
+            ));
+        }
+
+        self.audio_samples.push(AudioSample {
+            timestamp_millis: i64::from(timestamp_millis),
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+
+    /// Writes whatever is left as the last fragments, and hands back the
+    /// video and audio outputs.
+    pub fn finish(mut self) -> io::Result<(W, Option<W>)> {
+        self.write_fragments(None)?;
+        self.video.out.flush()?;
+        let audio = match self.audio {
+            Some(mut audio) => {
+                audio.out.flush()?;
+                Some(audio.out)
+            }
+            None => None,
+        };
+        Ok((self.video.out, audio))
+    }
+
+    // Writes the buffered video as a fragment, and the audio that starts
+    // before the end of it as a fragment of the audio track. end_time is the
+    // decode time of the frame after the last one, if we know it.
+    fn write_fragments(&mut self, end_time: Option<u64>) -> io::Result<()> {
+        let video: Vec<VideoSample> = self.video_samples.drain(..).collect();
+        if let Some(first) = video.first() {
+            let mut durations = Vec::with_capacity(video.len());
+            for (ix, sample) in video.iter().enumerate() {
+                let next = video.get(ix + 1).map(|s| s.decode_time).or(end_time);
+                let duration = match next {
+                    // write_video keeps decode times in order
+                    Some(next) => u32::try_from(next - sample.decode_time).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "video frame is too long")
+                    })?,
+                    None => self.last_frame_ticks,
+                };
+                self.last_frame_ticks = duration;
+                durations.push(duration);
+            }
+
+            let data: Vec<&[u8]> = video.iter().map(|sample| &sample.data[..]).collect();
+            let flags = SAMPLE_DURATION_PRESENT
+                | SAMPLE_SIZE_PRESENT
+                | SAMPLE_FLAGS_PRESENT
+                | SAMPLE_COMPOSITION_OFFSET_PRESENT;
+            // Version 1, for signed composition offsets
+            self.video
+                .write_fragment(first.decode_time, 1, flags, &data, |out| {
+                    for (sample, duration) in video.iter().zip(&durations) {
+                        out.write_u32::<BigEndian>(*duration)?;
+                        out.write_u32::<BigEndian>(sample_size(&sample.data)?)?;
+                        out.write_u32::<BigEndian>(if sample.seekable {
+                            SYNC_SAMPLE_FLAGS
+                        } else {
+                            NON_SYNC_SAMPLE_FLAGS
+                        })?;
+                        out.write_i32::<BigEndian>(sample.composition_offset)?;
+                    }
+                    Ok(())
+                })?;
+        }
+
+        let track = match &mut self.audio {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+        let split = match end_time {
+            Some(end) => {
+                let end_millis = (end * 1000 / u64::from(VIDEO_TIMESCALE)) as i64;
+                self.audio_samples
+                    .iter()
+                    .position(|s| s.timestamp_millis >= end_millis)
+                    .unwrap_or(self.audio_samples.len())
+            }
+            None => self.audio_samples.len(),
+        };
+        let audio: Vec<AudioSample> = self.audio_samples.drain(..split).collect();
+        if let Some(first) = audio.first() {
+            let decode_time = match self.audio_decode_time {
+                Some(decode_time) => decode_time,
+                None => millis_to_timescale(first.timestamp_millis, track.timescale)?,
+            };
+
+            let data: Vec<&[u8]> = audio.iter().map(|sample| &sample.data[..]).collect();
+            let flags = SAMPLE_DURATION_PRESENT | SAMPLE_SIZE_PRESENT;
+            track.write_fragment(decode_time, 0, flags, &data, |out| {
+                for sample in &audio {
+                    out.write_u32::<BigEndian>(AAC_FRAME_SAMPLES)?;
+                    out.write_u32::<BigEndian>(sample_size(&sample.data)?)?;
+                }
+                Ok(())
+            })?;
+            self.audio_decode_time =
+                Some(decode_time + audio.len() as u64 * u64::from(AAC_FRAME_SAMPLES));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{boxes, find, words, AVC_CONFIG};
+    use crate::{AUDIO_TRACK_ID, VIDEO_TRACK_ID};
+    use byteorder::ReadBytesExt;
+    use flvmux::aac::audio_specific_config;
+
+    // The moofs and mdats after a CMAF header
+    fn fragments(data: &[u8]) -> Vec<(String, &[u8])> {
+        let top = boxes(data);
+        let types: Vec<&str> = top.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(vec!["ftyp", "moov"], types[..2]);
+        top[2..].to_vec()
+    }
+
+    #[test]
+    fn test_init_segment() {
+        let video = VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap();
+        let init = video_init_segment(&video).unwrap();
+
+        let ftyp = find(&init, &["ftyp"]);
+        assert_eq!(b"iso6", &ftyp[..4]);
+        assert!(ftyp[8..].chunks(4).any(|brand| brand == b"cmfc"));
+
+        // One track, described by one trex
+        let moov = boxes(find(&init, &["moov"]));
+        assert_eq!(1, moov.iter().filter(|(t, _)| t == "trak").count());
+        let trex = words(find(&init, &["moov", "mvex", "trex"]));
+        assert_eq!(vec![VIDEO_TRACK_ID, 1, 0, 0, NON_SYNC_SAMPLE_FLAGS], trex);
+    }
+
+    #[test]
+    fn test_fragments() {
+        let video = VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap();
+        let audio = AudioTrack::new(audio_specific_config(2, 4, 2).to_vec()).unwrap();
+        let mut writer =
+            FragmentedMp4Writer::new(Vec::new(), video, Some((Vec::new(), audio))).unwrap();
+
+        writer.write_video(0, 0, false, &[9; 10]).unwrap(); // dropped, before a keyframe
+        writer.write_video(0, 66, true, &[1; 10]).unwrap();
+        writer.write_audio(0, &[5; 3]).unwrap();
+        writer.write_video(33, 0, false, &[2; 10]).unwrap();
+        writer.write_audio(23, &[6; 3]).unwrap();
+        writer.write_audio(46, &[7; 3]).unwrap();
+        writer.write_video(66, 0, true, &[3; 10]).unwrap();
+        writer.write_audio(69, &[8; 3]).unwrap();
+        let (video_mp4, audio_mp4) = writer.finish().unwrap();
+        let audio_mp4 = audio_mp4.unwrap();
+
+        let video_fragments = fragments(&video_mp4);
+        let types: Vec<&str> = video_fragments.iter().map(|(t, _)| t.as_str()).collect();
+        assert_eq!(vec!["moof", "mdat", "moof", "mdat"], types);
+
+        // The first video fragment: the two frames before the second keyframe
+        let (_, moof) = video_fragments[0];
+        let (_, mdat) = video_fragments[1];
+        assert_eq!([[1; 10], [2; 10]].concat(), mdat);
+        assert_eq!(vec![1], words(find(moof, &["mfhd"])));
+        assert_eq!(1, boxes(moof).iter().filter(|(t, _)| t == "traf").count());
+        assert_eq!(vec![VIDEO_TRACK_ID], words(find(moof, &["traf", "tfhd"])));
+        assert_eq!(vec![0, 0], words(find(moof, &["traf", "tfdt"])));
+
+        let mut fields = &find(moof, &["traf", "trun"])[4..];
+        assert_eq!(2, fields.read_u32::<BigEndian>().unwrap());
+        let moof_size = moof.len() as i32 + 8;
+        assert_eq!(moof_size + 8, fields.read_i32::<BigEndian>().unwrap());
+        assert_eq!(2970, fields.read_u32::<BigEndian>().unwrap()); // duration
+        assert_eq!(10, fields.read_u32::<BigEndian>().unwrap()); // size
+        assert_eq!(SYNC_SAMPLE_FLAGS, fields.read_u32::<BigEndian>().unwrap());
+        assert_eq!(5940, fields.read_i32::<BigEndian>().unwrap()); // composition offset
+
+        let (_, moof) = video_fragments[2];
+        assert_eq!(vec![2], words(find(moof, &["mfhd"])));
+        assert_eq!(vec![0, 5940], words(find(moof, &["traf", "tfdt"])));
+
+        // The audio that started before 66ms, then the rest
+        let audio_fragments = fragments(&audio_mp4);
+        assert_eq!(4, audio_fragments.len());
+        let (_, moof) = audio_fragments[0];
+        let (_, mdat) = audio_fragments[1];
+        assert_eq!([[5; 3], [6; 3], [7; 3]].concat(), mdat);
+        assert_eq!(vec![AUDIO_TRACK_ID], words(find(moof, &["traf", "tfhd"])));
+        assert_eq!(vec![0, 0], words(find(moof, &["traf", "tfdt"])));
+
+        let mut fields = &find(moof, &["traf", "trun"])[4..];
+        assert_eq!(3, fields.read_u32::<BigEndian>().unwrap());
+        let moof_size = moof.len() as i32 + 8;
+        assert_eq!(moof_size + 8, fields.read_i32::<BigEndian>().unwrap());
+        assert_eq!(AAC_FRAME_SAMPLES, fields.read_u32::<BigEndian>().unwrap());
+
+        let (_, moof) = audio_fragments[2];
+        assert_eq!([8; 3], audio_fragments[3].1);
+        assert_eq!(vec![0, 3 * 1024], words(find(moof, &["traf", "tfdt"])));
+    }
+
+    #[test]
+    fn test_negative_timestamps() {
+        let video = VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap();
+        let mut writer = FragmentedMp4Writer::new(Vec::new(), video, None).unwrap();
+        assert!(writer.write_video(-33, 0, true, &[1]).is_err());
+        assert!(writer.write_audio(0, &[1]).is_err());
+    }
+
+    #[test]
+    fn test_backwards_decode_time() {
+        let video = VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap();
+        let mut writer = FragmentedMp4Writer::new(Vec::new(), video, None).unwrap();
+        writer.write_video(100, 0, true, &[1]).unwrap();
+        // A repeated decode time is fine, an earlier one isn't
+        writer.write_video(100, 0, false, &[2]).unwrap();
+        let err = writer.write_video(66, 0, false, &[3]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        // Even once the frames it would go before are written out
+        writer.write_video(133, 0, true, &[4]).unwrap();
+        writer.write_video(166, 0, true, &[5]).unwrap();
+        let err = writer.write_video(150, 0, true, &[6]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -0,0 +1,160 @@
+use std::convert::TryFrom;
+use std::io;
+
+use flvmux::aac::{self, AudioConfig};
+use flvmux::avc;
+
+mod boxes;
+mod fragmented;
+mod progressive;
+
+pub use fragmented::{audio_init_segment, video_init_segment, FragmentedMp4Writer};
+pub use progressive::Mp4Writer;
+
+/// Timescale of the movie header, and of track header durations
+pub const MOVIE_TIMESCALE: u32 = 1000;
+
+/// Video timestamps are kept in 90kHz ticks, like h264 (and MPEG-TS) does.
+/// Audio uses its sample rate.
+pub const VIDEO_TIMESCALE: u32 = 90_000;
+
+pub const VIDEO_TRACK_ID: u32 = 1;
+pub const AUDIO_TRACK_ID: u32 = 2;
+
+/// Samples in an AAC-LC frame
+pub const AAC_FRAME_SAMPLES: u32 = 1024;
+
+// If a stream has just one frame, or ends on a keyframe, there's nothing
+// to say how long it lasts. One frame at 30 fps.
+const DEFAULT_FRAME_TICKS: u32 = VIDEO_TIMESCALE / 30;
+
+/// H.264 video, in AVCC format with four byte NAL unit lengths (like the
+/// payload of FLV video tags).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VideoTrack {
+    pub width: u16,
+    pub height: u16,
+    /// An AVCDecoderConfigurationRecord, like the payload of an FLV AVC sequence header
+    pub decoder_configuration: Vec<u8>,
+}
+
+impl VideoTrack {
+    pub fn new(width: u16, height: u16, decoder_configuration: Vec<u8>) -> io::Result<Self> {
+        avc::parse_decoder_configuration_record(&decoder_configuration)?;
+        Ok(VideoTrack {
+            width,
+            height,
+            decoder_configuration,
+        })
+    }
+
+    /// Takes the picture size from the first SPS in the record.
+    pub fn from_decoder_configuration(decoder_configuration: Vec<u8>) -> io::Result<Self> {
+        let config = avc::parse_decoder_configuration_record(&decoder_configuration)?;
+        let sps = config.sps.first().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted input, AVC decoder configuration has no SPS",
+            )
+        })?;
+        let info = avc::parse_sps(sps)?;
+        let dimension = |size| {
+            u16::try_from(size).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, "video is too large for MP4")
+            })
+        };
+
+        Ok(VideoTrack {
+            width: dimension(info.width)?,
+            height: dimension(info.height)?,
+            decoder_configuration,
+        })
+    }
+}
+
+/// AAC audio, as raw AAC frames of AAC_FRAME_SAMPLES samples each (like the
+/// payload of FLV audio tags).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AudioTrack {
+    /// Like the payload of an FLV AAC sequence header
+    pub audio_specific_config: Vec<u8>,
+    pub config: AudioConfig,
+}
+
+impl AudioTrack {
+    pub fn new(audio_specific_config: Vec<u8>) -> io::Result<Self> {
+        let config = aac::parse_audio_specific_config(&audio_specific_config)?;
+        Ok(AudioTrack {
+            audio_specific_config,
+            config,
+        })
+    }
+}
+
+// Timestamps come in as FLV style milliseconds.
+fn millis_to_timescale(millis: i64, timescale: u32) -> io::Result<u64> {
+    if millis < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MP4 decode times can't be negative",
+        ));
+    }
+
+    Ok(millis as u64 * u64::from(timescale) / 1000)
+}
+
+fn sample_size(data: &[u8]) -> io::Result<u32> {
+    u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MP4 sample is too large"))
+}
+
+// Fixtures and box walkers for the writers' tests
+#[cfg(test)]
+mod test_util {
+    use byteorder::{BigEndian, ReadBytesExt};
+
+    // A decoder configuration record with a (made up) SPS and PPS
+    pub const AVC_CONFIG: [u8; 17] = [
+        1, 0x64, 0x00, 0x1f, 0xff, 0xe1, 0, 4, 0x67, 0x64, 0x00, 0x1f, 1, 0, 2, 0x68, 0xef,
+    ];
+
+    // Splits a run of boxes into (type, body)
+    pub fn boxes(mut data: &[u8]) -> Vec<(String, &[u8])> {
+        let mut ret = Vec::new();
+        while !data.is_empty() {
+            let mut size = (&data[..4]).read_u32::<BigEndian>().unwrap() as usize;
+            let box_type = String::from_utf8(data[4..8].to_vec()).unwrap();
+            let mut header = 8;
+            if size == 1 {
+                size = (&data[8..16]).read_u64::<BigEndian>().unwrap() as usize;
+                header = 16;
+            }
+            ret.push((box_type, &data[header..size]));
+            data = &data[size..];
+        }
+        ret
+    }
+
+    // The body of the first box along a path, like ["moov", "mvex"]
+    pub fn find<'a>(data: &'a [u8], path: &[&str]) -> &'a [u8] {
+        let (first, rest) = path.split_first().unwrap();
+        let body = boxes(data)
+            .into_iter()
+            .find(|(t, _)| t == first)
+            .unwrap_or_else(|| panic!("no {} box", first))
+            .1;
+        if rest.is_empty() {
+            body
+        } else {
+            find(body, rest)
+        }
+    }
+
+    // The u32s after a full box's version and flags
+    pub fn words(body: &[u8]) -> Vec<u32> {
+        body[4..]
+            .chunks(4)
+            .map(|mut word| word.read_u32::<BigEndian>().unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,549 @@
+use byteorder::{BigEndian, WriteBytesExt};
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::boxes::{self, Edit, Track};
+use crate::{
+    millis_to_timescale, sample_size, AudioTrack, VideoTrack, AAC_FRAME_SAMPLES, AUDIO_TRACK_ID,
+    DEFAULT_FRAME_TICKS, MOVIE_TIMESCALE, VIDEO_TIMESCALE, VIDEO_TRACK_ID,
+};
+
+// A 32 bit size of 1 means a 64 bit size follows the type.
+const MDAT_HEADER_SIZE: u64 = 16;
+
+#[derive(Default)]
+struct SampleTable {
+    // In the track's timescale. Audio frames are all AAC_FRAME_SAMPLES long,
+    // so only the first one matters.
+    decode_times: Vec<u64>,
+    composition_offsets: Vec<i32>,
+    sizes: Vec<u32>,
+    // Numbered from one, like stss
+    sync_samples: Vec<u32>,
+    // (file offset, sample count)
+    chunks: Vec<(u64, u32)>,
+}
+
+impl SampleTable {
+    fn push(&mut self, offset: u64, size: u32, same_chunk: bool) {
+        self.sizes.push(size);
+        match self.chunks.last_mut() {
+            Some((_, count)) if same_chunk => *count += 1,
+            _ => self.chunks.push((offset, 1)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+}
+
+/// Writes progressive MP4: the ftyp, every sample in one mdat, then a moov
+/// indexing them. Samples go to the output as they come in, so only the
+/// index is kept in memory, and finish() seeks back to fill in the size of
+/// the mdat.
+///
+/// Timestamps are FLV style milliseconds, like FragmentedMp4Writer.
+pub struct Mp4Writer<W: Write + Seek> {
+    out: W,
+    mdat_start: u64,
+    position: u64,
+    video: Option<VideoTrack>,
+    audio: Option<AudioTrack>,
+    video_samples: SampleTable,
+    audio_samples: SampleTable,
+    // Track of the last sample written. Runs of samples from one track are
+    // written as chunks.
+    last_track: Option<u32>,
+}
+
+impl<W: Write + Seek> Mp4Writer<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        let mut header = Vec::new();
+        boxes::write_ftyp(&mut header, b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"])?;
+        let mdat_start = out.stream_position()? + header.len() as u64;
+        header.write_u32::<BigEndian>(1)?;
+        header.write_all(b"mdat")?;
+        header.write_u64::<BigEndian>(0)?; // filled in by finish()
+        out.write_all(&header)?;
+
+        Ok(Mp4Writer {
+            out,
+            mdat_start,
+            position: mdat_start + MDAT_HEADER_SIZE,
+            video: None,
+            audio: None,
+            video_samples: SampleTable::default(),
+            audio_samples: SampleTable::default(),
+            last_track: None,
+        })
+    }
+
+    /// Describes the video. Tracks can be set any time before their first
+    /// sample, but can't change after that.
+    pub fn set_video_track(&mut self, track: VideoTrack) -> io::Result<()> {
+        set_track(&mut self.video, track)
+    }
+
+    pub fn set_audio_track(&mut self, track: AudioTrack) -> io::Result<()> {
+        set_track(&mut self.audio, track)
+    }
+
+    /// Adds a frame of AVCC video. Frames before the first seekable one are
+    /// dropped, since nothing could decode them.
+    pub fn write_video(
+        &mut self,
+        decode_time_millis: i32,
+        composition_offset_millis: i32,
+        seekable: bool,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.video.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "video before the MP4 video track was set",
+            ));
+        }
+        if self.video_samples.is_empty() && !seekable {
+            return Ok(());
+        }
+
+        let decode_time = millis_to_timescale(i64::from(decode_time_millis), VIDEO_TIMESCALE)?;
+        if let Some(&last) = self.video_samples.decode_times.last() {
+            if decode_time < last {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "video decode times must not go backwards, got {} after {}",
+                        decode_time_millis,
+                        last * 1000 / u64::from(VIDEO_TIMESCALE)
+                    ),
+                ));
+            }
+        }
+        let composition_offset =
+            i64::from(composition_offset_millis) * i64::from(VIDEO_TIMESCALE) / 1000;
+        self.video_samples.decode_times.push(decode_time);
+        self.video_samples
+            .composition_offsets
+            .push(composition_offset as i32);
+        if seekable {
+            let number = self.video_samples.sizes.len() as u32 + 1;
+            self.video_samples.sync_samples.push(number);
+        }
+
+        let same_chunk = self.last_track == Some(VIDEO_TRACK_ID);
+        self.video_samples
+            .push(self.position, sample_size(data)?, same_chunk);
+        self.write_sample(VIDEO_TRACK_ID, data)
+    }
+
+    /// Adds a raw AAC frame.
+    pub fn write_audio(&mut self, timestamp_millis: i32, data: &[u8]) -> io::Result<()> {
+        let track = self.audio.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "audio before the MP4 audio track was set",
+            )
+        })?;
+
+        if self.audio_samples.is_empty() {
+            let decode_time =
+                millis_to_timescale(i64::from(timestamp_millis), track.config.sample_rate())?;
+            self.audio_samples.decode_times.push(decode_time);
+        }
+
+        let same_chunk = self.last_track == Some(AUDIO_TRACK_ID);
+        self.audio_samples
+            .push(self.position, sample_size(data)?, same_chunk);
+        self.write_sample(AUDIO_TRACK_ID, data)
+    }
+
+    /// Fills in the mdat size and writes the moov.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.out
+            .write_u64::<BigEndian>(self.position - self.mdat_start)?;
+        self.out.seek(SeekFrom::Start(self.position))?;
+
+        let moov = self.moov()?;
+        self.out.write_all(&moov)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_sample(&mut self, track_id: u32, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.position += data.len() as u64;
+        self.last_track = Some(track_id);
+        Ok(())
+    }
+
+    fn moov(&self) -> io::Result<Vec<u8>> {
+        let video = match &self.video {
+            Some(track) if !self.video_samples.is_empty() => {
+                Some(video_timing(track, &self.video_samples)?)
+            }
+            _ => None,
+        };
+        let audio = match &self.audio {
+            Some(track) if !self.audio_samples.is_empty() => {
+                Some(audio_timing(track, &self.audio_samples))
+            }
+            _ => None,
+        };
+
+        // Tracks that start late are delayed with an empty edit, to keep
+        // them in sync with the one that starts first.
+        let origin_millis = video
+            .iter()
+            .chain(audio.iter())
+            .map(|timing| timing.start_millis)
+            .min()
+            .unwrap_or(0);
+
+        let mut traks = Vec::new();
+        let mut movie_duration = 0;
+        for timing in video.iter().chain(audio.iter()) {
+            let mut edits = Vec::new();
+            let delay = (timing.start_millis - origin_millis) as u64;
+            if delay > 0 {
+                edits.push(Edit::Empty { duration: delay });
+            }
+            let media_millis =
+                to_timescale(timing.presented, timing.track.timescale(), MOVIE_TIMESCALE);
+            if delay > 0 || timing.media_time > 0 {
+                edits.push(Edit::Media {
+                    duration: media_millis,
+                    media_time: timing.media_time,
+                });
+            }
+
+            let track_duration = delay + media_millis;
+            movie_duration = cmp::max(movie_duration, track_duration);
+            boxes::write_trak(
+                &mut traks,
+                timing.track,
+                track_duration,
+                timing.duration,
+                &edits,
+                |out| match timing.track {
+                    Track::Video(_) => write_video_sample_table(out, &self.video_samples),
+                    Track::Audio(_) => write_audio_sample_table(out, &self.audio_samples),
+                },
+            )?;
+        }
+
+        let mut moov = Vec::new();
+        boxes::write_box(&mut moov, b"moov", |out| {
+            boxes::write_mvhd(out, movie_duration)?;
+            out.write_all(&traks)
+        })?;
+        Ok(moov)
+    }
+}
+
+fn set_track<T: PartialEq>(current: &mut Option<T>, track: T) -> io::Result<()> {
+    match current {
+        Some(current) if *current != track => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MP4 tracks can't change their configuration",
+        )),
+        _ => {
+            *current = Some(track);
+            Ok(())
+        }
+    }
+}
+
+struct Timing<'a> {
+    track: Track<'a>,
+    // When the first sample is presented
+    start_millis: i64,
+    // In the track's timescale
+    duration: u64,
+    // Where presentation starts on the media timeline. With B-frames, the
+    // first frame is presented a little after it's decoded.
+    media_time: u64,
+    // How long presentation lasts from there, to the end of the last frame
+    presented: u64,
+}
+
+fn video_timing<'a>(track: &'a VideoTrack, samples: &SampleTable) -> io::Result<Timing<'a>> {
+    let durations = video_durations(samples)?;
+    let first = samples.decode_times[0];
+
+    // Presentation times, relative to the first decode time. write_video
+    // keeps decode times in order, so only composition offsets go negative.
+    let mut start = i64::MAX;
+    let mut end = 0i64;
+    for ((dts, cto), duration) in samples
+        .decode_times
+        .iter()
+        .zip(&samples.composition_offsets)
+        .zip(&durations)
+    {
+        let pts = dts.saturating_sub(first) as i64 + i64::from(*cto);
+        start = cmp::min(start, pts);
+        end = cmp::max(end, pts + i64::from(*duration));
+    }
+    let media_time = start.max(0) as u64;
+
+    Ok(Timing {
+        track: Track::Video(track),
+        start_millis: ((first + media_time) * 1000 / u64::from(VIDEO_TIMESCALE)) as i64,
+        duration: durations.iter().map(|d| u64::from(*d)).sum(),
+        media_time,
+        presented: (end as u64).saturating_sub(media_time),
+    })
+}
+
+fn audio_timing<'a>(track: &'a AudioTrack, samples: &SampleTable) -> Timing<'a> {
+    let sample_rate = u64::from(track.config.sample_rate());
+    let duration = samples.sizes.len() as u64 * u64::from(AAC_FRAME_SAMPLES);
+    Timing {
+        track: Track::Audio(track),
+        start_millis: (samples.decode_times[0] * 1000 / sample_rate) as i64,
+        duration,
+        media_time: 0,
+        presented: duration,
+    }
+}
+
+// Each frame lasts until the next one is decoded. The last one gets the
+// same duration as the frame before it.
+fn video_durations(samples: &SampleTable) -> io::Result<Vec<u32>> {
+    let mut durations = Vec::with_capacity(samples.decode_times.len());
+    for pair in samples.decode_times.windows(2) {
+        let duration = u32::try_from(pair[1].saturating_sub(pair[0]))
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "video frame is too long"))?;
+        durations.push(duration);
+    }
+    durations.push(durations.last().copied().unwrap_or(DEFAULT_FRAME_TICKS));
+    Ok(durations)
+}
+
+fn to_timescale(value: u64, from: u32, to: u32) -> u64 {
+    value * u64::from(to) / u64::from(from)
+}
+
+fn write_video_sample_table(out: &mut Vec<u8>, samples: &SampleTable) -> io::Result<()> {
+    write_stts(out, &video_durations(samples)?)?;
+
+    let offsets = &samples.composition_offsets;
+    if offsets.iter().any(|offset| *offset != 0) {
+        // Version 1 offsets are signed
+        let version = if offsets.iter().any(|offset| *offset < 0) {
+            1
+        } else {
+            0
+        };
+        boxes::write_full_box(out, b"ctts", version, 0, |out| {
+            let runs = run_lengths(offsets);
+            out.write_u32::<BigEndian>(runs.len() as u32)?;
+            for (count, offset) in runs {
+                out.write_u32::<BigEndian>(count)?;
+                out.write_i32::<BigEndian>(offset)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    // Without an stss, every sample is a sync sample.
+    if samples.sync_samples.len() != samples.sizes.len() {
+        boxes::write_full_box(out, b"stss", 0, 0, |out| {
+            out.write_u32::<BigEndian>(samples.sync_samples.len() as u32)?;
+            for number in &samples.sync_samples {
+                out.write_u32::<BigEndian>(*number)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    write_chunks(out, samples)
+}
+
+fn write_audio_sample_table(out: &mut Vec<u8>, samples: &SampleTable) -> io::Result<()> {
+    write_stts(out, &vec![AAC_FRAME_SAMPLES; samples.sizes.len()])?;
+    write_chunks(out, samples)
+}
+
+fn write_stts(out: &mut Vec<u8>, durations: &[u32]) -> io::Result<()> {
+    boxes::write_full_box(out, b"stts", 0, 0, |out| {
+        let runs = run_lengths(durations);
+        out.write_u32::<BigEndian>(runs.len() as u32)?;
+        for (count, duration) in runs {
+            out.write_u32::<BigEndian>(count)?;
+            out.write_u32::<BigEndian>(duration)?;
+        }
+        Ok(())
+    })
+}
+
+// The sample to chunk map, sample sizes, and chunk offsets.
+fn write_chunks(out: &mut Vec<u8>, samples: &SampleTable) -> io::Result<()> {
+    boxes::write_full_box(out, b"stsc", 0, 0, |out| {
+        // Only chunks that hold a different number of samples than the one
+        // before them get an entry.
+        let mut entries = Vec::new();
+        let mut last_count = None;
+        for (ix, (_, count)) in samples.chunks.iter().enumerate() {
+            if last_count != Some(*count) {
+                entries.push((ix as u32 + 1, *count));
+                last_count = Some(*count);
+            }
+        }
+
+        out.write_u32::<BigEndian>(entries.len() as u32)?;
+        for (first_chunk, count) in entries {
+            out.write_u32::<BigEndian>(first_chunk)?;
+            out.write_u32::<BigEndian>(count)?;
+            out.write_u32::<BigEndian>(1)?; // sample description index
+        }
+        Ok(())
+    })?;
+
+    boxes::write_full_box(out, b"stsz", 0, 0, |out| {
+        out.write_u32::<BigEndian>(0)?; // sample size, they vary
+        out.write_u32::<BigEndian>(samples.sizes.len() as u32)?;
+        for size in &samples.sizes {
+            out.write_u32::<BigEndian>(*size)?;
+        }
+        Ok(())
+    })?;
+
+    // Past 4GB, offsets need 64 bits.
+    let last_offset = samples.chunks.last().map(|(offset, _)| *offset);
+    if last_offset.unwrap_or(0) > u64::from(u32::MAX) {
+        boxes::write_full_box(out, b"co64", 0, 0, |out| {
+            out.write_u32::<BigEndian>(samples.chunks.len() as u32)?;
+            for (offset, _) in &samples.chunks {
+                out.write_u64::<BigEndian>(*offset)?;
+            }
+            Ok(())
+        })
+    } else {
+        boxes::write_full_box(out, b"stco", 0, 0, |out| {
+            out.write_u32::<BigEndian>(samples.chunks.len() as u32)?;
+            for (offset, _) in &samples.chunks {
+                out.write_u32::<BigEndian>(*offset as u32)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// (count, value) for each run of equal values
+fn run_lengths<T: Copy + PartialEq>(values: &[T]) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if last == value => *count += 1,
+            _ => runs.push((1, *value)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{boxes, find, words, AVC_CONFIG};
+    use flvmux::aac::audio_specific_config;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_progressive() {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        writer
+            .set_video_track(VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap())
+            .unwrap();
+        writer
+            .set_audio_track(AudioTrack::new(audio_specific_config(2, 4, 2).to_vec()).unwrap())
+            .unwrap();
+
+        // I, P, B, then another I, presented at 66, 133, 100 and 166ms
+        writer.write_video(0, 66, true, &[1; 10]).unwrap();
+        writer.write_audio(0, &[5; 3]).unwrap();
+        writer.write_audio(21, &[6; 3]).unwrap();
+        writer.write_video(33, 100, false, &[2; 10]).unwrap();
+        writer.write_video(66, 33, false, &[3; 10]).unwrap();
+        writer.write_video(100, 66, true, &[4; 10]).unwrap();
+        let mp4 = writer.finish().unwrap().into_inner();
+
+        let top: Vec<String> = boxes(&mp4).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(vec!["ftyp", "mdat", "moov"], top);
+        let mdat = find(&mp4, &["mdat"]);
+        assert_eq!(46, mdat.len());
+        assert_eq!([&[1; 10][..], &[5; 3], &[6; 3]].concat(), mdat[..16]);
+
+        let traks: Vec<&[u8]> = boxes(find(&mp4, &["moov"]))
+            .into_iter()
+            .filter(|(t, _)| t == "trak")
+            .map(|(_, b)| b)
+            .collect();
+        assert_eq!(2, traks.len());
+
+        let video = find(traks[0], &["mdia", "minf", "stbl"]);
+        // The last frame lasts as long as the one before it
+        assert_eq!(vec![2, 2, 2970, 2, 3060], words(find(video, &["stts"])));
+        assert_eq!(
+            vec![4, 1, 5940, 1, 9000, 1, 2970, 1, 5940],
+            words(find(video, &["ctts"]))
+        );
+        assert_eq!(vec![2, 1, 4], words(find(video, &["stss"])));
+        // One chunk of one frame, then one of three
+        assert_eq!(vec![2, 1, 1, 1, 2, 3, 1], words(find(video, &["stsc"])));
+        assert_eq!(vec![0, 4, 10, 10, 10, 10], words(find(video, &["stsz"])));
+        let mdat_offset = 32 + 16;
+        assert_eq!(
+            vec![2, mdat_offset, mdat_offset + 16],
+            words(find(video, &["stco"]))
+        );
+
+        // Video starts at 66ms, after the audio. Its media starts with the
+        // first frame's composition offset, and lasts until the last frame
+        // presented (at 166ms) ends.
+        let elst = words(find(traks[0], &["edts", "elst"]));
+        assert_eq!(
+            vec![2, 66, 0xffff_ffff, 0x0001_0000, 134, 5940, 0x0001_0000],
+            elst
+        );
+
+        // Audio starts first, so it needs no edits.
+        let audio = find(traks[1], &["mdia", "minf", "stbl"]);
+        assert_eq!(vec![1, 2, 1024], words(find(audio, &["stts"])));
+        assert_eq!(vec![1, 1, 2, 1], words(find(audio, &["stsc"])));
+        assert_eq!(vec![1, mdat_offset + 10], words(find(audio, &["stco"])));
+        assert!(boxes(traks[1]).iter().all(|(t, _)| t != "edts"));
+    }
+
+    #[test]
+    fn test_backwards_decode_time() {
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        writer
+            .set_video_track(VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap())
+            .unwrap();
+        writer.write_video(100, 0, true, &[1; 10]).unwrap();
+        // A repeated decode time is fine, an earlier one isn't
+        writer.write_video(100, 0, false, &[2; 10]).unwrap();
+        let err = writer.write_video(66, 0, false, &[3; 10]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_single_frame() {
+        // A keyframe presented well after it's decoded, and nothing else
+        let mut writer = Mp4Writer::new(Cursor::new(Vec::new())).unwrap();
+        writer
+            .set_video_track(VideoTrack::new(320, 240, AVC_CONFIG.to_vec()).unwrap())
+            .unwrap();
+        writer.write_video(0, 66, true, &[1; 10]).unwrap();
+        let mp4 = writer.finish().unwrap().into_inner();
+
+        // Presented from 5940 for the default frame duration, 33ms
+        let elst = words(find(&mp4, &["moov", "trak", "edts", "elst"]));
+        assert_eq!(vec![1, 33, 5940, 0x0001_0000], elst);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use flvmux::AacAudioPacketType;
+
+use crate::{FrameRate, StreamError};
+
+/// AAC-LC frames always hold 1024 samples per channel
+pub const AAC_FRAME_SAMPLES: u64 = 1024;
+
+/// Turns interleaved 16 bit PCM into raw AAC frames. Implement this over
+/// whatever AAC library you have handy.
+pub trait AacEncoder {
+    /// Samples per second, per channel
+    fn sample_rate(&self) -> u32;
+
+    fn channels(&self) -> u8;
+
+    /// The AudioSpecificConfig that goes in the AAC sequence header
+    fn audio_specific_config(&self) -> Vec<u8>;
+
+    /// Takes any number of interleaved samples and returns every raw AAC frame
+    /// (without ADTS headers) that is ready. Encoders are expected to buffer
+    /// partial frames internally.
+    fn encode(&mut self, pcm: &[i16]) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Called once at the end of the stream, returns any frames still buffered.
+    fn flush(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Plays the frames of an ADTS (.aac) file over and over, for as long as the
+/// stream runs. It stands in for an encoder, so any audio the show writes is ignored.
+pub struct AacFileLoop {
+    frames: Vec<Vec<u8>>,
+    audio_specific_config: [u8; 2],
+    sample_rate: u32,
+    channels: u8,
+    next_frame: usize,
+    samples_pending: u64,
+}
+
+impl AacFileLoop {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_adts(&fs::read(path)?)
+    }
+
+    pub fn from_adts(data: &[u8]) -> io::Result<Self> {
+        let mut first_header = None;
+        let mut frames = Vec::new();
+        for frame in flvmux::aac::adts_frames(data) {
+            let frame = frame?;
+            match first_header {
+                None => first_header = Some(frame.header),
+                Some(header)
+                    if header.audio_specific_config() != frame.header.audio_specific_config() =>
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unsupported audio, the audio format changes part way through the file",
+                    ));
+                }
+                Some(_) => {}
+            }
+
+            frames.push(frame.data.to_vec());
+        }
+
+        let header = first_header.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "audio file has no AAC frames")
+        })?;
+
+        Ok(AacFileLoop {
+            frames,
+            audio_specific_config: header.audio_specific_config(),
+            sample_rate: header.sample_rate(),
+            channels: header.channels(),
+            next_frame: 0,
+            samples_pending: 0,
+        })
+    }
+}
+
+impl AacEncoder for AacFileLoop {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    fn audio_specific_config(&self) -> Vec<u8> {
+        self.audio_specific_config.to_vec()
+    }
+
+    fn encode(&mut self, pcm: &[i16]) -> io::Result<Vec<Vec<u8>>> {
+        self.samples_pending += (pcm.len() / usize::from(self.channels)) as u64;
+
+        let mut ret = Vec::new();
+        while self.samples_pending >= AAC_FRAME_SAMPLES {
+            ret.push(self.frames[self.next_frame].clone());
+            self.next_frame = (self.next_frame + 1) % self.frames.len();
+            self.samples_pending -= AAC_FRAME_SAMPLES;
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Collects audio from a show one video frame interval at a time, and holds
+/// encoded AAC frames until the video catches up so tags stay in timestamp order.
+pub(crate) struct AudioTrack<'a> {
+    encoder: &'a mut dyn AacEncoder,
+    fps: FrameRate,
+    start_millis: i32,
+    samples_rendered: u64,
+    frames_encoded: u64,
+    pcm: Vec<i16>,
+    pending: VecDeque<(i32, Vec<u8>)>,
+}
+
+impl<'a> AudioTrack<'a> {
+    pub fn new(encoder: &'a mut dyn AacEncoder, fps: FrameRate, start_millis: i32) -> Self {
+        AudioTrack {
+            encoder,
+            fps,
+            start_millis,
+            samples_rendered: 0,
+            frames_encoded: 0,
+            pcm: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn write_sequence_header(&self, out: &mut impl Write) -> io::Result<()> {
+        flvmux::write_audio_tag(
+            out,
+            0,
+            AacAudioPacketType::SequenceHeader,
+            &self.encoder.audio_specific_config(),
+        )
+    }
+
+    /// The (interleaved) sample buffer a show should fill for the given video
+    /// frame. Sample counts are computed from the start of the stream, so
+    /// frame intervals that aren't a whole number of samples don't drift.
+    pub fn frame_buffer(&mut self, frame: usize) -> &mut [i16] {
+        let rate = u64::from(self.encoder.sample_rate());
+        let samples_through_frame =
+            (frame as u64 + 1) * rate * u64::from(self.fps.den()) / u64::from(self.fps.num());
+        let samples = samples_through_frame.saturating_sub(self.samples_rendered);
+        self.samples_rendered += samples;
+
+        self.pcm.clear();
+        self.pcm
+            .resize(samples as usize * usize::from(self.encoder.channels()), 0);
+        &mut self.pcm
+    }
+
+    /// Encodes the buffer most recently handed out by frame_buffer
+    pub fn encode_frame_buffer(&mut self) -> Result<(), StreamError> {
+        let encoded = self.encoder.encode(&self.pcm).map_err(StreamError::Audio)?;
+        self.queue(encoded)
+    }
+
+    /// Writes all the queued audio that starts at or before the given timestamp
+    pub fn write_until(&mut self, out: &mut impl Write, millis: i32) -> io::Result<()> {
+        while let Some((timestamp, _)) = self.pending.front() {
+            if *timestamp > millis {
+                break;
+            }
+
+            let (timestamp, data) = self.pending.pop_front().unwrap();
+            flvmux::write_audio_tag(out, timestamp, AacAudioPacketType::Raw, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the encoder, and writes everything that's left.
+    pub fn finish(&mut self, out: &mut impl Write) -> Result<(), StreamError> {
+        let encoded = self.encoder.flush().map_err(StreamError::Audio)?;
+        self.queue(encoded)?;
+        Ok(self.write_until(out, i32::MAX)?)
+    }
+
+    fn queue(&mut self, frames: Vec<Vec<u8>>) -> Result<(), StreamError> {
+        let rate = u64::from(self.encoder.sample_rate());
+        for data in frames {
+            let offset = self.frames_encoded * AAC_FRAME_SAMPLES * 1000 / rate;
+            let timestamp = i32::try_from(offset)
+                .ok()
+                .and_then(|offset| self.start_millis.checked_add(offset))
+                .ok_or(StreamError::TimestampOverflow)?;
+            self.pending.push_back((timestamp, data));
+            self.frames_encoded += 1;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AAC-LC, 48kHz, mono, no CRC
+    fn adts_frame(payload: u8) -> Vec<u8> {
+        vec![
+            0xff,
+            0xf1,
+            (1 << 6) | (3 << 2),
+            1 << 6,
+            1,
+            0x1f,
+            0xfc,
+            payload,
+        ]
+    }
+
+    #[test]
+    fn test_aac_file_loop() {
+        let data: Vec<u8> = (0..3).flat_map(adts_frame).collect();
+        let mut file_loop = AacFileLoop::from_adts(&data).unwrap();
+
+        assert_eq!(48000, file_loop.sample_rate());
+        assert_eq!(1, file_loop.channels());
+        assert_eq!(vec![0x11, 0x88], file_loop.audio_specific_config());
+
+        assert!(file_loop.encode(&[0; 1000]).unwrap().is_empty());
+        let frames = file_loop.encode(&[0; 4096]).unwrap();
+        assert_eq!(vec![vec![0], vec![1], vec![2], vec![0]], frames);
+        assert_eq!(vec![vec![1]], file_loop.encode(&[0; 1000]).unwrap());
+    }
+
+    #[test]
+    fn test_empty_file() {
+        assert!(AacFileLoop::from_adts(&[]).is_err());
+    }
+}
@@ -0,0 +1,488 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::{EncoderControl, Pacing};
+
+pub const DEFAULT_WIDTH: usize = 1280;
+pub const DEFAULT_HEIGHT: usize = 720;
+pub const DEFAULT_FRAME_RATE: FrameRate = FrameRate { num: 30, den: 1 };
+pub const DEFAULT_PRESET: &str = "veryfast";
+pub const DEFAULT_PROFILE: &str = "high";
+pub const DEFAULT_KEYINT: u32 = 30; // in frames
+
+/// Environment variable read by StreamConfig::from_env, like "1920x1080"
+pub const RESOLUTION_VAR: &str = "STREAM_RESOLUTION";
+/// Environment variable read by StreamConfig::from_env, in frames per second,
+/// like "30", "29.97" or "30000/1001" (see FrameRate's FromStr)
+pub const FRAME_RATE_VAR: &str = "STREAM_FPS";
+/// Environment variable read by StreamConfig::from_env, a CBR bitrate in kbit/s
+pub const BITRATE_VAR: &str = "STREAM_BITRATE";
+/// Environment variable read by StreamConfig::from_env, one of "realtime",
+/// "drop" or "duplicate" (see Pacing)
+pub const PACING_VAR: &str = "STREAM_PACING";
+
+// x264 refuses more than this many consecutive B-frames (X264_BFRAME_MAX)
+const MAX_BFRAMES: u32 = 16;
+
+/// Frames per second, as a fraction so NTSC rates are exact. 29.97 fps is
+/// really 30000/1001.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FrameRate {
+    num: u32,
+    den: u32,
+}
+
+impl FrameRate {
+    /// 29.97 fps, NTSC video
+    pub const NTSC: FrameRate = FrameRate {
+        num: 30000,
+        den: 1001,
+    };
+    /// 23.976 fps, film telecined for NTSC
+    pub const NTSC_FILM: FrameRate = FrameRate {
+        num: 24000,
+        den: 1001,
+    };
+    /// 59.94 fps
+    pub const NTSC_DOUBLE: FrameRate = FrameRate {
+        num: 60000,
+        den: 1001,
+    };
+
+    /// num / den frames per second, in lowest terms. A zero rate is caught
+    /// when the stream starts.
+    pub fn new(num: u32, den: u32) -> Self {
+        let divisor = gcd(num, den).max(1);
+        FrameRate {
+            num: num / divisor,
+            den: den / divisor,
+        }
+    }
+
+    pub fn num(&self) -> u32 {
+        self.num
+    }
+
+    pub fn den(&self) -> u32 {
+        self.den
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        f64::from(self.num) / f64::from(self.den)
+    }
+}
+
+impl From<u32> for FrameRate {
+    fn from(fps: u32) -> Self {
+        FrameRate { num: fps, den: 1 }
+    }
+}
+
+impl fmt::Display for FrameRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.den {
+            1 => write!(f, "{}", self.num),
+            den => write!(f, "{}/{}", self.num, den),
+        }
+    }
+}
+
+/// Parses whole numbers ("30"), fractions ("30000/1001") and decimals.
+/// Decimals that round an NTSC rate, like "29.97" and "23.976", mean the
+/// NTSC rate. Other decimals are taken exactly, so "12.5" is 25/2.
+impl FromStr for FrameRate {
+    type Err = io::Error;
+
+    fn from_str(rate: &str) -> io::Result<Self> {
+        let rate = rate.trim();
+        let bad_rate = || {
+            invalid_input(format!(
+                "frame rate should look like 30, 29.97 or 30000/1001, got {:?}",
+                rate
+            ))
+        };
+
+        if let Some((num, den)) = rate.split_once('/') {
+            let num = num.trim().parse().map_err(|_| bad_rate())?;
+            let den = den.trim().parse().map_err(|_| bad_rate())?;
+            return Ok(FrameRate::new(num, den));
+        }
+
+        let (whole, fraction) = rate.split_once('.').unwrap_or((rate, ""));
+        if whole.is_empty() || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(bad_rate());
+        }
+        let whole: u32 = whole.parse().map_err(|_| bad_rate())?;
+        if fraction.trim_end_matches('0').is_empty() {
+            return Ok(FrameRate::from(whole));
+        }
+
+        let scale = 10u64
+            .checked_pow(fraction.len() as u32)
+            .filter(|scale| *scale <= u64::from(u32::MAX))
+            .ok_or_else(bad_rate)?;
+        let num = u64::from(whole) * scale + fraction.parse::<u64>().map_err(|_| bad_rate())?;
+        let num = u32::try_from(num).map_err(|_| bad_rate())?;
+        let exact = FrameRate::new(num, scale as u32);
+
+        // The NTSC rate near this one, if it rounds to what was written
+        let ntsc_fps = (exact.as_f64() * 1.001).round() as u32;
+        let ntsc = FrameRate::new(ntsc_fps.saturating_mul(1000), 1001);
+        if (ntsc.as_f64() - exact.as_f64()).abs() < 0.5 / scale as f64 {
+            Ok(ntsc)
+        } else {
+            Ok(exact)
+        }
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+/// How x264 spends bits. Bitrates and buffer sizes are in kbit/s and kbit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RateControl {
+    /// Constant quality, like x264 --crf. Lower is better, 23 is x264's default.
+    Crf(f32),
+    /// Constant bitrate, padded with filler if need be. This is what most
+    /// ingest servers (Twitch, YouTube) ask for. The VBV buffer holds one
+    /// second of video. Signals the bitrate to decoders with NAL HRD, unless
+    /// the stream has an EncoderControl.
+    Cbr { bitrate: u32 },
+    /// Average bitrate, with peaks limited by the VBV max rate and buffer.
+    Vbr {
+        bitrate: u32,
+        max_bitrate: u32,
+        buffer_size: u32,
+    },
+}
+
+/// Everything about the video a show streams. Start from StreamConfig::default()
+/// (or from_env) and override what you need:
+///
+///     let config = stream::StreamConfig::default()
+///         .resolution(1920, 1080)
+///         .rate_control(stream::RateControl::Cbr { bitrate: 6000 })
+///         .keyint(60);
+///
+/// Settings are checked when the stream starts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+    width: usize,
+    height: usize,
+    fps: FrameRate,
+    pub(crate) preset: String,
+    pub(crate) tune: Option<String>,
+    pub(crate) profile: String,
+    pub(crate) rate_control: RateControl,
+    pub(crate) keyint: u32,
+    pub(crate) bframes: Option<u32>,
+    pub(crate) threads: Option<u32>,
+    pub(crate) pacing: Pacing,
+    pub(crate) control: Option<EncoderControl>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        StreamConfig {
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            fps: DEFAULT_FRAME_RATE,
+            preset: DEFAULT_PRESET.into(),
+            tune: None,
+            profile: DEFAULT_PROFILE.into(),
+            rate_control: RateControl::Crf(23.0),
+            keyint: DEFAULT_KEYINT,
+            bframes: None,
+            threads: None,
+            pacing: Pacing::Unpaced,
+            control: None,
+        }
+    }
+}
+
+impl StreamConfig {
+    /// The defaults, overridden by STREAM_RESOLUTION, STREAM_FPS,
+    /// STREAM_BITRATE and STREAM_PACING if they're set. This lets the same show binary stream
+    /// at different sizes.
+    pub fn from_env() -> io::Result<Self> {
+        let mut config = StreamConfig::default();
+
+        if let Ok(resolution) = env::var(RESOLUTION_VAR) {
+            let (width, height) = parse_resolution(&resolution)?;
+            config = config.resolution(width, height);
+        }
+
+        if let Ok(fps) = env::var(FRAME_RATE_VAR) {
+            let fps: FrameRate = fps
+                .parse()
+                .map_err(|e| invalid_input(format!("{} {}", FRAME_RATE_VAR, e)))?;
+            config = config.frame_rate(fps);
+        }
+
+        if let Ok(bitrate) = env::var(BITRATE_VAR) {
+            let bitrate = parse_number(BITRATE_VAR, &bitrate)?;
+            config = config.rate_control(RateControl::Cbr { bitrate });
+        }
+
+        if let Ok(pacing) = env::var(PACING_VAR) {
+            config = config.pacing(parse_pacing(&pacing)?);
+        }
+
+        config.check()?;
+        Ok(config)
+    }
+
+    /// In pixels, both must be even
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// In frames per second: a whole number, or a FrameRate like FrameRate::NTSC
+    pub fn frame_rate(mut self, fps: impl Into<FrameRate>) -> Self {
+        self.fps = fps.into();
+        self
+    }
+
+    /// An x264 preset, "ultrafast" through "placebo"
+    pub fn preset(mut self, preset: &str) -> Self {
+        self.preset = preset.into();
+        self
+    }
+
+    /// An x264 tuning, like "zerolatency" or "animation"
+    pub fn tune(mut self, tune: &str) -> Self {
+        self.tune = Some(tune.into());
+        self
+    }
+
+    /// An H.264 profile, like "baseline", "main" or "high"
+    pub fn profile(mut self, profile: &str) -> Self {
+        self.profile = profile.into();
+        self
+    }
+
+    pub fn rate_control(mut self, rate_control: RateControl) -> Self {
+        self.rate_control = rate_control;
+        self
+    }
+
+    /// Maximum frames between keyframes. Most services want a keyframe every
+    /// two seconds, so twice the frame rate.
+    pub fn keyint(mut self, frames: u32) -> Self {
+        self.keyint = frames;
+        self
+    }
+
+    /// Maximum consecutive B-frames. Left alone, the preset and tuning decide.
+    pub fn bframes(mut self, bframes: u32) -> Self {
+        self.bframes = Some(bframes);
+        self
+    }
+
+    /// Encoder threads. Left alone, x264 picks based on the number of cores.
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Unpaced unless set. Use real time pacing when writing to anything
+    /// that doesn't pace the stream itself, like an RTMP sink.
+    pub fn pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Lets whoever holds a clone of control change the bitrate, or ask for
+    /// a keyframe, while the stream runs. CBR streams with a control go
+    /// without NAL HRD signalling, so x264 can change their bitrate.
+    pub fn control(mut self, control: EncoderControl) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// In pixels
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// In pixels
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// In frames per second
+    pub fn fps(&self) -> FrameRate {
+        self.fps
+    }
+
+    /// Width of the U and V planes. Pictures are 4:2:0, so chroma is half size.
+    pub fn chroma_width(&self) -> usize {
+        self.width >> 1
+    }
+
+    /// Height of the U and V planes.
+    pub fn chroma_height(&self) -> usize {
+        self.height >> 1
+    }
+
+    /// Catches the mistakes we can spot without asking x264. Presets, tunings
+    /// and profiles are checked by x264 itself when the stream starts.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.width == 0 || self.height == 0 || self.width & 1 != 0 || self.height & 1 != 0 {
+            return Err(invalid_input(format!(
+                "resolution must be even and non-zero for 4:2:0 video, got {}x{}",
+                self.width, self.height
+            )));
+        }
+
+        if self.fps.num == 0 || self.fps.den == 0 {
+            return Err(invalid_input(format!(
+                "frame rate must be more than zero frames per second, got {}",
+                self.fps
+            )));
+        }
+
+        if self.keyint == 0 {
+            return Err(invalid_input("keyint must be at least one frame"));
+        }
+
+        if let Some(bframes) = self.bframes {
+            if bframes > MAX_BFRAMES {
+                return Err(invalid_input(format!(
+                    "x264 allows at most {} B-frames, got {}",
+                    MAX_BFRAMES, bframes
+                )));
+            }
+
+            if bframes > 0 && self.profile == "baseline" {
+                return Err(invalid_input("baseline profile doesn't support B-frames"));
+            }
+        }
+
+        match self.rate_control {
+            RateControl::Crf(crf) if !(0.0..=51.0).contains(&crf) => Err(invalid_input(format!(
+                "CRF must be between 0 and 51, got {}",
+                crf
+            ))),
+            RateControl::Cbr { bitrate: 0 } | RateControl::Vbr { bitrate: 0, .. } => {
+                Err(invalid_input("bitrate must be at least 1 kbit/s"))
+            }
+            RateControl::Vbr {
+                bitrate,
+                max_bitrate,
+                buffer_size,
+            } if max_bitrate < bitrate || buffer_size == 0 => Err(invalid_input(format!(
+                "VBV needs a max bitrate of at least {} kbit/s and a non-zero buffer",
+                bitrate
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn invalid_input(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.into())
+}
+
+fn parse_number(var: &str, value: &str) -> io::Result<u32> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| invalid_input(format!("{} must be a whole number, got {:?}", var, value)))
+}
+
+fn parse_pacing(pacing: &str) -> io::Result<Pacing> {
+    match pacing.trim() {
+        "none" => Ok(Pacing::Unpaced),
+        "realtime" => Ok(Pacing::RealTime),
+        "drop" => Ok(Pacing::DropLateFrames),
+        "duplicate" => Ok(Pacing::DuplicateLateFrames),
+        _ => Err(invalid_input(format!(
+            "{} should be none, realtime, drop or duplicate, got {:?}",
+            PACING_VAR, pacing
+        ))),
+    }
+}
+
+fn parse_resolution(resolution: &str) -> io::Result<(usize, usize)> {
+    let parsed = resolution
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)));
+
+    parsed.ok_or_else(|| {
+        invalid_input(format!(
+            "{} should look like 1280x720, got {:?}",
+            RESOLUTION_VAR, resolution
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolution() {
+        assert_eq!((1920, 1080), parse_resolution("1920x1080").unwrap());
+        assert!(parse_resolution("1920").is_err());
+        assert!(parse_resolution("axb").is_err());
+    }
+
+    #[test]
+    fn test_parse_frame_rate() {
+        let parse = |rate: &str| rate.parse::<FrameRate>().unwrap();
+        assert_eq!(FrameRate::from(30), parse("30"));
+        assert_eq!(FrameRate::from(25), parse("25.00"));
+        assert_eq!(FrameRate::NTSC, parse("30000/1001"));
+        assert_eq!(FrameRate::NTSC, parse("29.97"));
+        assert_eq!(FrameRate::NTSC_FILM, parse("23.976"));
+        assert_eq!(FrameRate::NTSC_DOUBLE, parse("59.94"));
+        assert_eq!(FrameRate::new(25, 2), parse("12.5"));
+        assert_eq!(FrameRate::from(30), parse("60/2"));
+
+        assert!("".parse::<FrameRate>().is_err());
+        assert!("fast".parse::<FrameRate>().is_err());
+        assert!("29.97.1".parse::<FrameRate>().is_err());
+        assert!("-30".parse::<FrameRate>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(StreamConfig::default().check().is_ok());
+
+        let odd = StreamConfig::default().resolution(641, 480);
+        assert!(odd.check().is_err());
+
+        let stopped = StreamConfig::default().frame_rate(0);
+        assert!(stopped.check().is_err());
+        let ntsc = StreamConfig::default().frame_rate(FrameRate::NTSC);
+        assert!(ntsc.check().is_ok());
+
+        let no_keyframes = StreamConfig::default().keyint(0);
+        assert!(no_keyframes.check().is_err());
+
+        let baseline = StreamConfig::default().profile("baseline");
+        assert!(baseline.clone().bframes(0).check().is_ok());
+        assert!(baseline.bframes(2).check().is_err());
+
+        let bad_crf = StreamConfig::default().rate_control(RateControl::Crf(60.0));
+        assert!(bad_crf.check().is_err());
+
+        let bad_vbv = StreamConfig::default().rate_control(RateControl::Vbr {
+            bitrate: 3000,
+            max_bitrate: 2000,
+            buffer_size: 3000,
+        });
+        assert!(bad_vbv.check().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::RateControl;
+
+// x264 keeps bitrates in a C int
+const MAX_BITRATE: u32 = i32::MAX as u32;
+
+/// Changes a running stream's encoder from elsewhere: the show, another
+/// thread, or whatever an operator pokes. Give a clone to
+/// StreamConfig::control before starting the stream. Requests take effect
+/// from the next frame encoded.
+#[derive(Clone, Default)]
+pub struct EncoderControl {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Requests,
+    // The running stream's, once it starts
+    rate_control: Option<RateControl>,
+    // Why x264 turned down the last bitrate change
+    rejected: Option<io::Error>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Requests {
+    pub bitrate: Option<u32>,
+    pub keyframe: bool,
+}
+
+impl EncoderControl {
+    pub fn new() -> Self {
+        EncoderControl::default()
+    }
+
+    /// In kbit/s. Only a running CBR stream's bitrate can change; x264
+    /// can't change a VBR stream's average, and constant quality streams
+    /// have none. Returns InvalidInput for those, for bitrates x264 can't
+    /// hold, and before the stream starts. The latest request wins if
+    /// several come in between frames. x264 can still refuse the change, see
+    /// take_rejected.
+    pub fn set_bitrate(&self, bitrate: u32) -> io::Result<()> {
+        let mut state = self.lock();
+        match state.rate_control {
+            _ if bitrate == 0 || bitrate > MAX_BITRATE => Err(invalid_input(&format!(
+                "bitrate must be between 1 and {} kbit/s, got {}",
+                MAX_BITRATE, bitrate
+            ))),
+            Some(RateControl::Cbr { .. }) => {
+                state.requests.bitrate = Some(bitrate);
+                Ok(())
+            }
+            Some(_) => Err(invalid_input("only CBR streams can change bitrate")),
+            None => Err(invalid_input("stream hasn't started")),
+        }
+    }
+
+    /// Makes the next frame an IDR frame, one a decoder (or join_stream's
+    /// mixer) can start from.
+    pub fn request_keyframe(&self) {
+        self.lock().requests.keyframe = true;
+    }
+
+    /// Why x264 refused the last bitrate change, if it did since this was
+    /// last called. The stream carries on at the bitrate it had.
+    pub fn take_rejected(&self) -> Option<io::Error> {
+        self.lock().rejected.take()
+    }
+
+    // Called as the stream starts, with how it spends bits
+    pub(crate) fn attach(&self, rate_control: RateControl) {
+        let mut state = self.lock();
+        state.requests = Requests::default();
+        state.rate_control = Some(rate_control);
+        state.rejected = None;
+    }
+
+    // Called when the encoder won't take a requested bitrate
+    pub(crate) fn reject(&self, e: io::Error) {
+        self.lock().rejected = Some(e);
+    }
+
+    // Everything requested since the last call
+    pub(crate) fn take(&self) -> Requests {
+        mem::take(&mut self.lock().requests)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // State is plain values, so a panic elsewhere can't leave it half
+        // changed.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for EncoderControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EncoderControl")
+            .field(&self.lock().requests)
+            .finish()
+    }
+}
+
+/// Clones of the same control are equal.
+impl PartialEq for EncoderControl {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_requests() {
+        let control = EncoderControl::new();
+        let other = control.clone();
+        assert_eq!(control, other);
+        assert_ne!(control, EncoderControl::new());
+
+        assert!(other.set_bitrate(1000).is_err());
+        control.attach(RateControl::Cbr { bitrate: 2000 });
+        other.set_bitrate(1000).unwrap();
+        other.set_bitrate(800).unwrap();
+        assert!(other.set_bitrate(0).is_err());
+        let too_big = other.set_bitrate(u32::MAX).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, too_big.kind());
+        other.request_keyframe();
+        assert_eq!(
+            Requests {
+                bitrate: Some(800),
+                keyframe: true
+            },
+            control.take()
+        );
+        assert_eq!(Requests::default(), control.take());
+
+        assert!(other.take_rejected().is_none());
+        control.reject(invalid_input("no"));
+        assert!(other.take_rejected().is_some());
+        assert!(other.take_rejected().is_none());
+
+        control.attach(RateControl::Crf(23.0));
+        assert!(control.set_bitrate(1000).is_err());
+        let vbr = RateControl::Vbr {
+            bitrate: 2000,
+            max_bitrate: 3000,
+            buffer_size: 3000,
+        };
+        control.attach(vbr);
+        assert!(control.set_bitrate(1000).is_err());
+    }
+}
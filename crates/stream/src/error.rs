@@ -0,0 +1,67 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Why a stream stopped.
+#[derive(Debug)]
+pub enum StreamError {
+    /// The StreamConfig isn't something x264 can encode. Nothing was written.
+    Config(io::Error),
+    /// x264 couldn't allocate a picture.
+    Allocation,
+    /// x264 failed, or produced output we couldn't make sense of.
+    Encoder(String),
+    /// The show's AacEncoder failed.
+    Audio(io::Error),
+    /// The stream ran long enough that its millisecond timestamps overflowed.
+    TimestampOverflow,
+    /// Writing the stream failed. A BrokenPipe, ConnectionReset or
+    /// ConnectionAborted here usually means whoever was reading the stream
+    /// went away.
+    Output(io::Error),
+}
+
+impl StreamError {
+    /// True if the stream stopped because the reader went away, rather than
+    /// because anything went wrong on our end.
+    pub fn is_disconnect(&self) -> bool {
+        match self {
+            StreamError::Output(e) => matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Config(e) => write!(f, "invalid stream config: {}", e),
+            StreamError::Allocation => write!(f, "x264 couldn't allocate a picture"),
+            StreamError::Encoder(message) => write!(f, "video encoder failed: {}", message),
+            StreamError::Audio(e) => write!(f, "audio encoder failed: {}", e),
+            StreamError::TimestampOverflow => write!(f, "stream timestamps overflowed"),
+            StreamError::Output(e) => write!(f, "can't write stream: {}", e),
+        }
+    }
+}
+
+impl Error for StreamError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StreamError::Config(e) | StreamError::Audio(e) | StreamError::Output(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Most io::Errors come from writing tags, so ? treats them as output errors.
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> Self {
+        StreamError::Output(e)
+    }
+}
@@ -1,84 +1,162 @@
 use std::cmp;
-use std::convert::TryFrom;
-use std::ffi::CString;
-use std::io;
-use std::mem;
-use std::os::raw;
-use std::ptr;
-use std::slice;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use flvmux::{AvcPacketType, Metadata};
+
+use x264::{Encoder, FrameType, Param, Picture};
+
+mod audio;
+mod config;
+mod control;
+mod error;
+mod pacing;
+mod rtmp;
+mod sink;
+mod timebase;
+
+pub use audio::{AacEncoder, AacFileLoop, AAC_FRAME_SAMPLES};
+pub use config::{
+    FrameRate, RateControl, StreamConfig, BITRATE_VAR, DEFAULT_FRAME_RATE, DEFAULT_HEIGHT,
+    DEFAULT_KEYINT, DEFAULT_PRESET, DEFAULT_PROFILE, DEFAULT_WIDTH, FRAME_RATE_VAR, PACING_VAR,
+    RESOLUTION_VAR,
+};
+pub use control::EncoderControl;
+pub use error::StreamError;
+pub use pacing::{Pacer, Pacing};
+pub use rtmp::{RtmpPublisher, RtmpUrl, DEFAULT_RTMP_PORT};
+#[cfg(feature = "tokio")]
+pub use sink::{async_sink, AsyncSink};
+pub use sink::{Sink, OUTPUT_VAR};
+
+use audio::AudioTrack;
+use timebase::Timebase;
+
+/// How a stream went, returned once it ends. Only paced streams fall behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Frames dropped (Pacing::DropLateFrames) or duplicated
+    /// (Pacing::DuplicateLateFrames) because the show was too slow
+    pub frames_skipped: u64,
+    /// The furthest behind real time a frame was written
+    pub max_lag: Duration,
+}
 
-use flvmux::AvcPacketType;
+/// What a show can tell the encoder about a frame it just drew.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameMark {
+    /// Nothing special. x264 still spots most scene changes itself.
+    #[default]
+    Unmarked,
+    /// The picture has nothing to do with the last one. Starts a new GOP
+    /// with a keyframe, so the cut is clean and the stream seekable there.
+    SceneCut,
+    /// An IDR frame, whatever x264 would have chosen.
+    Idr,
+}
 
-use libx264_sys::*;
+impl FrameMark {
+    fn frame_type(self) -> FrameType {
+        match self {
+            FrameMark::Unmarked => FrameType::Auto,
+            // An IDR frame, unless x264 is set up for open GOPs
+            FrameMark::SceneCut => FrameType::Keyframe,
+            FrameMark::Idr => FrameType::Idr,
+        }
+    }
+}
 
 pub trait Show {
     fn frame(self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self;
+
+    /// Called after each frame is drawn, to mark it as a scene cut or an
+    /// IDR frame.
+    fn mark(&self, _frame: usize) -> FrameMark {
+        FrameMark::Unmarked
+    }
+
+    /// Called after each frame when streaming with audio. samples is interleaved
+    /// 16 bit PCM covering the frame's interval, and starts out silent.
+    fn audio(self, _frame: usize, _samples: &mut [i16]) -> Self
+    where
+        Self: Sized,
+    {
+        self
+    }
 }
 
-pub const WIDTH: usize = 1280;
-pub const HEIGHT: usize = 720;
-pub const DEFAULT_FRAME_RATE: u32 = 30; // in fps
-
-fn stream_params(fps: u32) -> x264_param_t {
-    let mut param: mem::MaybeUninit<x264_param_t> = mem::MaybeUninit::uninit();
-    let veryfast = CString::new("veryfast").unwrap();
-    let mut param = match unsafe {
-        x264_param_default_preset(
-            param.as_mut_ptr(),
-            veryfast.as_ptr() as *const i8,
-            ptr::null(),
-        )
-    } {
-        0 => unsafe { param.assume_init() },
-        _ => unreachable!(),
-    };
+fn stream_params(config: &StreamConfig) -> io::Result<Param> {
+    config.check()?;
 
-    param.i_fps_num = fps;
-    param.i_fps_den = 1;
-    param.i_keyint_max = 30;
-    param.i_keyint_min = 0;
-    param.i_height = HEIGHT as i32;
-    param.i_width = WIDTH as i32;
+    let mut param = Param::preset(&config.preset, config.tune.as_deref())?;
+    param.set_fps(config.fps().num(), config.fps().den());
 
-    let high = CString::new("high").unwrap();
+    // x264 takes timestamps in its timebase, whatever the frame rate says.
+    let timebase = Timebase::per_frame(config.fps());
+    param.set_timebase(timebase.num, timebase.den);
 
-    match unsafe { x264_param_apply_profile(&mut param, high.as_ptr() as *const i8) } {
-        0 => param,
-        _ => unreachable!(),
-    }
-}
+    param.set_keyint_max(config.keyint)?;
+    param.set_keyint_min(0)?;
+    param.set_resolution(config.width() as u32, config.height() as u32)?;
 
-struct Picture {
-    picture: x264_picture_t,
-}
+    if let Some(bframes) = config.bframes {
+        param.set_bframes(bframes)?;
+    }
 
-impl Picture {
-    fn new(param: &x264_param_t) -> Self {
-        let mut picture: mem::MaybeUninit<x264_picture_t> = mem::MaybeUninit::uninit();
-        let picture = match unsafe {
-            x264_picture_alloc(
-                picture.as_mut_ptr(),
-                param.i_csp,
-                param.i_width,
-                param.i_height,
-            )
-        } {
-            0 => unsafe { picture.assume_init() },
-            _ => panic!("allocation failure"),
-        };
+    if let Some(threads) = config.threads {
+        param.set_threads(threads)?;
+    }
 
-        Picture { picture }
+    match config.rate_control {
+        RateControl::Crf(crf) => param.set_crf(crf),
+        RateControl::Cbr { bitrate } => {
+            param.set_cbr(bitrate)?;
+            // x264 won't change the bitrate of a stream that signals it
+            param.set_nal_hrd(config.control.is_none());
+        }
+        RateControl::Vbr {
+            bitrate,
+            max_bitrate,
+            buffer_size,
+        } => param.set_vbr(bitrate, max_bitrate, buffer_size)?,
     }
+
+    // Profiles are applied last, since they restrict the settings above.
+    param.apply_profile(&config.profile)?;
+    Ok(param)
 }
 
-impl Drop for Picture {
-    fn drop(&mut self) {
-        unsafe { x264_picture_clean(&mut self.picture as *mut x264_picture_t) }
+fn stream_metadata(
+    param: &Param,
+    duration: Option<usize>,
+    audio: Option<&dyn AacEncoder>,
+) -> Metadata {
+    let (fps_num, fps_den) = param.fps();
+    let framerate = FrameRate::new(fps_num, fps_den);
+
+    // x264 rate control bitrates are in kbit/s.
+    let videodatarate = param.bitrate().map(f64::from);
+
+    let mut metadata = Metadata {
+        duration: duration
+            .map(|frames| frames as f64 * f64::from(framerate.den()) / f64::from(framerate.num())),
+        width: Some(f64::from(param.width())),
+        height: Some(f64::from(param.height())),
+        framerate: Some(framerate.as_f64()),
+        videocodecid: Some(flvmux::AVC_CODEC_ID),
+        videodatarate,
+        encoder: Some("forever-video libx264".into()),
+        ..Metadata::default()
+    };
+
+    if let Some(audio) = audio {
+        metadata.audiocodecid = Some(flvmux::AAC_CODEC_ID);
+        metadata.audiosamplerate = Some(f64::from(audio.sample_rate()));
+        metadata.audiosamplesize = Some(16.0);
+        metadata.stereo = Some(audio.channels() > 1);
     }
-}
 
-struct Encoder {
-    encoder: *mut x264_t,
+    metadata
 }
 
 struct Encoded {
@@ -89,190 +167,790 @@ struct Encoded {
 }
 
 impl Encoded {
-    fn decode_time_millis(&self) -> i32 {
-        i32::try_from(self.decode_ts / 90).unwrap()
+    fn new(frame: &x264::Frame) -> Result<Self, StreamError> {
+        // x264 hands us Annex-B start codes, FLV wants length prefixes.
+        let data =
+            flvmux::avc::annexb_to_avcc(&frame.nals().annex_b()).map_err(encoder_output_error)?;
+
+        Ok(Encoded {
+            data,
+            seekable: frame.is_idr(),
+            presentation_ts: frame.pts,
+            decode_ts: frame.dts,
+        })
     }
 
-    fn composition_offset_millis(&self) -> i32 {
-        i32::try_from((self.presentation_ts - self.decode_ts) / 90).unwrap()
+    fn decode_time_millis(&self, timebase: Timebase) -> Result<i32, StreamError> {
+        timebase.to_millis(self.decode_ts)
+    }
+
+    fn packet_type(&self, timebase: Timebase) -> Result<AvcPacketType, StreamError> {
+        Ok(AvcPacketType::Nalu {
+            composition_offset_millis: timebase
+                .composition_offset_millis(self.presentation_ts, self.decode_ts)?,
+            seekable: self.seekable,
+        })
     }
 }
 
-impl Encoder {
-    fn new(param: &mut x264_param_t) -> Self {
-        // libx264 defines "x264_encode_open" as a macro, that expands to
-        // another function name that knows the build version. If you change
-        // the version of the lib to (say) 999, you'll need to change the line
-        // below to x264_encoder_open_999
-        let encoder = unsafe { x264_encoder_open_155(param as *mut x264_param_t) };
+// Encodes the picture, or with None, drains a delayed frame. Any frame type
+// forced on the picture only applies to this encode.
+fn encode_picture(
+    encoder: &mut Encoder,
+    picture: Option<&mut Picture>,
+) -> Result<Option<Encoded>, StreamError> {
+    let frame = match picture {
+        Some(picture) => {
+            let frame = encoder.encode(picture);
+            picture.set_frame_type(FrameType::Auto);
+            frame
+        }
+        None => encoder.flush(),
+    }
+    .map_err(|e| StreamError::Encoder(e.to_string()))?;
 
-        if encoder.is_null() {
-            panic!("allocation failure");
+    frame.map(|frame| Encoded::new(&frame)).transpose()
+}
+
+// Applies whatever's been asked of the stream's EncoderControl since the
+// last picture, before encoding the next one.
+fn apply_control(config: &StreamConfig, encoder: &mut Encoder, picture: &mut Picture) {
+    let control = match &config.control {
+        Some(control) => control,
+        None => return,
+    };
+    let requests = control.take();
+
+    // Overrides however the show marked the frame
+    if requests.keyframe {
+        picture.set_frame_type(FrameType::Idr);
+    }
+
+    // EncoderControl only takes bitrates for CBR streams. If x264 won't
+    // change, the stream carries on as it was.
+    if let Some(bitrate) = requests.bitrate {
+        let mut param = encoder.parameters();
+        let changed = param
+            .set_cbr(bitrate)
+            .and_then(|()| encoder.reconfigure(&param));
+        if let Err(e) = changed {
+            control.reject(e);
         }
+    }
+}
 
-        Encoder { encoder }
+fn encoder_output_error(e: io::Error) -> StreamError {
+    StreamError::Encoder(format!("unexpected output from x264, {}", e))
+}
+
+/// duration is in number of frames. Shows should be built to draw at the
+/// configured resolution. Returns StreamError::Config, before writing
+/// anything, if the config isn't something x264 can encode, and how well a
+/// paced stream kept up once it ends.
+pub fn stream(
+    show: impl Show,
+    duration: Option<usize>,
+    config: &StreamConfig,
+) -> Result<StreamStats, StreamError> {
+    stream_to(show, duration, config, Sink::stdout())
+}
+
+/// Like stream, but also asks the show for audio (see Show::audio) and
+/// encodes it with the given encoder.
+pub fn stream_with_audio(
+    show: impl Show,
+    duration: Option<usize>,
+    config: &StreamConfig,
+    audio: &mut dyn AacEncoder,
+) -> Result<StreamStats, StreamError> {
+    stream_with_audio_to(show, duration, config, audio, Sink::stdout())
+}
+
+/// Like stream, but writes to out (a Sink, or any other Write) rather than
+/// standard output. Writes are buffered, and flushed after every frame.
+pub fn stream_to(
+    show: impl Show,
+    duration: Option<usize>,
+    config: &StreamConfig,
+    out: impl Write,
+) -> Result<StreamStats, StreamError> {
+    stream_flv(show, duration, config, None, io::BufWriter::new(out))
+}
+
+/// Like stream_with_audio, but writes to out rather than standard output.
+pub fn stream_with_audio_to(
+    show: impl Show,
+    duration: Option<usize>,
+    config: &StreamConfig,
+    audio: &mut dyn AacEncoder,
+    out: impl Write,
+) -> Result<StreamStats, StreamError> {
+    stream_flv(show, duration, config, Some(audio), io::BufWriter::new(out))
+}
+
+fn stream_flv(
+    show: impl Show,
+    duration: Option<usize>,
+    config: &StreamConfig,
+    audio: Option<&mut dyn AacEncoder>,
+    mut out: impl Write,
+) -> Result<StreamStats, StreamError> {
+    let framerate = config.fps();
+    let timebase = Timebase::per_frame(framerate);
+    let param = stream_params(config).map_err(StreamError::Config)?;
+    // x264 also checks settings here, and logs why it refused them.
+    let mut encoder = Encoder::open(&param).map_err(StreamError::Config)?;
+    let mut picture = Picture::new(&param).map_err(|_| StreamError::Allocation)?;
+    if let Some(control) = &config.control {
+        control.attach(config.rate_control);
     }
+    let mut show = show;
+
+    flvmux::write_flv_header(&mut out)?;
+    let metadata = stream_metadata(&param, duration, audio.as_deref());
+    flvmux::write_metadata(&mut out, &metadata)?;
+
+    let h264_headers = encoder
+        .headers()
+        .map_err(|e| StreamError::Encoder(e.to_string()))?
+        .annex_b();
+    let avc_config = flvmux::avc::decoder_configuration_record_from_annexb(&h264_headers)
+        .map_err(encoder_output_error)?;
+    flvmux::write_video_tag(&mut out, 0, AvcPacketType::SequenceHeader, &avc_config)?;
+
+    // With B-frames, x264 decodes frames ahead of presenting them. FLV (and
+    // RTMP) timestamps can't be negative, so present the first frame late
+    // enough that it decodes at zero. Timestamps tick once per frame.
+    let first_pts = encoder.bframe_delay();
+    picture.set_pts(first_pts - 1);
+
+    // Audio starts along with the first frame.
+    let audio_start_millis = timebase.to_millis(first_pts)?;
+    let mut audio = audio.map(|encoder| AudioTrack::new(encoder, framerate, audio_start_millis));
+    if let Some(track) = &audio {
+        track.write_sequence_header(&mut out)?;
+    }
+
+    let mut pacer = Pacer::new(config.pacing, framerate);
+    let mut frame = 0usize;
+    while duration.is_none() || duration.unwrap() > frame {
+        let late = pacer.frames_late(frame);
+        let late = duration.map_or(late, |d| cmp::min(late, d - frame));
+        if late > 0 {
+            pacer.skipped(late);
+            if config.pacing == Pacing::DuplicateLateFrames {
+                // The picture still holds the last frame the show drew
+                for late_frame in frame..frame + late {
+                    picture.set_pts(first_pts + late_frame as i64);
+                    apply_control(config, &mut encoder, &mut picture);
+                    if let Some(encoded) = encode_picture(&mut encoder, Some(&mut picture))? {
+                        write_encoded(&mut out, timebase, audio.as_mut(), &mut pacer, &encoded)?;
+                        out.flush()?;
+                    }
+                }
+            }
+
+            // The next frame's audio covers the skipped frames, too.
+            frame += late;
+            continue;
+        }
+
+        let (y_plane, u_plane, v_plane) = picture.planes_mut();
+        show = show.frame(frame, y_plane, u_plane, v_plane);
+        picture.set_pts(first_pts + frame as i64);
+        picture.set_frame_type(show.mark(frame).frame_type());
+
+        if let Some(track) = &mut audio {
+            show = show.audio(frame, track.frame_buffer(frame));
+            track.encode_frame_buffer()?;
+        }
+
+        apply_control(config, &mut encoder, &mut picture);
+        if let Some(encoded) = encode_picture(&mut encoder, Some(&mut picture))? {
+            write_encoded(&mut out, timebase, audio.as_mut(), &mut pacer, &encoded)?;
+            out.flush()?;
+        }
 
-    fn headers(&mut self) -> Vec<u8> {
-        let mut pp_nal: mem::MaybeUninit<*mut x264_nal_t> = mem::MaybeUninit::uninit();
-        let mut pi_nal: raw::c_int = 0;
+        frame += 1;
+    }
 
-        let ret = unsafe {
-            x264_encoder_headers(
-                self.encoder,
-                pp_nal.as_mut_ptr(),
-                &mut pi_nal as *mut raw::c_int,
-            )
+    let mut last_presentation_time = picture.pts();
+    while encoder.delayed_frames() > 0 {
+        let encoded = match encode_picture(&mut encoder, None)? {
+            Some(encoded) => encoded,
+            None => break,
         };
 
-        if ret < 0 {
-            panic!("can't produce encoder headers");
+        last_presentation_time = cmp::max(encoded.presentation_ts, last_presentation_time);
+        write_encoded(&mut out, timebase, audio.as_mut(), &mut pacer, &encoded)?;
+    }
+
+    if let Some(track) = &mut audio {
+        track.finish(&mut out)?;
+    }
+
+    // last_presentation_time and seekable here are best guesses.
+    let last_time_millis = timebase.to_millis(last_presentation_time)?;
+    flvmux::write_video_tag(&mut out, last_time_millis, AvcPacketType::SequenceEnd, &[])?;
+
+    out.flush()?;
+    let (frames_skipped, max_lag) = pacer.stats();
+    Ok(StreamStats {
+        frames_skipped,
+        max_lag,
+    })
+}
+
+// Writes a video frame, after any audio that should come before it, once
+// it's due.
+fn write_encoded(
+    out: &mut impl Write,
+    timebase: Timebase,
+    audio: Option<&mut AudioTrack>,
+    pacer: &mut Pacer,
+    encoded: &Encoded,
+) -> Result<(), StreamError> {
+    let decode_time_millis = encoded.decode_time_millis(timebase)?;
+    pacer.wait_until(decode_time_millis);
+    if let Some(track) = audio {
+        track.write_until(out, decode_time_millis)?;
+    }
+
+    flvmux::write_video_tag(
+        out,
+        decode_time_millis,
+        encoded.packet_type(timebase)?,
+        &encoded.data,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flvmux::avc;
+    use flvmux::{AacAudioPacketType, FlvReader, FlvTag, TagKind};
+    use std::cell::Cell;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    struct GradientShow {}
+
+    impl Show for GradientShow {
+        fn frame(self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self {
+            for (ix, px) in y.iter_mut().enumerate() {
+                *px = ((ix + frame * 8) % 256) as u8;
+            }
+            for px in u.iter_mut().chain(v.iter_mut()) {
+                *px = 128;
+            }
+
+            self
         }
+    }
+
+    // Small, to keep the tests quick
+    fn test_config() -> StreamConfig {
+        StreamConfig::default().resolution(320, 240)
+    }
+
+    fn stream_tags(frames: usize) -> Vec<FlvTag> {
+        let mut out = Vec::new();
+        stream_flv(
+            GradientShow {},
+            Some(frames),
+            &test_config(),
+            None,
+            &mut out,
+        )
+        .unwrap();
 
-        let pp_nal = unsafe { pp_nal.assume_init() };
-        let mut data = Vec::new();
-        for i in 0..pi_nal {
-            let nal = unsafe { Box::from_raw(pp_nal.offset(i as isize)) };
-            let payload = unsafe { slice::from_raw_parts(nal.p_payload, nal.i_payload as usize) };
-            data.extend_from_slice(payload);
+        FlvReader::new(&out[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>()
+            .unwrap()
+    }
 
-            mem::forget(nal);
+    #[test]
+    fn test_stream_is_avcc() {
+        let tags = stream_tags(10);
+
+        assert_eq!(TagKind::ScriptData, tags[0].kind);
+        let metadata = flvmux::read_metadata(&tags[0].payload).unwrap().unwrap();
+        assert_eq!(Some(320.0), metadata.width);
+        assert_eq!(Some(240.0), metadata.height);
+
+        assert_eq!(TagKind::Video(AvcPacketType::SequenceHeader), tags[1].kind);
+        let config = &tags[1].payload;
+        assert_eq!(1, config[0]); // configurationVersion
+        assert_eq!(0xff, config[4]); // four byte NAL lengths
+
+        let nalu_tags: Vec<&FlvTag> = tags
+            .iter()
+            .filter(|t| matches!(t.kind, TagKind::Video(AvcPacketType::Nalu { .. })))
+            .collect();
+        assert_eq!(10, nalu_tags.len());
+
+        for tag in &nalu_tags {
+            let nals = avc::avcc_nal_units(&tag.payload).unwrap();
+            assert!(!nals.is_empty());
+            for nal in nals {
+                // Start codes would show up as zero length or forbidden NAL units
+                assert!(!nal.is_empty());
+                assert_eq!(0, nal[0] & 0x80);
+            }
         }
 
-        data
+        let first = &nalu_tags[0];
+        assert!(matches!(
+            first.kind,
+            TagKind::Video(AvcPacketType::Nalu { seekable: true, .. })
+        ));
+        assert!(avc::avcc_nal_units(&first.payload)
+            .unwrap()
+            .iter()
+            .any(|nal| avc::nal_unit_type(nal) == Some(avc::NAL_SLICE_IDR)));
+
+        // Decode timestamps start at zero, instead of before the stream does
+        assert_eq!(0, first.timestamp);
+        assert!(nalu_tags.iter().all(|t| t.timestamp >= 0));
+
+        assert_eq!(
+            TagKind::Video(AvcPacketType::SequenceEnd),
+            tags.last().unwrap().kind
+        );
     }
 
-    fn encode_picture(&mut self, pic_in: Option<&mut x264_picture_t>) -> Option<Encoded> {
-        let mut pic_out: mem::MaybeUninit<x264_picture_t> = mem::MaybeUninit::uninit();
-        let mut pp_nal: mem::MaybeUninit<*mut x264_nal_t> = mem::MaybeUninit::uninit();
-        let mut pi_nal: raw::c_int = 0;
+    #[test]
+    fn test_stream_timestamps() {
+        let rates = [
+            FrameRate::from(24),
+            FrameRate::from(25),
+            FrameRate::NTSC,
+            FrameRate::from(50),
+            FrameRate::NTSC_DOUBLE,
+            FrameRate::from(60),
+        ];
+        for fps in rates {
+            let frames = 2 * fps.num() as usize / fps.den() as usize;
+            let mut out = Vec::new();
+            let config = test_config().frame_rate(fps).bframes(2);
+            stream_flv(GradientShow {}, Some(frames), &config, None, &mut out).unwrap();
+
+            let tags: Vec<FlvTag> = FlvReader::new(&out[..])
+                .unwrap()
+                .map(|tag| tag.unwrap())
+                .collect();
+            let metadata = flvmux::read_metadata(&tags[0].payload).unwrap().unwrap();
+            assert_eq!(Some(fps.as_f64()), metadata.framerate);
+
+            // Frames are presented every 1000 / fps milliseconds, rounded
+            // but never drifting, after however many frames B-frames delay
+            // them.
+            let mut presented: Vec<i32> = tags
+                .iter()
+                .filter_map(|tag| match tag.kind {
+                    TagKind::Video(AvcPacketType::Nalu {
+                        composition_offset_millis,
+                        ..
+                    }) => Some(tag.timestamp + composition_offset_millis),
+                    _ => None,
+                })
+                .collect();
+            presented.sort_unstable();
+            assert_eq!(frames, presented.len());
+
+            let delay = presented[0];
+            for (frame, millis) in presented.iter().enumerate() {
+                let exact = frame as f64 * 1000.0 / fps.as_f64();
+                let offset = f64::from(millis - delay);
+                assert!(
+                    (offset - exact).abs() <= 1.0,
+                    "{} fps frame {} at {} ms",
+                    fps,
+                    frame,
+                    millis
+                );
+            }
+        }
+    }
 
-        let pic_in_ptr = match pic_in {
-            Some(p) => p as *mut x264_picture_t,
-            None => ptr::null::<*const x264_picture_t>() as *mut x264_picture_t,
-        };
+    #[test]
+    fn test_stream_params() {
+        let config = test_config()
+            .rate_control(RateControl::Cbr { bitrate: 2500 })
+            .keyint(60)
+            .bframes(2);
+        let param = stream_params(&config).unwrap();
+        assert_eq!(Some(2500), param.bitrate());
+        assert_eq!(2500, param.as_raw().rc.i_vbv_max_bitrate);
+        assert_eq!(60, param.keyint_max());
+        assert_eq!(2, param.bframes());
+        assert_eq!((1, 30), param.timebase());
+
+        assert!(stream_params(&test_config().preset("warpspeed")).is_err());
+        assert!(stream_params(&test_config().tune("zerolatency")).is_ok());
+        assert!(stream_params(&test_config().profile("extreme")).is_err());
+
+        // x264 won't do lossless outside of the high 4:4:4 profile
+        let lossless = test_config().rate_control(RateControl::Crf(0.0));
+        assert!(stream_params(&lossless).is_err());
+    }
 
-        let result = unsafe {
-            x264_encoder_encode(
-                self.encoder,
-                pp_nal.as_mut_ptr(),
-                &mut pi_nal as *mut raw::c_int,
-                pic_in_ptr,
-                pic_out.as_mut_ptr(),
-            )
-        };
+    // Asks for a keyframe, and a lower bitrate, partway through
+    struct ControlledShow {
+        control: EncoderControl,
+    }
 
-        if result < 0 {
-            panic!("can't encode");
+    impl Show for ControlledShow {
+        fn frame(self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self {
+            match frame {
+                3 => self.control.set_bitrate(500).unwrap(),
+                7 => self.control.request_keyframe(),
+                _ => {}
+            }
+            GradientShow {}.frame(frame, y, u, v);
+            self
         }
+    }
 
-        if pi_nal <= 0 {
-            return None;
+    #[test]
+    fn test_stream_control() {
+        let control = EncoderControl::new();
+        let config = test_config()
+            .rate_control(RateControl::Cbr { bitrate: 1000 })
+            .control(control.clone());
+        let mut out = Vec::new();
+        stream_flv(
+            ControlledShow { control },
+            Some(15),
+            &config,
+            None,
+            &mut out,
+        )
+        .unwrap();
+
+        let seekable = presented_seekable(&out);
+        assert_eq!(15, seekable.len());
+        assert!(seekable[0] && seekable[7]);
+        assert!(!seekable[6] && !seekable[8]);
+    }
+
+    // Fresh noise every frame, so the encoder spends every bit it's given
+    struct NoisyShow {
+        control: EncoderControl,
+        seed: u32,
+    }
+
+    impl Show for NoisyShow {
+        fn frame(mut self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self {
+            if frame == 30 {
+                self.control.set_bitrate(200).unwrap();
+            }
+            for px in y.iter_mut().chain(u.iter_mut()).chain(v.iter_mut()) {
+                self.seed = self.seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *px = (self.seed >> 16) as u8;
+            }
+            self
         }
+    }
 
-        let pic_out = unsafe { pic_out.assume_init() };
-        let pp_nal = unsafe { pp_nal.assume_init() };
-        let mut data = Vec::new();
-        let mut seekable = false;
+    #[test]
+    fn test_stream_bitrate_change() {
+        let control = EncoderControl::new();
+        assert!(control.set_bitrate(200).is_err());
+
+        let config = test_config()
+            .rate_control(RateControl::Cbr { bitrate: 2000 })
+            .control(control.clone());
+        let mut out = Vec::new();
+        let show = NoisyShow {
+            control: control.clone(),
+            seed: 1,
+        };
+        stream_flv(show, Some(90), &config, None, &mut out).unwrap();
+        assert!(control.take_rejected().is_none());
+        assert!(control.set_bitrate(0).is_err());
+
+        let sizes: Vec<usize> = FlvReader::new(&out[..])
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .filter(|tag| matches!(tag.kind, TagKind::Video(AvcPacketType::Nalu { .. })))
+            .map(|tag| tag.payload.len())
+            .collect();
+        assert_eq!(90, sizes.len());
+
+        // Leaving the buffer a second to drain after the change
+        let before = sizes[10..30].iter().sum::<usize>() / 20;
+        let after = sizes[60..].iter().sum::<usize>() / 30;
+        assert!(
+            after * 2 < before,
+            "{} bytes per frame before, {} after",
+            before,
+            after
+        );
+    }
 
-        // OK, we have an array of nal units, and *some* of them might be IDR frames?
-        for i in 0..pi_nal {
-            let nal = unsafe { Box::from_raw(pp_nal.offset(i as isize)) };
+    // Whether each frame is seekable, in presentation order
+    fn presented_seekable(out: &[u8]) -> Vec<bool> {
+        let mut frames: Vec<(i32, bool)> = FlvReader::new(out)
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .filter_map(|tag| match tag.kind {
+                TagKind::Video(AvcPacketType::Nalu {
+                    composition_offset_millis,
+                    seekable,
+                }) => Some((tag.timestamp + composition_offset_millis, seekable)),
+                _ => None,
+            })
+            .collect();
+        frames.sort_unstable();
+        frames.into_iter().map(|(_, seekable)| seekable).collect()
+    }
 
-            // I *believe* that if we have any seekable nal units, we'll have ONLY
-            // the one seekable nal unit.
-            seekable = seekable || nal.i_type == nal_unit_type_e_NAL_SLICE_IDR as i32;
-            let payload = unsafe { slice::from_raw_parts(nal.p_payload, nal.i_payload as usize) };
+    // Cuts to a new scene, and asks for an IDR frame, partway through
+    struct CuttingShow {}
 
-            data.extend_from_slice(payload);
-            mem::forget(nal);
+    impl Show for CuttingShow {
+        fn frame(self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self {
+            GradientShow {}.frame(frame, y, u, v);
+            self
         }
 
-        Some(Encoded {
-            data,
-            seekable,
-            decode_ts: pic_out.i_dts,
-            presentation_ts: pic_out.i_pts,
-        })
+        fn mark(&self, frame: usize) -> FrameMark {
+            match frame {
+                5 => FrameMark::SceneCut,
+                9 => FrameMark::Idr,
+                _ => FrameMark::Unmarked,
+            }
+        }
     }
 
-    fn delayed_frames(&mut self) -> i32 {
-        unsafe { x264_encoder_delayed_frames(self.encoder) }
+    #[test]
+    fn test_stream_marks() {
+        let mut out = Vec::new();
+        stream_flv(CuttingShow {}, Some(15), &test_config(), None, &mut out).unwrap();
+
+        let seekable = presented_seekable(&out);
+        assert_eq!(15, seekable.len());
+        let cuts: Vec<usize> = (0..15).filter(|ix| seekable[*ix]).collect();
+        assert_eq!(vec![0, 5, 9], cuts);
     }
-}
 
-impl Drop for Encoder {
-    fn drop(&mut self) {
-        unsafe { x264_encoder_close(self.encoder) };
+    // Accepts a few bytes, then acts like the reader hung up
+    struct HangUp {
+        remaining: usize,
     }
-}
 
-/// duration is in number of frames
-pub fn stream(show: impl Show, duration: Option<usize>, fps: Option<u32>) {
-    let framerate = fps.unwrap_or(DEFAULT_FRAME_RATE);
-    let mut param = stream_params(framerate);
-    let mut picture = Picture::new(&param);
-    let mut encoder = Encoder::new(&mut param);
-    let mut show = show;
+    impl Write for HangUp {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "hung up"));
+            }
+            let len = cmp::min(buf.len(), self.remaining);
+            self.remaining -= len;
+            Ok(len)
+        }
 
-    // TODO blocking writes on stdout is probably the wrong thing
-    // consider a buffered writer.
-    let mut out = io::stdout();
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-    flvmux::write_flv_header(&mut out).unwrap();
+    #[test]
+    fn test_stream_errors() {
+        let mut out = Vec::new();
+        let bad_config = test_config().preset("warpspeed");
+        let err = stream_flv(GradientShow {}, Some(1), &bad_config, None, &mut out).unwrap_err();
+        assert!(matches!(err, StreamError::Config(_)));
+        assert!(out.is_empty());
+
+        let err = stream_flv(
+            GradientShow {},
+            None,
+            &test_config(),
+            None,
+            HangUp { remaining: 1000 },
+        )
+        .unwrap_err();
+        assert!(err.is_disconnect());
+    }
 
-    let h264_headers = encoder.headers();
-    flvmux::write_video_tag(&mut out, 0, AvcPacketType::SequenceHeader, &h264_headers).unwrap();
+    #[test]
+    fn test_stream_tcp_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = Sink::connect_tcp(listener.local_addr().unwrap()).unwrap();
+        // Hanging up with data still unread resets the connection
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut header = [0u8; 9];
+            conn.read_exact(&mut header).unwrap();
+        });
+
+        let err = stream_to(GradientShow {}, None, &test_config(), sink).unwrap_err();
+        server.join().unwrap();
+        assert!(err.is_disconnect(), "{}", err);
+    }
 
-    // h264 time in 90,000 ticks per second, framerate in frames / second
-    let ticks_per_frame = 90000 / i64::from(framerate);
-    let mut frame = 0usize;
-    while duration.is_none() || duration.unwrap() > frame {
-        let y_plane =
-            unsafe { slice::from_raw_parts_mut(picture.picture.img.plane[0], WIDTH * HEIGHT) };
-        let u_plane = unsafe {
-            std::slice::from_raw_parts_mut(picture.picture.img.plane[1], (WIDTH * HEIGHT) >> 2)
-        };
-        let v_plane = unsafe {
-            std::slice::from_raw_parts_mut(picture.picture.img.plane[2], (WIDTH * HEIGHT) >> 2)
+    // Takes longer than a frame to draw each frame, and counts them
+    struct SlowShow {
+        drawn: Rc<Cell<usize>>,
+    }
+
+    impl Show for SlowShow {
+        fn frame(self, frame: usize, y: &mut [u8], u: &mut [u8], v: &mut [u8]) -> Self {
+            thread::sleep(Duration::from_millis(60));
+            self.drawn.set(self.drawn.get() + 1);
+            GradientShow {}.frame(frame, y, u, v);
+            self
+        }
+    }
+
+    fn stream_slowly(pacing: Pacing) -> (usize, usize, StreamStats) {
+        let drawn = Rc::new(Cell::new(0));
+        let show = SlowShow {
+            drawn: drawn.clone(),
         };
+        let mut out = Vec::new();
+        let config = test_config().pacing(pacing);
+        let stats = stream_flv(show, Some(15), &config, None, &mut out).unwrap();
+
+        let encoded = FlvReader::new(&out[..])
+            .unwrap()
+            .map(|tag| tag.unwrap())
+            .filter(|t| matches!(t.kind, TagKind::Video(AvcPacketType::Nalu { .. })))
+            .count();
+        (drawn.get(), encoded, stats)
+    }
 
-        show = show.frame(frame, y_plane, u_plane, v_plane);
-        picture.picture.i_pts += ticks_per_frame;
-
-        if let Some(encoded) = encoder.encode_picture(Some(&mut picture.picture)) {
-            flvmux::write_video_tag(
-                &mut out,
-                encoded.decode_time_millis(),
-                AvcPacketType::Nalu {
-                    composition_offset_millis: encoded.composition_offset_millis(),
-                    seekable: encoded.seekable,
-                },
-                &encoded.data,
-            )
-            .unwrap();
+    #[test]
+    fn test_stream_pacing() {
+        let (drawn, encoded, stats) = stream_slowly(Pacing::DropLateFrames);
+        assert!(drawn < 15);
+        assert_eq!(drawn, encoded);
+        assert_eq!(15, drawn as u64 + stats.frames_skipped);
+
+        let (drawn, encoded, stats) = stream_slowly(Pacing::DuplicateLateFrames);
+        assert!(drawn < 15);
+        assert_eq!(15, encoded);
+        assert_eq!(15, drawn as u64 + stats.frames_skipped);
+
+        let started = Instant::now();
+        let (drawn, encoded, stats) = stream_slowly(Pacing::RealTime);
+        assert_eq!((15, 15), (drawn, encoded));
+        assert!(started.elapsed() >= Duration::from_millis(14 * 60));
+        // 60ms frames at 30 fps fall further behind every frame
+        assert_eq!(0, stats.frames_skipped);
+        assert!(stats.max_lag >= Duration::from_millis(200));
+    }
+
+    // Pretends each AAC frame is just the first sample of the frame, so we
+    // can see what the show wrote.
+    struct FakeAacEncoder {
+        buffered: Vec<i16>,
+    }
+
+    impl AacEncoder for FakeAacEncoder {
+        fn sample_rate(&self) -> u32 {
+            44100
         }
 
-        frame += 1;
+        fn channels(&self) -> u8 {
+            2
+        }
+
+        fn audio_specific_config(&self) -> Vec<u8> {
+            vec![0x12, 0x10]
+        }
+
+        fn encode(&mut self, pcm: &[i16]) -> io::Result<Vec<Vec<u8>>> {
+            self.buffered.extend_from_slice(pcm);
+            let frame_len = AAC_FRAME_SAMPLES as usize * 2;
+            let mut frames = Vec::new();
+            while self.buffered.len() >= frame_len {
+                let frame: Vec<i16> = self.buffered.drain(..frame_len).collect();
+                frames.push(frame[0].to_be_bytes().to_vec());
+            }
+            Ok(frames)
+        }
     }
 
-    let mut last_presentation_time = picture.picture.i_pts;
-    while encoder.delayed_frames() > 0 {
-        let encoded = encoder.encode_picture(None).unwrap();
-        last_presentation_time = cmp::max(encoded.presentation_ts, last_presentation_time);
-        flvmux::write_video_tag(
+    // Fills each frame's audio with the frame number
+    struct FrameNumberShow {}
+
+    impl Show for FrameNumberShow {
+        fn frame(self, _frame: usize, _y: &mut [u8], _u: &mut [u8], _v: &mut [u8]) -> Self {
+            self
+        }
+
+        fn audio(self, frame: usize, samples: &mut [i16]) -> Self {
+            for s in samples.iter_mut() {
+                *s = frame as i16;
+            }
+            self
+        }
+    }
+
+    #[test]
+    fn test_stream_with_audio() {
+        let mut encoder = FakeAacEncoder {
+            buffered: Vec::new(),
+        };
+        let mut out = Vec::new();
+        stream_flv(
+            FrameNumberShow {},
+            Some(30),
+            &test_config(),
+            Some(&mut encoder),
             &mut out,
-            encoded.decode_time_millis(),
-            AvcPacketType::Nalu {
-                composition_offset_millis: encoded.composition_offset_millis(),
-                seekable: encoded.seekable,
-            },
-            &encoded.data,
         )
         .unwrap();
-    }
 
-    // last_presentation_time and seekable here are best guesses.
-    let last_time_millis = i32::try_from(last_presentation_time / 90).unwrap();
-    flvmux::write_video_tag(&mut out, last_time_millis, AvcPacketType::SequenceEnd, &[]).unwrap();
+        let tags = FlvReader::new(&out[..])
+            .unwrap()
+            .collect::<io::Result<Vec<FlvTag>>>()
+            .unwrap();
+
+        let metadata = flvmux::read_metadata(&tags[0].payload).unwrap().unwrap();
+        assert_eq!(Some(flvmux::AAC_CODEC_ID), metadata.audiocodecid);
+        assert_eq!(Some(44100.0), metadata.audiosamplerate);
+
+        let audio: Vec<&FlvTag> = tags
+            .iter()
+            .filter(|t| t.kind == TagKind::Audio(AacAudioPacketType::Raw))
+            .collect();
+
+        // One second of stereo audio is 44100 samples, or 43 whole AAC frames
+        assert_eq!(43, audio.len());
+        // veryfast uses B-frame pyramids, so the first frame is presented
+        // after two frames (66.7ms) of decode delay.
+        for (ix, tag) in audio.iter().enumerate() {
+            let expected_ts = 67 + (ix as i32 * 1024 * 1000 / 44100);
+            assert_eq!(expected_ts, tag.timestamp);
+
+            // The first sample in each AAC frame came from this video frame
+            let expected_frame = (ix * 1024 * 30 / 44100) as i16;
+            assert_eq!(expected_frame.to_be_bytes().to_vec(), tag.payload);
+        }
+
+        // Tags are in timestamp order, across both tracks
+        let media_timestamps: Vec<i32> = tags[1..tags.len() - 1]
+            .iter()
+            .filter(|t| {
+                !matches!(
+                    t.kind,
+                    TagKind::Audio(AacAudioPacketType::SequenceHeader)
+                        | TagKind::Video(AvcPacketType::SequenceHeader)
+                )
+            })
+            .map(|t| t.timestamp)
+            .collect();
+        let mut sorted = media_timestamps.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, media_timestamps);
+    }
 }
@@ -0,0 +1,113 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::FrameRate;
+
+/// How fast stream functions write. Direct sinks (RTMP, TCP) need real time,
+/// since nothing downstream slows the stream down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// As fast as the show renders and x264 encodes. Right for files, and
+    /// for pipes into ffmpeg -re.
+    #[default]
+    Unpaced,
+    /// Writes each frame at its decode time, like ffmpeg -re. Shows that
+    /// can't keep up fall behind, see StreamStats::max_lag.
+    RealTime,
+    /// Real time, skipping frames the show is already too late to render.
+    /// The show sees gaps in its frame numbers, and the video frame rate drops.
+    DropLateFrames,
+    /// Real time, repeating the last picture in place of frames the show is
+    /// too late to render, so the video frame rate holds steady. The show
+    /// sees gaps in its frame numbers.
+    DuplicateLateFrames,
+}
+
+/// Keeps a stream on the wall clock. Stream time zero is the first call to
+/// wait_until or frames_late, so for shows, x264's lookahead doesn't count
+/// against the show.
+pub struct Pacer {
+    pacing: Pacing,
+    fps: FrameRate,
+    start: Option<Instant>,
+    max_lag: Duration,
+    frames_skipped: u64,
+}
+
+impl Pacer {
+    /// fps is only used to work out which frames are late.
+    pub fn new(pacing: Pacing, fps: FrameRate) -> Self {
+        Pacer {
+            pacing,
+            fps,
+            start: None,
+            max_lag: Duration::ZERO,
+            frames_skipped: 0,
+        }
+    }
+
+    /// Sleeps until the given stream time (in milliseconds) comes around, or
+    /// notes how far behind we are if it already has.
+    pub fn wait_until(&mut self, millis: i32) {
+        if self.pacing == Pacing::Unpaced {
+            return;
+        }
+
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due = start + Duration::from_millis(millis.max(0) as u64);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        } else {
+            self.max_lag = self.max_lag.max(now - due);
+        }
+    }
+
+    /// How many frames, starting with the given one, are already too late to
+    /// render. Always zero unless we're dropping or duplicating frames.
+    pub fn frames_late(&mut self, frame: usize) -> usize {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        if self.pacing != Pacing::DropLateFrames && self.pacing != Pacing::DuplicateLateFrames {
+            return 0;
+        }
+
+        // Frames are due one after another from stream time zero
+        let due = start.elapsed().as_micros() * u128::from(self.fps.num())
+            / (u128::from(self.fps.den()) * 1_000_000);
+        (due as usize).saturating_sub(frame)
+    }
+
+    /// Counts frames that were dropped or duplicated
+    pub fn skipped(&mut self, frames: usize) {
+        self.frames_skipped += frames as u64;
+    }
+
+    /// Frames dropped or duplicated so far, and the furthest behind real
+    /// time we've been.
+    pub fn stats(&self) -> (u64, Duration) {
+        (self.frames_skipped, self.max_lag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_late() {
+        let mut pacer = Pacer::new(Pacing::DropLateFrames, 30.into());
+        assert_eq!(0, pacer.frames_late(0));
+
+        pacer.start = Some(Instant::now() - Duration::from_millis(1010));
+        assert_eq!(30, pacer.frames_late(0));
+        assert_eq!(0, pacer.frames_late(40));
+
+        let mut real_time = Pacer::new(Pacing::RealTime, 30.into());
+        real_time.start = pacer.start;
+        assert_eq!(0, real_time.frames_late(0));
+
+        let mut ntsc = Pacer::new(Pacing::DuplicateLateFrames, FrameRate::NTSC);
+        ntsc.start = Some(Instant::now() - Duration::from_millis(10_020));
+        assert_eq!(300, ntsc.frames_late(0));
+    }
+}
@@ -0,0 +1,658 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use bytes::Bytes;
+use rml_amf0::Amf0Value;
+use rml_rtmp::chunk_io::{ChunkDeserializer, ChunkSerializer};
+use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
+use rml_rtmp::messages::{MessagePayload, RtmpMessage, UserControlEventType};
+use rml_rtmp::time::RtmpTimestamp;
+
+use flvmux::{FlvParser, FlvTag, MediaType};
+
+pub const DEFAULT_RTMP_PORT: u16 = 1935;
+
+const READ_BUFFER_SIZE: usize = 4096;
+const CHUNK_SIZE: u32 = 4096;
+// How long to wait for each reply while setting up the stream
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Transaction ids for the commands we send while setting up
+const CONNECT_TRANSACTION: f64 = 1.0;
+const RELEASE_STREAM_TRANSACTION: f64 = 2.0;
+const FC_PUBLISH_TRANSACTION: f64 = 3.0;
+const CREATE_STREAM_TRANSACTION: f64 = 4.0;
+const PUBLISH_TRANSACTION: f64 = 5.0;
+
+/// An RTMP ingest url, like rtmp://live.twitch.tv/app/stream_key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtmpUrl {
+    pub host: String,
+    pub port: u16,
+    pub app: String,
+    pub stream_key: String,
+}
+
+impl RtmpUrl {
+    /// Everything after the last slash is the stream key, everything
+    /// between the host and the key is the app.
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected rtmp://host[:port]/app/stream_key, got {:?}", url),
+            )
+        };
+
+        let rest = url.strip_prefix("rtmp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (app, stream_key) = path.rsplit_once('/').ok_or_else(invalid)?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, DEFAULT_RTMP_PORT),
+        };
+
+        if host.is_empty() || app.is_empty() || stream_key.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(RtmpUrl {
+            host: host.into(),
+            port,
+            app: app.into(),
+            stream_key: stream_key.into(),
+        })
+    }
+
+    // The url of the app, without the stream key. Some servers insist on it.
+    fn tc_url(&self) -> String {
+        format!("rtmp://{}:{}/{}", self.host, self.port, self.app)
+    }
+}
+
+/// Publishes an FLV stream to an RTMP server. Write FLV to it (the stream
+/// functions will, if it's in a Sink) and each tag goes out as an RTMP
+/// message. Server messages are handled after each tag goes out.
+pub struct RtmpPublisher {
+    socket: TcpStream,
+    serializer: ChunkSerializer,
+    deserializer: ChunkDeserializer,
+    inbox: VecDeque<RtmpMessage>,
+    chunk_size_sent: bool,
+    stream_id: u32,
+    bytes_received: u32,
+    bytes_since_ack: u32,
+    ack_window: Option<u32>,
+    flv: FlvParser,
+}
+
+impl RtmpPublisher {
+    /// Connects, and asks to publish a live stream. Returns once the server
+    /// has agreed.
+    pub fn connect(url: &str) -> io::Result<Self> {
+        let url = RtmpUrl::parse(url)?;
+        let mut socket = TcpStream::connect((url.host.as_str(), url.port))?;
+        socket.set_nodelay(true)?;
+        socket.set_read_timeout(Some(SETUP_TIMEOUT))?;
+        let remaining_bytes = handshake(&mut socket)?;
+
+        let mut publisher = RtmpPublisher {
+            socket,
+            serializer: ChunkSerializer::new(),
+            deserializer: ChunkDeserializer::new(),
+            inbox: VecDeque::new(),
+            chunk_size_sent: false,
+            stream_id: 0,
+            bytes_received: 0,
+            bytes_since_ack: 0,
+            ack_window: None,
+            flv: FlvParser::new(),
+        };
+
+        publisher.receive(&remaining_bytes)?;
+        publisher.start_publishing(&url)?;
+        publisher.socket.set_read_timeout(None)?;
+        Ok(publisher)
+    }
+
+    fn start_publishing(&mut self, url: &RtmpUrl) -> io::Result<()> {
+        let mut properties = HashMap::new();
+        properties.insert("app".into(), Amf0Value::Utf8String(url.app.clone()));
+        properties.insert("type".into(), Amf0Value::Utf8String("nonprivate".into()));
+        properties.insert(
+            "flashVer".into(),
+            Amf0Value::Utf8String("FMLE/3.0 (compatible; forever-video)".into()),
+        );
+        properties.insert("tcUrl".into(), Amf0Value::Utf8String(url.tc_url()));
+        self.send_command(
+            "connect",
+            CONNECT_TRANSACTION,
+            Amf0Value::Object(properties),
+            vec![],
+        )?;
+        self.wait_for_result(CONNECT_TRANSACTION)?;
+
+        // Servers that never set a chunk size still get ours.
+        if !self.chunk_size_sent {
+            self.send_chunk_size()?;
+        }
+
+        let stream_key = Amf0Value::Utf8String(url.stream_key.clone());
+        self.send_command(
+            "releaseStream",
+            RELEASE_STREAM_TRANSACTION,
+            Amf0Value::Null,
+            vec![stream_key.clone()],
+        )?;
+        self.send_command(
+            "FCPublish",
+            FC_PUBLISH_TRANSACTION,
+            Amf0Value::Null,
+            vec![stream_key.clone()],
+        )?;
+        self.send_command(
+            "createStream",
+            CREATE_STREAM_TRANSACTION,
+            Amf0Value::Null,
+            vec![],
+        )?;
+
+        let stream_id = self
+            .wait_for_result(CREATE_STREAM_TRANSACTION)?
+            .into_iter()
+            .next()
+            .and_then(Amf0Value::get_number)
+            .ok_or_else(|| rtmp_error("createStream result has no stream id"))?;
+        self.stream_id = stream_id as u32;
+
+        self.send_command(
+            "publish",
+            PUBLISH_TRANSACTION,
+            Amf0Value::Null,
+            vec![stream_key, Amf0Value::Utf8String("live".into())],
+        )?;
+
+        loop {
+            let message = self.read_message()?;
+            if let Some(status) = Status::from_message(&message) {
+                status.check()?;
+                if status.code == "NetStream.Publish.Start" {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn send_command(
+        &mut self,
+        command_name: &str,
+        transaction_id: f64,
+        command_object: Amf0Value,
+        additional_arguments: Vec<Amf0Value>,
+    ) -> io::Result<()> {
+        // Everything after createStream belongs to the new stream.
+        let stream_id = self.stream_id;
+        self.send(
+            RtmpMessage::Amf0Command {
+                command_name: command_name.into(),
+                transaction_id,
+                command_object,
+                additional_arguments,
+            },
+            stream_id,
+        )
+    }
+
+    fn send(&mut self, message: RtmpMessage, stream_id: u32) -> io::Result<()> {
+        let payload = message
+            .into_message_payload(RtmpTimestamp::new(0), stream_id)
+            .map_err(rtmp_error)?;
+        self.send_payload(&payload)
+    }
+
+    fn send_payload(&mut self, payload: &MessagePayload) -> io::Result<()> {
+        let packet = self
+            .serializer
+            .serialize(payload, false, false)
+            .map_err(rtmp_error)?;
+        self.socket.write_all(&packet.bytes)
+    }
+
+    fn send_chunk_size(&mut self) -> io::Result<()> {
+        let packet = self
+            .serializer
+            .set_max_chunk_size(CHUNK_SIZE, RtmpTimestamp::new(0))
+            .map_err(rtmp_error)?;
+        self.chunk_size_sent = true;
+        self.socket.write_all(&packet.bytes)
+    }
+
+    // Skips everything up to the reply to the given command.
+    fn wait_for_result(&mut self, transaction: f64) -> io::Result<Vec<Amf0Value>> {
+        loop {
+            match self.read_message()? {
+                RtmpMessage::Amf0Command {
+                    command_name,
+                    transaction_id,
+                    additional_arguments,
+                    ..
+                } if transaction_id == transaction => match command_name.as_str() {
+                    "_result" => return Ok(additional_arguments),
+                    "_error" => {
+                        let description = additional_arguments
+                            .iter()
+                            .find_map(|arg| Status::from_value(arg.clone()))
+                            .map(|status| status.description)
+                            .unwrap_or_default();
+                        return Err(rtmp_error(format!(
+                            "server refused {}: {}",
+                            transaction_name(transaction),
+                            description
+                        )));
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Blocks until the server sends something other than a control message
+    fn read_message(&mut self) -> io::Result<RtmpMessage> {
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        loop {
+            if let Some(message) = self.inbox.pop_front() {
+                return Ok(message);
+            }
+
+            let n = self.socket.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "RTMP server closed the connection",
+                ));
+            }
+            self.receive(&buf[..n])?;
+        }
+    }
+
+    // Handles whatever the server has sent since we last looked, without blocking.
+    fn poll(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+        self.socket.set_nonblocking(true)?;
+        let result = loop {
+            match self.socket.read(&mut buf) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "RTMP server closed the connection",
+                    ))
+                }
+                Ok(n) => {
+                    if let Err(e) = self.receive(&buf[..n]) {
+                        break Err(e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.socket.set_nonblocking(false)?;
+        result?;
+
+        // Once we're publishing, the only news we care about is bad news.
+        while let Some(message) = self.inbox.pop_front() {
+            if let Some(status) = Status::from_message(&message) {
+                status.check()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Deserializes bytes from the server, dealing with protocol control
+    // messages and queueing the rest.
+    fn receive(&mut self, input: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(input.len()).unwrap();
+        self.bytes_received = self.bytes_received.wrapping_add(len);
+        self.bytes_since_ack = self.bytes_since_ack.saturating_add(len);
+        if let Some(window) = self.ack_window {
+            if self.bytes_since_ack >= window {
+                let sequence_number = self.bytes_received;
+                self.send(RtmpMessage::Acknowledgement { sequence_number }, 0)?;
+                self.bytes_since_ack = 0;
+            }
+        }
+
+        let mut input = input;
+        while let Some(payload) = self
+            .deserializer
+            .get_next_message(input)
+            .map_err(rtmp_error)?
+        {
+            input = &[];
+            match payload.to_rtmp_message().map_err(rtmp_error)? {
+                RtmpMessage::SetChunkSize { size } => {
+                    self.deserializer
+                        .set_max_chunk_size(size as usize)
+                        .map_err(rtmp_error)?;
+                    // Some servers (join_stream) want ours in return, before anything else.
+                    if !self.chunk_size_sent {
+                        self.send_chunk_size()?;
+                    }
+                }
+                RtmpMessage::WindowAcknowledgement { size } => self.ack_window = Some(size),
+                RtmpMessage::UserControl {
+                    event_type: UserControlEventType::PingRequest,
+                    timestamp,
+                    ..
+                } => {
+                    let pong = RtmpMessage::UserControl {
+                        event_type: UserControlEventType::PingResponse,
+                        stream_id: None,
+                        buffer_length: None,
+                        timestamp,
+                    };
+                    self.send(pong, 0)?;
+                }
+                RtmpMessage::Acknowledgement { .. }
+                | RtmpMessage::SetPeerBandwidth { .. }
+                | RtmpMessage::UserControl { .. } => {}
+                message => self.inbox.push_back(message),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn publish_tag(&mut self, tag: &FlvTag) -> io::Result<()> {
+        let media_type = tag.media_type();
+        let data = match media_type {
+            MediaType::Audio | MediaType::Video => Bytes::from(tag.body()),
+            MediaType::ScriptData => {
+                // @setDataFrame asks the server to hold on to the metadata, and
+                // pass it along to each new viewer.
+                let mut body = Vec::with_capacity(tag.payload.len() + 16);
+                let set_data_frame = flvmux::Amf0Value::String("@setDataFrame".into());
+                flvmux::amf0::write_value(&mut body, &set_data_frame)?;
+                body.extend_from_slice(&tag.payload);
+                Bytes::from(body)
+            }
+        };
+
+        // RTMP message types match FLV tag types.
+        let payload = MessagePayload {
+            timestamp: RtmpTimestamp::new(tag.timestamp as u32),
+            type_id: media_type as u8,
+            message_stream_id: self.stream_id,
+            data,
+        };
+        self.send_payload(&payload)
+    }
+}
+
+impl Write for RtmpPublisher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flv.push(buf);
+        while let Some(tag) = self.flv.next_tag()? {
+            self.publish_tag(&tag)?;
+            self.poll()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl Drop for RtmpPublisher {
+    // Best effort, the server will notice the socket closing anyway.
+    fn drop(&mut self) {
+        if self.stream_id != 0 {
+            let delete_stream = RtmpMessage::Amf0Command {
+                command_name: "deleteStream".into(),
+                transaction_id: 0.0,
+                command_object: Amf0Value::Null,
+                additional_arguments: vec![Amf0Value::Number(f64::from(self.stream_id))],
+            };
+            let _ = self.send(delete_stream, 0);
+        }
+    }
+}
+
+fn handshake(socket: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    let mut handshake = Handshake::new(PeerType::Client);
+    let p0_and_p1 = handshake
+        .generate_outbound_p0_and_p1()
+        .map_err(rtmp_error)?;
+    socket.write_all(&p0_and_p1)?;
+
+    loop {
+        let n = socket.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "RTMP server went away during handshake",
+            ));
+        }
+
+        match handshake.process_bytes(&buf[..n]).map_err(rtmp_error)? {
+            HandshakeProcessResult::InProgress { response_bytes } => {
+                socket.write_all(&response_bytes)?;
+            }
+            HandshakeProcessResult::Completed {
+                response_bytes,
+                remaining_bytes,
+            } => {
+                socket.write_all(&response_bytes)?;
+                return Ok(remaining_bytes);
+            }
+        }
+    }
+}
+
+// The info object that comes with onStatus (and _error) commands
+struct Status {
+    level: String,
+    code: String,
+    description: String,
+}
+
+impl Status {
+    fn from_message(message: &RtmpMessage) -> Option<Self> {
+        match message {
+            RtmpMessage::Amf0Command {
+                command_name,
+                additional_arguments,
+                ..
+            } if command_name == "onStatus" => additional_arguments
+                .iter()
+                .find_map(|arg| Status::from_value(arg.clone())),
+            _ => None,
+        }
+    }
+
+    fn from_value(value: Amf0Value) -> Option<Self> {
+        let mut properties = value.get_object_properties()?;
+        let mut string = |name: &str| {
+            properties
+                .remove(name)
+                .and_then(Amf0Value::get_string)
+                .unwrap_or_default()
+        };
+
+        Some(Status {
+            level: string("level"),
+            code: string("code"),
+            description: string("description"),
+        })
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.level == "error" {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("RTMP server said {}: {}", self.code, self.description),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn transaction_name(transaction: f64) -> &'static str {
+    match transaction {
+        t if t == CONNECT_TRANSACTION => "connect",
+        t if t == CREATE_STREAM_TRANSACTION => "createStream",
+        _ => "request",
+    }
+}
+
+fn rtmp_error(e: impl Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("RTMP error, {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rml_rtmp::sessions::{
+        ServerSession, ServerSessionConfig, ServerSessionEvent, ServerSessionResult,
+    };
+    use std::mem;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            RtmpUrl {
+                host: "live.twitch.tv".into(),
+                port: DEFAULT_RTMP_PORT,
+                app: "app".into(),
+                stream_key: "live_123?bandwidthtest=true".into(),
+            },
+            RtmpUrl::parse("rtmp://live.twitch.tv/app/live_123?bandwidthtest=true").unwrap()
+        );
+
+        let url = RtmpUrl::parse("rtmp://localhost:1936/live/nested/key").unwrap();
+        assert_eq!(1936, url.port);
+        assert_eq!("live/nested", url.app);
+        assert_eq!("rtmp://localhost:1936/live/nested", url.tc_url());
+
+        assert!(RtmpUrl::parse("rtmp://localhost/key").is_err());
+        assert!(RtmpUrl::parse("http://localhost/app/key").is_err());
+    }
+
+    // Accepts one publisher, and returns the server events it saw.
+    fn serve_one(listener: TcpListener) -> Vec<ServerSessionEvent> {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+
+        let mut handshake = Handshake::new(PeerType::Server);
+        let remaining = loop {
+            let n = socket.read(&mut buf).unwrap();
+            match handshake.process_bytes(&buf[..n]).unwrap() {
+                HandshakeProcessResult::InProgress { response_bytes } => {
+                    socket.write_all(&response_bytes).unwrap()
+                }
+                HandshakeProcessResult::Completed {
+                    response_bytes,
+                    remaining_bytes,
+                } => {
+                    socket.write_all(&response_bytes).unwrap();
+                    break remaining_bytes;
+                }
+            }
+        };
+
+        let (mut session, results) = ServerSession::new(ServerSessionConfig::new()).unwrap();
+        let mut pending = results;
+        pending.extend(session.handle_input(&remaining).unwrap());
+
+        let mut events = Vec::new();
+        loop {
+            while !pending.is_empty() {
+                for result in mem::take(&mut pending) {
+                    match result {
+                        ServerSessionResult::OutboundResponse(packet) => {
+                            socket.write_all(&packet.bytes).unwrap()
+                        }
+                        ServerSessionResult::RaisedEvent(event) => {
+                            match &event {
+                                ServerSessionEvent::ConnectionRequested { request_id, .. }
+                                | ServerSessionEvent::PublishStreamRequested {
+                                    request_id, ..
+                                } => pending.extend(session.accept_request(*request_id).unwrap()),
+                                _ => {}
+                            }
+                            events.push(event);
+                        }
+                        ServerSessionResult::UnhandleableMessageReceived(_) => {}
+                    }
+                }
+            }
+
+            let n = socket.read(&mut buf).unwrap();
+            if n == 0 {
+                return events;
+            }
+            pending = session.handle_input(&buf[..n]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("rtmp://{}/live/test_key", listener.local_addr().unwrap());
+        let server = thread::spawn(move || serve_one(listener));
+
+        let mut publisher = RtmpPublisher::connect(&url).unwrap();
+        let mut flv = Vec::new();
+        flvmux::write_flv_header(&mut flv).unwrap();
+        let metadata = flvmux::Metadata {
+            width: Some(320.0),
+            ..flvmux::Metadata::default()
+        };
+        flvmux::write_metadata(&mut flv, &metadata).unwrap();
+        flvmux::write_video_tag(
+            &mut flv,
+            0,
+            flvmux::AvcPacketType::SequenceHeader,
+            &[1, 2, 3],
+        )
+        .unwrap();
+        flvmux::write_audio_tag(&mut flv, 40, flvmux::AacAudioPacketType::Raw, &[4, 5]).unwrap();
+
+        // Split writes, to check partial tags are held until they're complete.
+        for chunk in flv.chunks(7) {
+            publisher.write_all(chunk).unwrap();
+        }
+        drop(publisher);
+
+        let events = server.join().unwrap();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ServerSessionEvent::PublishStreamRequested { stream_key, .. } if stream_key == "test_key"
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ServerSessionEvent::StreamMetadataChanged { metadata, .. }
+                if metadata.video_width == Some(320)
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ServerSessionEvent::VideoDataReceived { data, .. } if data[..] == [0x17, 0, 0, 0, 0, 1, 2, 3]
+        )));
+        assert!(events.iter().any(|e| matches!(
+            e,
+            ServerSessionEvent::AudioDataReceived { data, timestamp, .. }
+                if data[..] == [0xaf, 1, 4, 5] && timestamp.value == 40
+        )));
+    }
+}
@@ -0,0 +1,225 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use hls::HlsSegmenter;
+
+use crate::RtmpPublisher;
+
+/// Environment variable read by Sink::from_env, see Sink::open for the format
+pub const OUTPUT_VAR: &str = "STREAM_OUTPUT";
+
+/// Somewhere to send a stream besides a shell pipe. Any Write will do for
+/// stream_to, these are the common ones.
+pub enum Sink {
+    Stdout(io::Stdout),
+    File(File),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Rtmp(Box<RtmpPublisher>),
+    Hls(Box<HlsSegmenter>),
+}
+
+impl Sink {
+    pub fn stdout() -> Self {
+        Sink::Stdout(io::stdout())
+    }
+
+    /// Creates (or truncates) a file to record the stream into.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Sink::File(File::create(path)?))
+    }
+
+    /// Connects to a server that reads raw FLV, like ffmpeg with
+    /// "-f flv -listen 1 -i tcp://..."
+    pub fn connect_tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        // Frames are flushed as they're finished, don't hold them back.
+        stream.set_nodelay(true)?;
+        Ok(Sink::Tcp(stream))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Sink::Unix(UnixStream::connect(path)?))
+    }
+
+    /// Publishes to an RTMP server, like rtmp://live.twitch.tv/app/stream_key
+    pub fn publish_rtmp(url: &str) -> io::Result<Self> {
+        Ok(Sink::Rtmp(Box::new(RtmpPublisher::connect(url)?)))
+    }
+
+    /// Cuts the stream into HLS segments, alongside the given .m3u8 playlist.
+    pub fn hls(playlist: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Sink::Hls(Box::new(HlsSegmenter::create(playlist)?)))
+    }
+
+    /// Opens a sink described by a string:
+    ///
+    /// - "-" for standard output
+    /// - "rtmp://host[:port]/app/stream_key" to publish to an RTMP server
+    /// - "tcp://host:port" to connect over TCP
+    /// - "unix:///path/to/socket" to connect to a Unix socket
+    /// - "path/to/playlist.m3u8" for HLS
+    /// - anything else is a file path (or "file:///path")
+    pub fn open(target: &str) -> io::Result<Self> {
+        if target == "-" {
+            Ok(Sink::stdout())
+        } else if target.starts_with("rtmp://") {
+            Sink::publish_rtmp(target)
+        } else if let Some(addr) = target.strip_prefix("tcp://") {
+            Sink::connect_tcp(addr)
+        } else if let Some(path) = target.strip_prefix("unix://") {
+            open_unix(path)
+        } else if target.ends_with(".m3u8") {
+            Sink::hls(target)
+        } else if let Some(path) = target.strip_prefix("file://") {
+            Sink::create(path)
+        } else {
+            Sink::create(target)
+        }
+    }
+
+    /// Opens the sink named by STREAM_OUTPUT, or standard output if it isn't set.
+    pub fn from_env() -> io::Result<Self> {
+        match env::var(OUTPUT_VAR) {
+            Ok(target) => Sink::open(&target),
+            Err(_) => Ok(Sink::stdout()),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Stdout(out) => out,
+            Sink::File(out) => out,
+            Sink::Tcp(out) => out,
+            #[cfg(unix)]
+            Sink::Unix(out) => out,
+            Sink::Rtmp(out) => out.as_mut(),
+            Sink::Hls(out) => out.as_mut(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_unix(path: &str) -> io::Result<Sink> {
+    Sink::connect_unix(path)
+}
+
+#[cfg(not(unix))]
+fn open_unix(_path: &str) -> io::Result<Sink> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "unix sockets aren't available on this platform",
+    ))
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use self::async_sink::{async_sink, AsyncSink};
+
+#[cfg(feature = "tokio")]
+mod async_sink {
+    use std::future::Future;
+    use std::io::{self, Write};
+
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    // In chunks of (at most) a buffered writer's worth, see stream_to
+    const CHANNEL_SIZE: usize = 64;
+
+    /// The blocking half of async_sink. Writes block when the async writer
+    /// falls behind, and fail with BrokenPipe once it's gone.
+    pub struct AsyncSink {
+        sender: mpsc::Sender<Vec<u8>>,
+    }
+
+    /// Bridges the (blocking) stream functions to an async writer. Run the
+    /// stream on a blocking thread (tokio::task::spawn_blocking) with the
+    /// AsyncSink, and await the returned future to do the writing.
+    pub fn async_sink<W>(mut writer: W) -> (AsyncSink, impl Future<Output = io::Result<()>>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(CHANNEL_SIZE);
+        let forward = async move {
+            while let Some(data) = receiver.recv().await {
+                writer.write_all(&data).await?;
+                writer.flush().await?;
+            }
+
+            writer.shutdown().await
+        };
+
+        (AsyncSink { sender }, forward)
+    }
+
+    impl Write for AsyncSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sender.blocking_send(buf.to_vec()).map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "async writer has stopped")
+            })?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_tcp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut sink = Sink::open(&format!("tcp://{}", addr)).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        sink.write_all(b"FLV").unwrap();
+        drop(sink);
+
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).unwrap();
+        assert_eq!(b"FLV".to_vec(), received);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_sink() {
+        use tokio::io::AsyncReadExt;
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        let (mut sink, forward) = async_sink(writer);
+        let forward = tokio::spawn(forward);
+
+        tokio::task::spawn_blocking(move || sink.write_all(b"FLV").unwrap())
+            .await
+            .unwrap();
+        forward.await.unwrap().unwrap();
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(b"FLV".to_vec(), received);
+    }
+}
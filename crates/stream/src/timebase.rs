@@ -0,0 +1,116 @@
+use std::convert::TryFrom;
+
+use crate::{FrameRate, StreamError};
+
+/// How long one x264 timestamp tick lasts, as a fraction of a second.
+/// Streams tick once per frame, so timestamps are frame numbers and any
+/// frame rate (NTSC's 30000/1001 included) is exact.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Timebase {
+    pub num: u32,
+    pub den: u32,
+}
+
+impl Timebase {
+    /// One tick per frame
+    pub fn per_frame(fps: FrameRate) -> Self {
+        Timebase {
+            num: fps.den(),
+            den: fps.num(),
+        }
+    }
+
+    /// Converts to FLV milliseconds, rounding to the nearest. Every timestamp
+    /// is converted on its own, so rounding can't accumulate into drift.
+    pub fn to_millis(self, ticks: i64) -> Result<i32, StreamError> {
+        let scaled = 2 * i128::from(ticks) * 1000 * i128::from(self.num);
+        let den = i128::from(self.den);
+        let millis = (scaled + den).div_euclid(2 * den);
+        i32::try_from(millis).map_err(|_| StreamError::TimestampOverflow)
+    }
+
+    /// FLV composition offsets are the difference between the presentation
+    /// and decode times after they're converted, so presentation times are
+    /// as exact as decode times.
+    pub fn composition_offset_millis(
+        self,
+        presentation_ticks: i64,
+        decode_ticks: i64,
+    ) -> Result<i32, StreamError> {
+        let offset = i64::from(self.to_millis(presentation_ticks)?)
+            - i64::from(self.to_millis(decode_ticks)?);
+        i32::try_from(offset).map_err(|_| StreamError::TimestampOverflow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 24, 25, 29.97, 50, 59.94 and 60 fps, and NTSC film
+    const FRAME_RATES: [(u32, u32); 7] = [
+        (24, 1),
+        (25, 1),
+        (30000, 1001),
+        (50, 1),
+        (60000, 1001),
+        (60, 1),
+        (24000, 1001),
+    ];
+
+    #[test]
+    fn test_to_millis() {
+        let ntsc = Timebase::per_frame(FrameRate::NTSC);
+        let millis: Vec<i32> = (0..4).map(|t| ntsc.to_millis(t).unwrap()).collect();
+        assert_eq!(vec![0, 33, 67, 100], millis);
+        assert_eq!(-33, ntsc.to_millis(-1).unwrap());
+
+        assert_eq!(42, Timebase::per_frame(24.into()).to_millis(1).unwrap());
+        assert_eq!(40, Timebase::per_frame(25.into()).to_millis(1).unwrap());
+
+        // i32 milliseconds run out after about 24 days
+        let overflow = Timebase::per_frame(60.into()).to_millis(60 * 60 * 24 * 25 * 60);
+        assert!(matches!(overflow, Err(StreamError::TimestampOverflow)));
+    }
+
+    #[test]
+    fn test_no_drift() {
+        for (fps_num, fps_den) in FRAME_RATES.iter() {
+            let timebase = Timebase {
+                num: *fps_den,
+                den: *fps_num,
+            };
+
+            // A day of frames never strays more than half a millisecond
+            // from the exact time, and never goes backwards.
+            let frames = (60 * 60 * 24 * u64::from(*fps_num) / u64::from(*fps_den)) as i64;
+            let mut last = -1;
+            for frame in (0..frames).step_by(997).chain(frames - 10..frames) {
+                let millis = timebase.to_millis(frame).unwrap();
+                let exact = frame as f64 * 1000.0 * f64::from(*fps_den) / f64::from(*fps_num);
+                assert!(
+                    (f64::from(millis) - exact).abs() <= 0.5,
+                    "{}/{} fps frame {}: {} ms, should be {}",
+                    fps_num,
+                    fps_den,
+                    frame,
+                    millis,
+                    exact
+                );
+                assert!(millis > last);
+                last = millis;
+            }
+
+            // Decode time plus composition offset is the presentation time
+            // converted directly.
+            for dts in 0..1000 {
+                let pts = dts + 2;
+                let offset = timebase.composition_offset_millis(pts, dts).unwrap();
+                assert_eq!(
+                    timebase.to_millis(pts).unwrap(),
+                    timebase.to_millis(dts).unwrap() + offset
+                );
+            }
+        }
+    }
+}
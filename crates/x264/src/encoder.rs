@@ -0,0 +1,415 @@
+use std::io;
+use std::mem;
+use std::os::raw;
+use std::ptr;
+use std::slice;
+
+use libx264_sys::*;
+
+use crate::{FrameType, Param, Picture};
+
+/// An open x264 encoder.
+pub struct Encoder {
+    raw: *mut x264_t,
+}
+
+// x264 encoders aren't tied to the thread that opened them, they just
+// can't be used from two at once.
+unsafe impl Send for Encoder {}
+
+impl Encoder {
+    /// Fails with InvalidInput if x264 rejects the settings. x264 logs why
+    /// to stderr.
+    pub fn open(param: &Param) -> io::Result<Self> {
+        // x264 copies the settings, changing them as it validates them.
+        let mut raw_param = *param.as_raw();
+        let raw = unsafe { x264_encoder_open(&mut raw_param) };
+        if raw.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "x264 rejected the encoder settings",
+            ));
+        }
+
+        Ok(Encoder { raw })
+    }
+
+    /// The SPS and PPS (and an SEI describing the encoder) that have to come
+    /// before the first frame. Fails with OutOfMemory if x264 can't make
+    /// room for them.
+    pub fn headers(&mut self) -> io::Result<Nals<'_>> {
+        let mut pp_nal: *mut x264_nal_t = ptr::null_mut();
+        let mut pi_nal: raw::c_int = 0;
+
+        if unsafe { x264_encoder_headers(self.raw, &mut pp_nal, &mut pi_nal) } < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "x264 couldn't allocate the encoder headers",
+            ));
+        }
+
+        Ok(unsafe { Nals::from_raw(pp_nal, pi_nal) })
+    }
+
+    /// Encodes a picture. Returns the next frame out of the encoder, which
+    /// (with B-frames or lookahead) is probably one from a few pictures ago,
+    /// or None if the encoder is still filling up.
+    ///
+    /// Fails with InvalidInput if x264 won't take the picture (one made for
+    /// different settings, say). x264 logs why to stderr.
+    pub fn encode(&mut self, picture: &mut Picture) -> io::Result<Option<Frame<'_>>> {
+        let pic_in = picture.as_raw_mut() as *mut x264_picture_t;
+        self.encode_raw(pic_in)
+    }
+
+    /// Returns one of the frames the encoder is still holding on to, or
+    /// None once there aren't any. Call this until delayed_frames is zero
+    /// at the end of a stream.
+    pub fn flush(&mut self) -> io::Result<Option<Frame<'_>>> {
+        self.encode_raw(ptr::null_mut())
+    }
+
+    /// How many pictures went in without coming out yet
+    pub fn delayed_frames(&self) -> usize {
+        let delayed = unsafe { x264_encoder_delayed_frames(self.raw) };
+        delayed.max(0) as usize
+    }
+
+    /// The settings the encoder is using, after x264 filled in its choices.
+    /// File names and zones are left at x264's defaults, since the encoder's
+    /// copies go when it does.
+    pub fn parameters(&self) -> Param {
+        let mut raw: mem::MaybeUninit<x264_param_t> = mem::MaybeUninit::uninit();
+        let raw = unsafe {
+            x264_encoder_parameters(self.raw, raw.as_mut_ptr());
+            raw.assume_init()
+        };
+        Param::from_raw(raw)
+    }
+
+    /// Changes settings mid-stream, from the next picture on (so parameters()
+    /// won't show them until that picture is through lookahead). x264 only
+    /// takes some changes (rate control, mostly), and quietly ignores the
+    /// rest. Start from parameters() rather than a fresh Param.
+    ///
+    /// Bitrates are the catch. x264 can change a CBR stream's bitrate, but
+    /// not with NAL HRD signalling on (see Param::set_nal_hrd), and only
+    /// the max bitrate of a VBR stream, never its average.
+    pub fn reconfigure(&mut self, param: &Param) -> io::Result<()> {
+        let mut raw_param = *param.as_raw();
+        if unsafe { x264_encoder_reconfig(self.raw, &mut raw_param) } < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "x264 rejected the new encoder settings",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// How many frames decode times trail presentation times, x264's
+    /// i_bframe_delay. Depends on the B-frame settings the encoder settled on.
+    pub fn bframe_delay(&self) -> i64 {
+        let param = self.parameters();
+        match (param.bframes(), param.bframe_pyramid()) {
+            (0, _) => 0,
+            (_, false) => 1,
+            _ => 2,
+        }
+    }
+
+    fn encode_raw(&mut self, pic_in: *mut x264_picture_t) -> io::Result<Option<Frame<'_>>> {
+        let mut pic_out: mem::MaybeUninit<x264_picture_t> = mem::MaybeUninit::uninit();
+        let mut pp_nal: *mut x264_nal_t = ptr::null_mut();
+        let mut pi_nal: raw::c_int = 0;
+
+        let result = unsafe {
+            x264_encoder_encode(
+                self.raw,
+                &mut pp_nal,
+                &mut pi_nal,
+                pic_in,
+                pic_out.as_mut_ptr(),
+            )
+        };
+
+        if result < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "x264 couldn't encode the picture",
+            ));
+        }
+
+        if pi_nal <= 0 {
+            return Ok(None);
+        }
+
+        let pic_out = unsafe { pic_out.assume_init() };
+        Ok(Some(Frame {
+            nals: unsafe { Nals::from_raw(pp_nal, pi_nal) },
+            pts: pic_out.i_pts,
+            dts: pic_out.i_dts,
+            keyframe: pic_out.b_keyframe != 0,
+            frame_type: FrameType::from_raw(pic_out.i_type),
+        }))
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        unsafe { x264_encoder_close(self.raw) };
+    }
+}
+
+/// An encoded frame. Its NAL units live in the encoder, until the next
+/// call to it.
+pub struct Frame<'a> {
+    nals: Nals<'a>,
+    /// Presentation and decode timestamps, in the param's timebase
+    pub pts: i64,
+    pub dts: i64,
+    /// A frame a decoder can start from
+    pub keyframe: bool,
+    pub frame_type: FrameType,
+}
+
+impl<'a> Frame<'a> {
+    pub fn nals(&self) -> Nals<'a> {
+        self.nals.clone()
+    }
+
+    /// True if the frame has an IDR slice
+    pub fn is_idr(&self) -> bool {
+        self.nals()
+            .any(|nal| nal.unit_type == NalUnitType::SliceIdr)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NalUnitType {
+    Slice,
+    SliceIdr,
+    Sei,
+    Sps,
+    Pps,
+    Aud,
+    Filler,
+    Other(u8),
+}
+
+impl NalUnitType {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(i_type: raw::c_int) -> Self {
+        match i_type as u32 {
+            nal_unit_type_e_NAL_SLICE => NalUnitType::Slice,
+            nal_unit_type_e_NAL_SLICE_IDR => NalUnitType::SliceIdr,
+            nal_unit_type_e_NAL_SEI => NalUnitType::Sei,
+            nal_unit_type_e_NAL_SPS => NalUnitType::Sps,
+            nal_unit_type_e_NAL_PPS => NalUnitType::Pps,
+            nal_unit_type_e_NAL_AUD => NalUnitType::Aud,
+            nal_unit_type_e_NAL_FILLER => NalUnitType::Filler,
+            other => NalUnitType::Other(other as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Nal<'a> {
+    pub unit_type: NalUnitType,
+    /// The NAL unit, Annex-B start code and all
+    pub payload: &'a [u8],
+}
+
+/// The NAL units from one call to the encoder, in order.
+#[derive(Clone)]
+pub struct Nals<'a> {
+    nals: slice::Iter<'a, x264_nal_t>,
+}
+
+impl<'a> Nals<'a> {
+    // pp_nal has to stay valid for 'a
+    unsafe fn from_raw(pp_nal: *mut x264_nal_t, pi_nal: raw::c_int) -> Self {
+        let nals = if pp_nal.is_null() || pi_nal <= 0 {
+            &[]
+        } else {
+            slice::from_raw_parts(pp_nal as *const x264_nal_t, pi_nal as usize)
+        };
+        Nals { nals: nals.iter() }
+    }
+
+    /// All of the NAL units as one Annex-B byte stream
+    pub fn annex_b(self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in self {
+            data.extend_from_slice(nal.payload);
+        }
+        data
+    }
+}
+
+impl<'a> Iterator for Nals<'a> {
+    type Item = Nal<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.nals.next().map(|nal| Nal {
+            unit_type: NalUnitType::from_raw(nal.i_type),
+            payload: unsafe { slice::from_raw_parts(nal.p_payload, nal.i_payload as usize) },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flvmux::avc;
+
+    fn test_param() -> Param {
+        let mut param = Param::preset("veryfast", None).unwrap();
+        param.set_resolution(160, 96).unwrap();
+        param.set_fps(30, 1);
+        param.set_timebase(1, 30);
+        param.set_keyint_max(10).unwrap();
+        param.set_bframes(2).unwrap();
+        param.apply_profile("high").unwrap();
+        param
+    }
+
+    fn draw(picture: &mut Picture, frame: usize) {
+        let (y, u, v) = picture.planes_mut();
+        for (ix, px) in y.iter_mut().enumerate() {
+            *px = ((ix + frame * 8) % 256) as u8;
+        }
+        for px in u.iter_mut().chain(v.iter_mut()) {
+            *px = 128;
+        }
+    }
+
+    struct Encoded {
+        pts: i64,
+        dts: i64,
+        idr: bool,
+        frame_type: FrameType,
+        data: Vec<u8>,
+    }
+
+    fn encoded(frame: Frame) -> Encoded {
+        Encoded {
+            pts: frame.pts,
+            dts: frame.dts,
+            idr: frame.is_idr(),
+            frame_type: frame.frame_type,
+            data: frame.nals().annex_b(),
+        }
+    }
+
+    #[test]
+    fn test_headers() {
+        let mut encoder = Encoder::open(&test_param()).unwrap();
+        let types: Vec<NalUnitType> = encoder.headers().unwrap().map(|n| n.unit_type).collect();
+        assert_eq!(
+            vec![NalUnitType::Sps, NalUnitType::Pps, NalUnitType::Sei],
+            types
+        );
+
+        let headers = encoder.headers().unwrap().annex_b();
+        let sps = avc::annexb_nal_units(&headers).next().unwrap();
+        let sps = avc::parse_sps(sps).unwrap();
+        assert_eq!((160, 96), (sps.width, sps.height));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let param = test_param();
+        let mut encoder = Encoder::open(&param).unwrap();
+        let mut picture = Picture::new(&param).unwrap();
+        assert_eq!(160 * 96, picture.planes_mut().0.len());
+        assert_eq!(80 * 48, picture.planes_mut().1.len());
+
+        let mut frames = Vec::new();
+        for frame in 0..25 {
+            draw(&mut picture, frame);
+            picture.set_pts(frame as i64);
+            // Ask for an IDR off the usual keyint schedule
+            if frame == 13 {
+                picture.set_frame_type(FrameType::Idr);
+            }
+            if let Some(out) = encoder.encode(&mut picture).unwrap() {
+                frames.push(encoded(out));
+            }
+            picture.set_frame_type(FrameType::Auto);
+        }
+
+        // B-frames and lookahead hold some frames back, until we flush
+        assert!(frames.len() < 25);
+        assert_eq!(25 - frames.len(), encoder.delayed_frames());
+        while encoder.delayed_frames() > 0 {
+            frames.push(encoded(encoder.flush().unwrap().unwrap()));
+        }
+        assert!(encoder.flush().unwrap().is_none());
+
+        // Every picture came out once
+        let mut pts: Vec<i64> = frames.iter().map(|f| f.pts).collect();
+        pts.sort_unstable();
+        assert_eq!((0..25).collect::<Vec<i64>>(), pts);
+
+        // In decode order, with B-frames decoding ahead of presentation
+        let delay = encoder.bframe_delay();
+        assert_eq!(2, delay);
+        for (ix, frame) in frames.iter().enumerate() {
+            assert_eq!(ix as i64 - delay, frame.dts);
+            assert!(frame.dts <= frame.pts);
+        }
+
+        assert!(frames[0].idr);
+        assert_eq!(FrameType::Idr, frames[0].frame_type);
+        let idr = frames.iter().find(|f| f.pts == 13).unwrap();
+        assert!(idr.idr);
+        for frame in &frames {
+            let types: Vec<u8> = avc::annexb_nal_units(&frame.data)
+                .filter_map(avc::nal_unit_type)
+                .collect();
+            assert!(!types.is_empty());
+            assert_eq!(frame.idr, types.contains(&avc::NAL_SLICE_IDR));
+        }
+    }
+
+    #[test]
+    fn test_parameters_outlive_encoder() {
+        let param = Encoder::open(&test_param()).unwrap().parameters();
+        // Not the freed copy in the encoder
+        let defaults = Param::preset("veryfast", None).unwrap();
+        assert_eq!(
+            defaults.as_raw().rc.psz_stat_out,
+            param.as_raw().rc.psz_stat_out
+        );
+
+        let mut encoder = Encoder::open(&param).unwrap();
+        let mut picture = Picture::new(&param).unwrap();
+        draw(&mut picture, 0);
+        encoder.encode(&mut picture).unwrap();
+        assert_eq!((160, 96), (param.width(), param.height()));
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let mut param = test_param();
+        param.set_cbr(1000).unwrap();
+        let mut encoder = Encoder::open(&param).unwrap();
+        let mut picture = Picture::new(&param).unwrap();
+        draw(&mut picture, 0);
+        encoder.encode(&mut picture).unwrap();
+
+        let mut param = encoder.parameters();
+        assert_eq!(Some(1000), param.bitrate());
+        param.set_cbr(500).unwrap();
+        encoder.reconfigure(&param).unwrap();
+
+        // The change applies from the next picture, once it's through
+        // lookahead
+        for pts in 1..30 {
+            picture.set_pts(pts);
+            encoder.encode(&mut picture).unwrap();
+        }
+        assert_eq!(Some(500), encoder.parameters().bitrate());
+    }
+}
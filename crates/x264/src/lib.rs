@@ -0,0 +1,13 @@
+//! Safe wrappers around the parts of libx264 we use: setting up encoder
+//! parameters, allocating pictures, and encoding them to H.264 NAL units.
+
+mod encoder;
+mod param;
+mod picture;
+
+pub use encoder::{Encoder, Frame, Nal, NalUnitType, Nals};
+pub use param::Param;
+pub use picture::{FrameType, Picture};
+
+/// The raw bindings, for anything these wrappers don't cover.
+pub use libx264_sys as sys;
@@ -0,0 +1,263 @@
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::raw;
+use std::ptr;
+
+use libx264_sys::*;
+
+/// Encoder settings, an x264_param_t. Start from a preset, change what you
+/// need, then apply a profile last, since profiles restrict the rest.
+#[derive(Clone)]
+pub struct Param {
+    raw: x264_param_t,
+}
+
+// Besides what callers set through as_raw_mut, the only pointers in a
+// Param are x264's static default strings and callbacks (see from_raw).
+unsafe impl Send for Param {}
+
+impl Param {
+    /// x264's settings for a preset ("veryfast", say) and an optional tune
+    /// ("zerolatency").
+    pub fn preset(preset: &str, tune: Option<&str>) -> io::Result<Self> {
+        let preset_str = c_string("preset", preset)?;
+        let tune_str = tune.map(|tune| c_string("tune", tune)).transpose()?;
+        let tune_ptr = tune_str.as_ref().map_or(ptr::null(), |tune| tune.as_ptr());
+
+        let mut raw: mem::MaybeUninit<x264_param_t> = mem::MaybeUninit::uninit();
+        match unsafe { x264_param_default_preset(raw.as_mut_ptr(), preset_str.as_ptr(), tune_ptr) }
+        {
+            0 => Ok(Param {
+                raw: unsafe { raw.assume_init() },
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("x264 doesn't know preset {:?} with tune {:?}", preset, tune),
+            )),
+        }
+    }
+
+    /// Restricts the settings to a profile, like "high" or "baseline".
+    /// Fails if the current settings need more than the profile allows.
+    pub fn apply_profile(&mut self, profile: &str) -> io::Result<()> {
+        let profile_str = c_string("profile", profile)?;
+        match unsafe { x264_param_apply_profile(&mut self.raw, profile_str.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("x264 can't use profile {:?} with these settings", profile),
+            )),
+        }
+    }
+
+    pub fn set_resolution(&mut self, width: u32, height: u32) -> io::Result<()> {
+        self.raw.i_width = c_int("width", width)?;
+        self.raw.i_height = c_int("height", height)?;
+        Ok(())
+    }
+
+    pub fn width(&self) -> u32 {
+        self.raw.i_width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.raw.i_height as u32
+    }
+
+    /// Frames per second, as a fraction
+    pub fn set_fps(&mut self, num: u32, den: u32) {
+        self.raw.i_fps_num = num;
+        self.raw.i_fps_den = den;
+    }
+
+    pub fn fps(&self) -> (u32, u32) {
+        (self.raw.i_fps_num, self.raw.i_fps_den)
+    }
+
+    /// The units of picture timestamps, in seconds. x264 uses this rather
+    /// than the frame rate to make sense of timestamps.
+    pub fn set_timebase(&mut self, num: u32, den: u32) {
+        self.raw.i_timebase_num = num;
+        self.raw.i_timebase_den = den;
+    }
+
+    pub fn timebase(&self) -> (u32, u32) {
+        (self.raw.i_timebase_num, self.raw.i_timebase_den)
+    }
+
+    /// Most frames between IDR frames
+    pub fn set_keyint_max(&mut self, keyint: u32) -> io::Result<()> {
+        self.raw.i_keyint_max = c_int("keyint", keyint)?;
+        Ok(())
+    }
+
+    /// Fewest frames between IDR frames. Zero lets x264 choose.
+    pub fn set_keyint_min(&mut self, keyint: u32) -> io::Result<()> {
+        self.raw.i_keyint_min = c_int("keyint min", keyint)?;
+        Ok(())
+    }
+
+    pub fn keyint_max(&self) -> u32 {
+        self.raw.i_keyint_max as u32
+    }
+
+    /// Most consecutive B-frames
+    pub fn set_bframes(&mut self, bframes: u32) -> io::Result<()> {
+        self.raw.i_bframe = c_int("bframes", bframes)?;
+        Ok(())
+    }
+
+    pub fn bframes(&self) -> u32 {
+        self.raw.i_bframe as u32
+    }
+
+    /// True if B-frames can be used as references by other B-frames
+    pub fn bframe_pyramid(&self) -> bool {
+        self.raw.i_bframe_pyramid != 0
+    }
+
+    /// Zero lets x264 choose.
+    pub fn set_threads(&mut self, threads: u32) -> io::Result<()> {
+        self.raw.i_threads = c_int("threads", threads)?;
+        Ok(())
+    }
+
+    /// Constant quality, 0 (lossless) to 51. x264 defaults to 23.
+    pub fn set_crf(&mut self, crf: f32) {
+        self.raw.rc.i_rc_method = X264_RC_CRF as raw::c_int;
+        self.raw.rc.f_rf_constant = crf;
+    }
+
+    /// Constant bitrate in kbit/s, with a one second VBV buffer. HRD
+    /// signalling is left to set_nal_hrd.
+    pub fn set_cbr(&mut self, bitrate: u32) -> io::Result<()> {
+        self.raw.rc.i_rc_method = X264_RC_ABR as raw::c_int;
+        self.raw.rc.i_bitrate = c_int("bitrate", bitrate)?;
+        self.raw.rc.i_vbv_max_bitrate = self.raw.rc.i_bitrate;
+        self.raw.rc.i_vbv_buffer_size = self.raw.rc.i_bitrate;
+        Ok(())
+    }
+
+    /// Average bitrate in kbit/s, capped at max_bitrate (kbit/s) over a
+    /// buffer_size (kbit) VBV buffer.
+    pub fn set_vbr(&mut self, bitrate: u32, max_bitrate: u32, buffer_size: u32) -> io::Result<()> {
+        self.raw.rc.i_rc_method = X264_RC_ABR as raw::c_int;
+        self.raw.rc.i_bitrate = c_int("bitrate", bitrate)?;
+        self.raw.rc.i_vbv_max_bitrate = c_int("max bitrate", max_bitrate)?;
+        self.raw.rc.i_vbv_buffer_size = c_int("buffer size", buffer_size)?;
+        Ok(())
+    }
+
+    /// Whether the stream signals its bitrate and buffer (HRD parameters)
+    /// to decoders, as broadcast and some ingest servers want. Set after the
+    /// rate control. x264 can't change the bitrate of a stream with them.
+    pub fn set_nal_hrd(&mut self, nal_hrd: bool) {
+        let hrd = if !nal_hrd {
+            X264_NAL_HRD_NONE
+        } else if self.raw.rc.i_vbv_max_bitrate == self.raw.rc.i_bitrate {
+            X264_NAL_HRD_CBR
+        } else {
+            X264_NAL_HRD_VBR
+        };
+        self.raw.i_nal_hrd = hrd as raw::c_int;
+    }
+
+    pub fn nal_hrd(&self) -> bool {
+        self.raw.i_nal_hrd != X264_NAL_HRD_NONE as raw::c_int
+    }
+
+    /// The target bitrate in kbit/s, or None if rate control targets
+    /// quality instead.
+    pub fn bitrate(&self) -> Option<u32> {
+        if self.raw.rc.i_rc_method == X264_RC_ABR as raw::c_int {
+            Some(self.raw.rc.i_bitrate as u32)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_raw(&self) -> &x264_param_t {
+        &self.raw
+    }
+
+    /// # Safety
+    ///
+    /// x264 will follow any pointers set here, and trusts that the strings
+    /// are NUL terminated. Whatever they point to has to outlive the Param
+    /// and its clones.
+    pub unsafe fn as_raw_mut(&mut self) -> &mut x264_param_t {
+        &mut self.raw
+    }
+
+    // For settings from x264_encoder_parameters. x264 copies the strings
+    // (and zones) a param points to into the encoder, and frees them with
+    // it, so those go back to x264's defaults, which are static.
+    pub(crate) fn from_raw(mut raw: x264_param_t) -> Self {
+        let mut defaults: mem::MaybeUninit<x264_param_t> = mem::MaybeUninit::uninit();
+        let defaults = unsafe {
+            x264_param_default(defaults.as_mut_ptr());
+            defaults.assume_init()
+        };
+
+        raw.psz_cqm_file = defaults.psz_cqm_file;
+        raw.psz_dump_yuv = defaults.psz_dump_yuv;
+        raw.psz_clbin_file = defaults.psz_clbin_file;
+        raw.rc.psz_stat_out = defaults.rc.psz_stat_out;
+        raw.rc.psz_stat_in = defaults.rc.psz_stat_in;
+        raw.rc.psz_zones = defaults.rc.psz_zones;
+        raw.rc.zones = defaults.rc.zones;
+        raw.rc.i_zones = defaults.rc.i_zones;
+        raw.param_free = defaults.param_free;
+        Param { raw }
+    }
+}
+
+fn c_string(name: &str, value: &str) -> io::Result<CString> {
+    CString::new(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't contain NUL bytes, got {:?}", name, value),
+        )
+    })
+}
+
+fn c_int(name: &str, value: u32) -> io::Result<raw::c_int> {
+    raw::c_int::try_from(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is too large, got {}", name, value),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_param() {
+        let mut param = Param::preset("veryfast", Some("zerolatency")).unwrap();
+        param.set_resolution(320, 240).unwrap();
+        param.set_fps(30000, 1001);
+        param.set_cbr(2500).unwrap();
+        assert_eq!((320, 240), (param.width(), param.height()));
+        assert_eq!((30000, 1001), param.fps());
+        assert_eq!(Some(2500), param.bitrate());
+        assert!(!param.nal_hrd());
+        param.set_nal_hrd(true);
+        assert_eq!(X264_NAL_HRD_CBR as raw::c_int, param.as_raw().i_nal_hrd);
+        // zerolatency turns off B-frames
+        assert_eq!(0, param.bframes());
+
+        param.set_crf(23.0);
+        assert_eq!(None, param.bitrate());
+        param.apply_profile("high").unwrap();
+
+        assert!(Param::preset("warpspeed", None).is_err());
+        assert!(Param::preset("fast\0", None).is_err());
+        assert!(param.set_keyint_max(u32::MAX).is_err());
+        assert!(param.apply_profile("extreme").is_err());
+    }
+}
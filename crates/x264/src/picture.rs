@@ -0,0 +1,139 @@
+use std::io;
+use std::mem;
+use std::os::raw;
+use std::slice;
+
+use libx264_sys::*;
+
+use crate::Param;
+
+/// What kind of frame a picture should be encoded as, or was.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// Let x264 decide
+    Auto,
+    Idr,
+    I,
+    P,
+    /// A B-frame other B-frames refer to
+    BRef,
+    B,
+    /// An IDR frame, or an I frame with open GOPs
+    Keyframe,
+}
+
+impl FrameType {
+    pub(crate) fn from_raw(i_type: raw::c_int) -> Self {
+        match i_type as u32 {
+            X264_TYPE_IDR => FrameType::Idr,
+            X264_TYPE_I => FrameType::I,
+            X264_TYPE_P => FrameType::P,
+            X264_TYPE_BREF => FrameType::BRef,
+            X264_TYPE_B => FrameType::B,
+            X264_TYPE_KEYFRAME => FrameType::Keyframe,
+            _ => FrameType::Auto,
+        }
+    }
+
+    fn to_raw(self) -> raw::c_int {
+        let i_type = match self {
+            FrameType::Auto => X264_TYPE_AUTO,
+            FrameType::Idr => X264_TYPE_IDR,
+            FrameType::I => X264_TYPE_I,
+            FrameType::P => X264_TYPE_P,
+            FrameType::BRef => X264_TYPE_BREF,
+            FrameType::B => X264_TYPE_B,
+            FrameType::Keyframe => X264_TYPE_KEYFRAME,
+        };
+        i_type as raw::c_int
+    }
+}
+
+/// An I420 picture, allocated by x264, to fill in and encode.
+pub struct Picture {
+    raw: x264_picture_t,
+    height: usize,
+}
+
+// The planes belong to the picture alone.
+unsafe impl Send for Picture {}
+
+impl Picture {
+    /// Sized for param's resolution.
+    pub fn new(param: &Param) -> io::Result<Self> {
+        let raw_param = param.as_raw();
+        if raw_param.i_csp as u32 & X264_CSP_MASK != X264_CSP_I420 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only I420 pictures are supported",
+            ));
+        }
+
+        let mut raw: mem::MaybeUninit<x264_picture_t> = mem::MaybeUninit::uninit();
+        let raw = match unsafe {
+            x264_picture_alloc(
+                raw.as_mut_ptr(),
+                raw_param.i_csp,
+                raw_param.i_width,
+                raw_param.i_height,
+            )
+        } {
+            0 => unsafe { raw.assume_init() },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    "x264 couldn't allocate a picture",
+                ))
+            }
+        };
+
+        Ok(Picture {
+            raw,
+            height: param.height() as usize,
+        })
+    }
+
+    /// The Y, U and V planes. Rows are packed, so the Y plane is width
+    /// bytes by height rows, and U and V are half that in each direction.
+    pub fn planes_mut(&mut self) -> (&mut [u8], &mut [u8], &mut [u8]) {
+        let img = &self.raw.img;
+        let luma_size = img.i_stride[0] as usize * self.height;
+        let chroma_size = img.i_stride[1] as usize * (self.height / 2);
+        unsafe {
+            (
+                slice::from_raw_parts_mut(img.plane[0], luma_size),
+                slice::from_raw_parts_mut(img.plane[1], chroma_size),
+                slice::from_raw_parts_mut(img.plane[2], chroma_size),
+            )
+        }
+    }
+
+    /// The presentation timestamp, in the param's timebase
+    pub fn pts(&self) -> i64 {
+        self.raw.i_pts
+    }
+
+    pub fn set_pts(&mut self, pts: i64) {
+        self.raw.i_pts = pts;
+    }
+
+    /// Forces the next encode of this picture to a frame type. Remember to
+    /// set it back to FrameType::Auto afterwards.
+    pub fn set_frame_type(&mut self, frame_type: FrameType) {
+        self.raw.i_type = frame_type.to_raw();
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        FrameType::from_raw(self.raw.i_type)
+    }
+
+    pub(crate) fn as_raw_mut(&mut self) -> &mut x264_picture_t {
+        &mut self.raw
+    }
+}
+
+impl Drop for Picture {
+    fn drop(&mut self) {
+        unsafe { x264_picture_clean(&mut self.raw) }
+    }
+}
@@ -7,6 +7,9 @@
 # This script will look for (and source) ./SECRETS and assume that
 # that script has `define RTMP_INGEST` with an RTMP url+key that
 # will be the sink of the stream.
+#
+# Shows that loop their own audio (for example `lightcycles music.aac`)
+# already produce complete audio and video, so leave off the audio argument.
 
 audio=$1
 
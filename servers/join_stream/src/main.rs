@@ -14,7 +14,7 @@ use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::Display;
 use std::time::Instant;
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc;
 
@@ -24,6 +24,7 @@ use rml_rtmp::handshake::{Handshake, HandshakeProcessResult, PeerType};
 use rml_rtmp::messages::{PeerBandwidthLimitType, RtmpMessage, UserControlEventType};
 use rml_rtmp::time::RtmpTimestamp;
 
+use flvmux::{AsyncFlvWriter, MediaType, TimestampPolicy};
 use mixer::Mixer;
 
 struct Clock(Instant);
@@ -40,13 +41,6 @@ const READ_BUFFER_SIZE: usize = 4096;
 const PRE_MIXER_CHANNEL_BUFFER_SIZE: usize = 100;
 const MAX_CLIENT_COUNT: usize = 10;
 
-#[derive(Debug)]
-struct WriteMessage {
-    message: RtmpMessage,
-    force_uncompressed: bool,
-    can_be_dropped: bool,
-}
-
 #[derive(Debug)]
 struct ClientError {
     message: String,
@@ -435,35 +429,61 @@ async fn handle_client_stream(
 async fn main() {
     let listener = TcpListener::bind("0.0.0.0:1935").await.unwrap();
 
-    let mut out = io::stdout();
     let mut mixer = mixer::FifoMixer::default();
-    let mut outbuffer = Vec::new();
-    flvmux::write_flv_header(&mut outbuffer).unwrap();
-    out.write_all(&outbuffer).await.unwrap();
+
+    // Switching sources can step timestamps back a little. Players cope
+    // with a repeated timestamp better than a backwards one.
+    let mut writer = AsyncFlvWriter::new(BufWriter::new(io::stdout()))
+        .await
+        .unwrap()
+        .timestamp_policy(TimestampPolicy::Clamp);
 
     {
         // Scope for channels and children
         let (media_sender, mut media_receiver) = mpsc::channel(PRE_MIXER_CHANNEL_BUFFER_SIZE);
-        let (client_exit_sender, mut client_exit_receiver) = mpsc::channel(MAX_CLIENT_COUNT);
+        let (client_exit_sender, mut client_exit_receiver) =
+            mpsc::channel::<Result<(), ClientError>>(MAX_CLIENT_COUNT);
 
         tokio::spawn(async move {
             while let Some(media) = media_receiver.recv().await {
-                outbuffer.truncate(0);
-                let result = match media {
+                let (media_type, data, result) = match media {
                     MediaData::Video {
                         data,
                         timestamp,
                         source,
-                    } => mixer.source_video(&mut outbuffer, source, &data, timestamp),
+                    } => {
+                        let result = mixer.source_video(source, &data, timestamp);
+                        (MediaType::Video, data, result)
+                    }
                     MediaData::Audio {
                         data,
                         timestamp,
                         source,
-                    } => mixer.source_audio(&mut outbuffer, source, &data, timestamp),
+                    } => {
+                        let result = mixer.source_audio(source, &data, timestamp);
+                        (MediaType::Audio, data, result)
+                    }
+                };
+
+                let timestamp = match result {
+                    Ok(Some(timestamp)) => timestamp,
+                    Ok(None) => continue,
+                    Err(e) => panic!("mixer failed: {}", e.message()),
                 };
-                result.unwrap();
-                out.write_all(&outbuffer).await.unwrap(); // TODO
+                writer
+                    .write_raw_tag(media_type, timestamp, &data)
+                    .await
+                    .unwrap(); // TODO
+                writer.flush().await.unwrap();
             }
+
+            let stats = writer.stats();
+            eprintln!(
+                "wrote {} video and {} audio tags, {:.1}s",
+                stats.video_tags,
+                stats.audio_tags,
+                stats.duration_millis() as f64 / 1000.0
+            );
         });
 
         let mut next_source: mixer::MixerSource = 0;
@@ -473,7 +493,7 @@ async fn main() {
                 val = listener.accept() => val,
                 result = client_exit_receiver.recv() => {
                     if let Err(e) = result.unwrap() {
-                        eprintln!("client error: {:?}", e);
+                        eprintln!("client error: {}", e.message);
                     }
 
                     client_count -= 1;
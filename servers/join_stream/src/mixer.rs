@@ -1,8 +1,5 @@
-use byteorder::{BigEndian, WriteBytesExt};
 use std::collections::HashMap;
-use std::convert::TryFrom;
 use std::fmt::Display;
-use std::io::Write;
 
 use flvmux::{AacAudioPacketType, AvcPacketType};
 
@@ -15,6 +12,12 @@ pub struct MixerError {
     message: String,
 }
 
+impl MixerError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
 impl<T: Display> From<T> for MixerError {
     fn from(other: T) -> Self {
         Self {
@@ -23,22 +26,21 @@ impl<T: Display> From<T> for MixerError {
     }
 }
 
-// Uniqueness of MixerSources is up to the client.
+// Uniqueness of MixerSources is up to the client. Mixers return the
+// timestamp to write the tag with, or None to drop it.
 pub trait Mixer {
     fn source_video(
         &mut self,
-        out: impl Write,
         source: MixerSource,
         data: &[u8],
         timestamp: i32,
-    ) -> Result<(), MixerError>;
+    ) -> Result<Option<i32>, MixerError>;
     fn source_audio(
         &mut self,
-        out: impl Write,
         source: MixerSource,
         data: &[u8],
         timestamp: i32,
-    ) -> Result<(), MixerError>;
+    ) -> Result<Option<i32>, MixerError>;
 }
 
 #[derive(Debug)]
@@ -75,6 +77,7 @@ impl LastSwitchInfo for Option<LastSwitch> {
     }
 }
 
+#[derive(Default)]
 pub struct FifoMixer {
     source_timestamps: HashMap<MixerSource, SourceTs>,
     audio_timestamp: i32,
@@ -83,29 +86,16 @@ pub struct FifoMixer {
     last_audio_switch: Option<LastSwitch>,
 }
 
-impl Default for FifoMixer {
-    fn default() -> Self {
-        Self {
-            source_timestamps: HashMap::new(),
-            audio_timestamp: 0,
-            video_timestamp: 0,
-            last_video_switch: None,
-            last_audio_switch: None,
-        }
-    }
-}
-
 // This assumes that the relevant resolution and color space and sample rate
 // (and any other out-of-band stuff that decoders expect not to change
 // during a stream) are the same for all sources.
 impl Mixer for FifoMixer {
     fn source_audio(
         &mut self,
-        mut out: impl Write,
         source: MixerSource,
         data: &[u8],
         timestamp: i32,
-    ) -> Result<(), MixerError> {
+    ) -> Result<Option<i32>, MixerError> {
         let ts = match self.source_timestamps.get_mut(&source) {
             Some(ts) => ts,
             None => {
@@ -151,26 +141,19 @@ impl Mixer for FifoMixer {
             AacAudioPacketType::Raw if self.last_audio_switch.same_source(source) => {
                 // Ok, pass through
             }
-            _ => return Ok(()),
+            _ => return Ok(None),
         }
 
         self.audio_timestamp += dt;
-        let data_size = u32::try_from(data.len())?;
-        flvmux::write_audio_tag_header(&mut out, data_size, self.audio_timestamp)?;
-        out.write_all(data)?;
-        let data_size = u32::try_from(data.len())?;
-        out.write_u32::<BigEndian>(data_size + 11)?; // 11 bytes of header
-
-        Ok(())
+        Ok(Some(self.audio_timestamp))
     }
 
     fn source_video(
         &mut self,
-        mut out: impl Write,
         source: MixerSource,
         data: &[u8],
         timestamp: i32,
-    ) -> Result<(), MixerError> {
+    ) -> Result<Option<i32>, MixerError> {
         let ts = match self.source_timestamps.get_mut(&source) {
             Some(ts) => ts,
             None => {
@@ -203,16 +186,10 @@ impl Mixer for FifoMixer {
             AvcPacketType::Nalu { .. } if self.last_video_switch.same_source(source) => {
                 // Ok, pass though
             }
-            _ => return Ok(()),
+            _ => return Ok(None),
         }
 
         self.video_timestamp += dt;
-        let data_size = u32::try_from(data.len())?;
-        flvmux::write_video_tag_header(&mut out, data_size, self.video_timestamp)?;
-        out.write_all(data)?;
-        let data_size = u32::try_from(data.len())?;
-        out.write_u32::<BigEndian>(data_size + 11)?; // 11 bytes of header
-
-        Ok(())
+        Ok(Some(self.video_timestamp))
     }
 }
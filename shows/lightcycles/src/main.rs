@@ -1,7 +1,13 @@
-use stream::Show;
+use std::env;
+use std::process;
+use std::time::Duration;
+use stream::{FrameMark, Show};
 
 mod line;
 
+// Falling behind by less than this isn't worth mentioning
+const MENTIONABLE_LAG: Duration = Duration::from_millis(500);
+
 struct Yuv {
     y: u8,
     u: u8,
@@ -17,27 +23,38 @@ struct LightCycle {
     dy: f32,
 }
 
-const UV_WIDTH: usize = stream::WIDTH >> 1;
-const UV_HEIGHT: usize = stream::HEIGHT >> 1;
 const CYCLE_SENSE_RANGE: f32 = 8.0;
 
-#[inline]
-fn uv_index(x: usize, y: usize) -> usize {
-    (UV_WIDTH * y) + x
+// Sizes of the uv planes. The y plane is twice as wide and twice as high.
+#[derive(Clone, Copy)]
+struct Dimensions {
+    uv_width: usize,
+    uv_height: usize,
 }
 
-#[inline]
-fn y_indexes(x: usize, y: usize) -> [usize; 4] {
-    let scaled_x = x * 2;
-    let scaled_y = y * 2;
-    let row1 = (stream::WIDTH * scaled_y) + scaled_x;
-    let row2 = (stream::WIDTH * (scaled_y + 1)) + scaled_x;
-    [row1, row1 + 1, row2, row2 + 1]
+impl Dimensions {
+    #[inline]
+    fn uv_index(&self, x: usize, y: usize) -> usize {
+        (self.uv_width * y) + x
+    }
+
+    #[inline]
+    fn y_indexes(&self, x: usize, y: usize) -> [usize; 4] {
+        let y_width = self.uv_width * 2;
+        let scaled_x = x * 2;
+        let scaled_y = y * 2;
+        let row1 = (y_width * scaled_y) + scaled_x;
+        let row2 = (y_width * (scaled_y + 1)) + scaled_x;
+        [row1, row1 + 1, row2, row2 + 1]
+    }
 }
 
 struct LightCycleShow {
     cycles: Vec<LightCycle>,
     last_frame: usize,
+    dimensions: Dimensions,
+    // The last frame drawn wiped the screen
+    wiped: bool,
 }
 
 impl Show for LightCycleShow {
@@ -59,20 +76,22 @@ impl Show for LightCycleShow {
             Some(dt) => dt as f32,
         };
         self.last_frame = frame;
+        self.wiped = false;
 
+        let dimensions = self.dimensions;
         let mut alive = false;
         for cycle in &mut self.cycles {
             let try_dx = cycle.dx * dt;
             let try_dy = cycle.dy * dt;
 
-            let has_clear_path = if path_is_clear(cycle, try_dx, try_dy, y_plane) {
+            let has_clear_path = if path_is_clear(cycle, try_dx, try_dy, dimensions, y_plane) {
                 true
-            } else if path_is_clear(cycle, -try_dy, try_dx, y_plane) {
+            } else if path_is_clear(cycle, -try_dy, try_dx, dimensions, y_plane) {
                 let new_dx = -cycle.dy;
                 cycle.dy = cycle.dx;
                 cycle.dx = new_dx;
                 true
-            } else if path_is_clear(cycle, try_dy, -try_dx, y_plane) {
+            } else if path_is_clear(cycle, try_dy, -try_dx, dimensions, y_plane) {
                 let new_dy = -cycle.dx;
                 cycle.dx = cycle.dy;
                 cycle.dy = new_dy;
@@ -98,12 +117,12 @@ impl Show for LightCycleShow {
                         let y = iy as usize;
 
                         let luma = (intensity * cycle.color.y as f32) as u8;
-                        for ix in y_indexes(x as usize, y as usize) {
+                        for ix in dimensions.y_indexes(x, y) {
                             y_plane[ix] = luma;
                         }
 
-                        u_plane[uv_index(x, y)] = cycle.color.u;
-                        v_plane[uv_index(x, y)] = cycle.color.v;
+                        u_plane[dimensions.uv_index(x, y)] = cycle.color.u;
+                        v_plane[dimensions.uv_index(x, y)] = cycle.color.v;
 
                         Ok(())
                     },
@@ -118,13 +137,28 @@ impl Show for LightCycleShow {
             set_constant(0, y_plane);
             set_constant(128, u_plane);
             set_constant(128, v_plane);
+            self.wiped = true;
         }
 
         self
     }
+
+    fn mark(&self, _frame: usize) -> FrameMark {
+        if self.wiped {
+            FrameMark::SceneCut
+        } else {
+            FrameMark::Unmarked
+        }
+    }
 }
 
-fn path_is_clear(cycle: &LightCycle, move_dx: f32, move_dy: f32, y_plane: &mut [u8]) -> bool {
+fn path_is_clear(
+    cycle: &LightCycle,
+    move_dx: f32,
+    move_dy: f32,
+    dimensions: Dimensions,
+    y_plane: &mut [u8],
+) -> bool {
     let hyp_squared = move_dx * move_dx + move_dy * move_dy;
     let (sense_range_x, sense_range_y) = if CYCLE_SENSE_RANGE * CYCLE_SENSE_RANGE > hyp_squared {
         let hyp = hyp_squared.sqrt();
@@ -147,7 +181,9 @@ fn path_is_clear(cycle: &LightCycle, move_dx: f32, move_dy: f32, y_plane: &mut [
                 return Ok(());
             }
 
-            if !(0..UV_WIDTH as isize).contains(&x) || !(0..UV_HEIGHT as isize).contains(&y) {
+            if !(0..dimensions.uv_width as isize).contains(&x)
+                || !(0..dimensions.uv_height as isize).contains(&y)
+            {
                 return Err((x, y));
             }
 
@@ -157,7 +193,7 @@ fn path_is_clear(cycle: &LightCycle, move_dx: f32, move_dy: f32, y_plane: &mut [
                 return Ok(());
             }
 
-            for ix in y_indexes(x as usize, y as usize) {
+            for ix in dimensions.y_indexes(x as usize, y as usize) {
                 if y_plane[ix] != 0 {
                     return Err((x, y));
                 }
@@ -171,8 +207,18 @@ fn path_is_clear(cycle: &LightCycle, move_dx: f32, move_dy: f32, y_plane: &mut [
 }
 
 fn main() {
+    let config = stream::StreamConfig::from_env().unwrap();
+    let dimensions = Dimensions {
+        uv_width: config.chroma_width(),
+        uv_height: config.chroma_height(),
+    };
+
+    let center_x = (dimensions.uv_width / 2) as f32;
+    let center_y = (dimensions.uv_height / 2) as f32;
     let show = LightCycleShow {
         last_frame: 0,
+        dimensions,
+        wiped: false,
         cycles: vec![
             LightCycle {
                 color: Yuv {
@@ -180,8 +226,8 @@ fn main() {
                     u: 255,
                     v: 0,
                 },
-                x: (UV_WIDTH / 2) as f32,
-                y: (UV_HEIGHT / 2) as f32,
+                x: center_x,
+                y: center_y,
                 dx: 0.0,
                 dy: 6.0,
             },
@@ -191,8 +237,8 @@ fn main() {
                     u: 0,
                     v: 255,
                 },
-                x: (UV_WIDTH / 2) as f32,
-                y: (UV_HEIGHT / 2) as f32,
+                x: center_x,
+                y: center_y,
                 dx: -5.0,
                 dy: 0.0,
             },
@@ -202,14 +248,37 @@ fn main() {
                     u: 20,
                     v: 150,
                 },
-                x: (UV_WIDTH / 2) as f32,
-                y: (UV_HEIGHT / 2) as f32,
+                x: center_x,
+                y: center_y,
                 dx: -5.0,
                 dy: 0.0,
             },
         ],
     };
-    stream::stream(show, None, None);
+
+    // An optional .aac file to loop under the show
+    let out = stream::Sink::from_env().unwrap();
+    let result = match env::args().nth(1) {
+        Some(audio_path) => {
+            let mut audio = stream::AacFileLoop::open(audio_path).unwrap();
+            stream::stream_with_audio_to(show, None, &config, &mut audio, out)
+        }
+        None => stream::stream_to(show, None, &config, out),
+    };
+
+    // Whoever was watching going away isn't a failure
+    match result {
+        Err(e) if !e.is_disconnect() => {
+            eprintln!("lightcycles: {}", e);
+            process::exit(1);
+        }
+        Ok(stats) if stats.max_lag > MENTIONABLE_LAG => eprintln!(
+            "lightcycles: fell {:.1}s behind real time, {} frames skipped",
+            stats.max_lag.as_secs_f64(),
+            stats.frames_skipped
+        ),
+        _ => {}
+    }
 }
 
 fn set_constant(val: u8, buf: &mut [u8]) {
@@ -1,6 +1,11 @@
 use std::env;
+use std::process;
+use std::time::Duration;
 use stream::Show;
 
+// Falling behind by less than this isn't worth mentioning
+const MENTIONABLE_LAG: Duration = Duration::from_millis(500);
+
 const SIN_AT_FRAME: [u8; 60] = [
     128, 141, 154, 167, 179, 191, 202, 213, 222, 231, 238, 244, 249, 252, 254, 255, 254, 252, 249,
     244, 238, 231, 222, 213, 202, 191, 179, 167, 154, 141, 128, 114, 101, 88, 76, 64, 53, 42, 33,
@@ -35,7 +40,22 @@ fn main() {
         None
     };
 
-    stream::stream(SimpleShow {}, duration, None);
+    let config = stream::StreamConfig::from_env().unwrap();
+    let out = stream::Sink::from_env().unwrap();
+
+    // Whoever was watching going away isn't a failure
+    match stream::stream_to(SimpleShow {}, duration, &config, out) {
+        Err(e) if !e.is_disconnect() => {
+            eprintln!("simple: {}", e);
+            process::exit(1);
+        }
+        Ok(stats) if stats.max_lag > MENTIONABLE_LAG => eprintln!(
+            "simple: fell {:.1}s behind real time, {} frames skipped",
+            stats.max_lag.as_secs_f64(),
+            stats.frames_skipped
+        ),
+        _ => {}
+    }
 }
 
 fn set_constant(val: u8, buf: &mut [u8]) {
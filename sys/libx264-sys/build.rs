@@ -1,18 +1,407 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Where to find libx264 (its include and lib directories), rather than
+// asking pkg-config or looking in the usual places.
+const PREFIX_VAR: &str = "X264_PREFIX";
+
+// Where the vendored feature finds x264's source, rather than the x264
+// directory next to this file.
+const SOURCE_VAR: &str = "X264_SOURCE_DIR";
+
+const SOURCE_URL: &str = "https://code.videolan.org/videolan/x264.git";
+
+const INSTALL_HINT: &str = "Install libx264 (on Debian or Ubuntu, apt install libx264-dev), \
+     set PKG_CONFIG_PATH to the directory with its x264.pc, \
+     or set X264_PREFIX to where it's installed.";
 
 fn main() {
-    println!("cargo:rustc-link-lib=x264");
     println!("cargo:rerun-if-changed=include/wrapper.h");
+    println!("cargo:rerun-if-env-changed={}", PREFIX_VAR);
+    println!("cargo:rerun-if-env-changed={}", SOURCE_VAR);
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+    println!("cargo:rerun-if-env-changed=CPATH");
+    println!("cargo:rerun-if-env-changed=LIBRARY_PATH");
+    println!("cargo:rerun-if-env-changed=CC");
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let vendored = env::var_os("CARGO_FEATURE_VENDORED").is_some();
+    let statik = vendored || env::var_os("CARGO_FEATURE_STATIC").is_some();
+    let prefix = if vendored {
+        Some(build_vendored(&out_path))
+    } else {
+        env::var_os(PREFIX_VAR).map(PathBuf::from)
+    };
+    let x264 = X264::locate(prefix, statik);
+    println!("cargo:rerun-if-changed={}", x264.header.display());
+    if let Some(archive) = &x264.archive {
+        println!("cargo:rerun-if-changed={}", archive.display());
+    }
+
+    for dir in &x264.lib_dirs {
+        println!("cargo:rustc-link-search=native={}", dir.display());
+    }
+    if statik {
+        println!("cargo:rustc-link-lib=static=x264");
+        for lib in &x264.static_deps {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    } else {
+        println!("cargo:rustc-link-lib=x264");
+    }
 
-    let bindings = bindgen::Builder::default()
+    x264.check_link(&out_path);
+
+    let mut builder = bindgen::Builder::default()
         .header("include/wrapper.h")
-        .parse_callbacks(Box::new(bindgen::CargoCallbacks))
-        .generate()
-        .expect("Unable to generate bindings");
+        .parse_callbacks(Box::new(bindgen::CargoCallbacks));
+    for dir in &x264.include_dirs {
+        builder = builder.clang_arg(format!("-I{}", dir.display()));
+    }
+    let bindings = builder.generate().expect("Unable to generate bindings");
 
-    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     bindings
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
+
+    write_encoder_open(&x264.header, &out_path);
+}
+
+struct X264 {
+    header: PathBuf,
+    // Only the ones the compiler might not know about
+    include_dirs: Vec<PathBuf>,
+    lib_dirs: Vec<PathBuf>,
+    // With the static feature, libx264.a and the libraries it needs (like
+    // pthread)
+    archive: Option<PathBuf>,
+    static_deps: Vec<String>,
+}
+
+impl X264 {
+    fn locate(prefix: Option<PathBuf>, statik: bool) -> Self {
+        let pkg_config = PkgConfig::probe(prefix.as_deref(), statik);
+
+        let mut include_dirs = Vec::new();
+        let mut lib_dirs = Vec::new();
+        if let Some(prefix) = &prefix {
+            include_dirs.push(prefix.join("include"));
+            lib_dirs.push(prefix.join("lib"));
+        }
+        if let Some(pkg_config) = &pkg_config {
+            include_dirs.extend(pkg_config.include_dirs.iter().cloned());
+            lib_dirs.extend(pkg_config.lib_dirs.iter().cloned());
+        }
+
+        // The compiler looks in these anyway, but we need the header too.
+        let mut search_dirs = include_dirs.clone();
+        if let Some(cpath) = env::var_os("CPATH") {
+            search_dirs.extend(env::split_paths(&cpath).filter(|dir| !dir.as_os_str().is_empty()));
+        }
+        search_dirs.push("/usr/local/include".into());
+        search_dirs.push("/usr/include".into());
+
+        let header = search_dirs
+            .iter()
+            .map(|dir| dir.join("x264.h"))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| {
+                fail(&format!(
+                    "couldn't find x264.h in {:?}.\n{}",
+                    search_dirs, INSTALL_HINT
+                ))
+            });
+
+        // rustc has to find libx264.a itself, so it has to be somewhere
+        // we tell it to look.
+        let archive = if statik {
+            let archive = lib_dirs
+                .iter()
+                .chain(&system_lib_dirs())
+                .map(|dir| dir.join("libx264.a"))
+                .find(|path| path.is_file())
+                .unwrap_or_else(|| {
+                    fail(&format!(
+                        "the static feature needs libx264.a, which isn't in {:?}.\n{}",
+                        lib_dirs, INSTALL_HINT
+                    ))
+                });
+            let dir = archive.parent().unwrap().to_path_buf();
+            if !lib_dirs.contains(&dir) {
+                lib_dirs.push(dir);
+            }
+            Some(archive)
+        } else {
+            None
+        };
+
+        let static_deps = match &pkg_config {
+            Some(pkg_config) if !pkg_config.libs.is_empty() => pkg_config
+                .libs
+                .iter()
+                .filter(|lib| *lib != "x264")
+                .cloned()
+                .collect(),
+            // What x264's own configure usually adds
+            _ if env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") => {
+                vec!["pthread".into(), "m".into(), "dl".into()]
+            }
+            _ => vec!["pthread".into(), "m".into()],
+        };
+
+        X264 {
+            header,
+            include_dirs,
+            lib_dirs,
+            archive,
+            static_deps,
+        }
+    }
+
+    // Compiles and links a program that opens an encoder, which catches a
+    // missing (or, with the static feature, missing static) library, and a
+    // header from a different build of x264 than the library. That all
+    // surfaces much less clearly when the first binary links.
+    fn check_link(&self, out_path: &Path) {
+        // Needs a C compiler for the target, which we only know how to find
+        // when it's a unix we're running on.
+        let compiling_for_host = env::var("HOST").ok() == env::var("TARGET").ok();
+        if !compiling_for_host || env::var("CARGO_CFG_TARGET_FAMILY").as_deref() != Ok("unix") {
+            return;
+        }
+
+        let source = out_path.join("x264_check.c");
+        fs::write(
+            &source,
+            "#include <stdint.h>\n\
+             #include <x264.h>\n\
+             int main(void) {\n\
+             x264_param_t param;\n\
+             x264_param_default(&param);\n\
+             return x264_encoder_open(&param) == 0;\n\
+             }\n",
+        )
+        .expect("Couldn't write x264_check.c");
+
+        let compiler = env::var("CC").unwrap_or_else(|_| "cc".into());
+        let mut command = Command::new(&compiler);
+        command
+            .arg(&source)
+            .arg("-o")
+            .arg(out_path.join("x264_check"));
+        for dir in &self.include_dirs {
+            command.arg(format!("-I{}", dir.display()));
+        }
+        for dir in &self.lib_dirs {
+            command.arg(format!("-L{}", dir.display()));
+        }
+        if let Some(archive) = &self.archive {
+            command.arg(archive);
+            for lib in &self.static_deps {
+                command.arg(format!("-l{}", lib));
+            }
+        } else {
+            command.arg("-lx264");
+        }
+
+        let output = match command.output() {
+            Ok(output) => output,
+            Err(e) => {
+                println!(
+                    "cargo:warning=skipping the libx264 link check, can't run {}: {}",
+                    compiler, e
+                );
+                return;
+            }
+        };
+
+        if !output.status.success() {
+            fail(&format!(
+                "couldn't build a test program against libx264 (X264_BUILD {} in {}). \
+                 Is the library missing, or from a different version than the header?\n\
+                 {}\n{}",
+                x264_build(&self.header),
+                self.header.display(),
+                String::from_utf8_lossy(&output.stderr).trim(),
+                INSTALL_HINT
+            ));
+        }
+    }
+}
+
+// Builds x264's static library from source, and installs it (with its
+// header and x264.pc) to a prefix in OUT_DIR, which it returns.
+fn build_vendored(out_path: &Path) -> PathBuf {
+    let source = match env::var_os(SOURCE_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("x264"),
+    };
+    if !source.join("configure").is_file() {
+        fail(&format!(
+            "the vendored feature builds x264 from {}, which has no configure script. \
+             Clone {} there, or set {} to a checkout of it.",
+            source.display(),
+            SOURCE_URL,
+            SOURCE_VAR
+        ));
+    }
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    // x264 builds out of tree, as long as the source tree hasn't been
+    // configured itself.
+    let build_dir = out_path.join("x264-build");
+    let prefix = out_path.join("x264");
+    fs::create_dir_all(&build_dir).expect("Couldn't create the x264 build directory");
+
+    let mut configure = Command::new(source.join("configure"));
+    configure
+        .current_dir(&build_dir)
+        .arg(format!("--prefix={}", prefix.display()))
+        .args(["--enable-static", "--enable-pic", "--disable-cli"]);
+    let target = env::var("TARGET").unwrap();
+    if env::var("HOST").ok().as_ref() != Some(&target) {
+        configure.arg(format!("--host={}", target));
+    }
+    run_build_step(
+        configure,
+        "configure x264 (on x86, it needs nasm to build x264's assembly)",
+    );
+
+    let mut make = Command::new("make");
+    make.current_dir(&build_dir);
+    if let Ok(jobs) = env::var("NUM_JOBS") {
+        make.arg(format!("-j{}", jobs));
+    }
+    run_build_step(make, "build x264");
+
+    let mut install = Command::new("make");
+    install
+        .current_dir(&build_dir)
+        .args(["install-lib-dev", "install-lib-static"]);
+    run_build_step(install, "install x264");
+
+    prefix
+}
+
+fn run_build_step(mut command: Command, what: &str) {
+    let output = command.output().unwrap_or_else(|e| {
+        fail(&format!(
+            "couldn't {}, running {:?} failed: {}",
+            what, command, e
+        ))
+    });
+    if !output.status.success() {
+        fail(&format!(
+            "couldn't {}.\n{}\n{}",
+            what,
+            String::from_utf8_lossy(&output.stdout).trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+}
+
+struct PkgConfig {
+    include_dirs: Vec<PathBuf>,
+    lib_dirs: Vec<PathBuf>,
+    libs: Vec<String>,
+}
+
+impl PkgConfig {
+    // None if pkg-config (or its x264.pc) isn't around
+    fn probe(prefix: Option<&Path>, statik: bool) -> Option<Self> {
+        let mut args = vec!["--cflags", "--libs"];
+        if statik {
+            args.push("--static");
+        }
+        let flags = run_pkg_config(prefix, &args)?;
+
+        let mut pkg_config = PkgConfig {
+            include_dirs: Vec::new(),
+            lib_dirs: Vec::new(),
+            libs: Vec::new(),
+        };
+        for flag in flags.split_whitespace() {
+            if let Some(dir) = flag.strip_prefix("-I") {
+                pkg_config.include_dirs.push(dir.into());
+            } else if let Some(dir) = flag.strip_prefix("-L") {
+                pkg_config.lib_dirs.push(dir.into());
+            } else if let Some(lib) = flag.strip_prefix("-l") {
+                pkg_config.libs.push(lib.into());
+            }
+        }
+
+        // pkg-config leaves system directories (like Debian's
+        // /usr/lib/x86_64-linux-gnu) out of --libs, but we need to know
+        // where libx264.a is.
+        if let Some(libdir) = run_pkg_config(prefix, &["--variable=libdir"]) {
+            let libdir = PathBuf::from(libdir.trim());
+            if !libdir.as_os_str().is_empty() && !pkg_config.lib_dirs.contains(&libdir) {
+                pkg_config.lib_dirs.push(libdir);
+            }
+        }
+
+        Some(pkg_config)
+    }
+}
+
+// Runs pkg-config on x264, returning what it printed if it succeeded.
+fn run_pkg_config(prefix: Option<&Path>, args: &[&str]) -> Option<String> {
+    let mut command = Command::new("pkg-config");
+    command.args(args).arg("x264");
+    // Prefer the prefix's x264.pc, if it has one.
+    if let Some(prefix) = prefix {
+        let mut paths = vec![prefix.join("lib").join("pkgconfig")];
+        if let Some(path) = env::var_os("PKG_CONFIG_PATH") {
+            paths.extend(env::split_paths(&path));
+        }
+        command.env("PKG_CONFIG_PATH", env::join_paths(paths).ok()?);
+    }
+
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn system_lib_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = vec!["/usr/local/lib".into(), "/usr/lib".into()];
+    if let Some(path) = env::var_os("LIBRARY_PATH") {
+        dirs.extend(env::split_paths(&path).filter(|dir| !dir.as_os_str().is_empty()));
+    }
+    dirs
+}
+
+fn fail(message: &str) -> ! {
+    panic!("libx264-sys: {}", message);
+}
+
+// x264.h defines x264_encoder_open as a macro, naming a function that
+// includes X264_BUILD (x264_encoder_open_155, say) so that programs built
+// against one version of the header refuse to link against another. Macros
+// don't make it through bindgen, so we do the expansion here.
+fn write_encoder_open(header: &Path, out_path: &Path) {
+    let build = x264_build(header);
+    let source = format!(
+        "pub use self::x264_encoder_open_{} as x264_encoder_open;\n",
+        build
+    );
+    fs::write(out_path.join("encoder_open.rs"), source).expect("Couldn't write encoder_open.rs");
+}
+
+fn x264_build(header: &Path) -> u32 {
+    let text = fs::read_to_string(header)
+        .unwrap_or_else(|e| fail(&format!("couldn't read {}: {}", header.display(), e)));
+    text.lines()
+        .find_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("#define"), Some("X264_BUILD"), Some(build)) => build.parse().ok(),
+                _ => None,
+            }
+        })
+        .unwrap_or_else(|| fail(&format!("no X264_BUILD in {}", header.display())))
 }
@@ -7,3 +7,6 @@
 use libc::*;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+// x264_encoder_open, whatever X264_BUILD this was built against
+include!(concat!(env!("OUT_DIR"), "/encoder_open.rs"));